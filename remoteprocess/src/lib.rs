@@ -235,6 +235,21 @@ pub trait ProcessMemory {
     /// byte buffer
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error>;
 
+    /// Copies several ranges of memory from another process at once. Implementations can
+    /// override this to do all the reads in a single system call, by default each range is
+    /// read separately
+    fn read_vectored(&self, reads: &mut [(usize, &mut [u8])]) -> Result<(), Error> {
+        for (addr, buf) in reads.iter_mut() {
+            self.read(*addr, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Hints that these ranges of memory (given as address and length) are about to be read.
+    /// Implementations that cache memory can override this to read them all at once, by
+    /// default this does nothing
+    fn prefetch(&self, _ranges: &[(usize, usize)]) {}
+
     /// Copies a series of bytes from another process. Main difference
     /// with 'read' is that this will allocate memory for you
     fn copy(&self, addr: usize, length: usize) -> Result<Vec<u8>, Error> {
@@ -290,4 +305,26 @@ pub mod tests {
         assert_eq!(original.x, copy.x);
         assert_eq!(original.y, copy.y);
     }
+
+    fn check_read_vectored<P: ProcessMemory>(process: &P) {
+        let first: [u8; 5] = [1, 2, 3, 4, 5];
+        let second: Vec<u64> = (0..1000).collect();
+        let mut a = [0_u8; 3];
+        let mut b = vec![0_u64; 1000];
+        {
+            let b = unsafe { std::slice::from_raw_parts_mut(b.as_mut_ptr() as *mut u8, b.len() * 8) };
+            let mut reads = [(first.as_ptr() as usize + 2, &mut a[..]), (second.as_ptr() as usize, b)];
+            process.read_vectored(&mut reads).unwrap();
+        }
+        assert_eq!(a, [3, 4, 5]);
+        assert_eq!(b, second);
+        process.read_vectored(&mut []).unwrap();
+    }
+
+    #[test]
+    fn test_read_vectored() {
+        check_read_vectored(&LocalProcess);
+        #[cfg(target_os="linux")]
+        check_read_vectored(&Process::new(std::process::id() as Pid).unwrap());
+    }
 }
@@ -1,8 +1,8 @@
 use std::fs::File;
 use std::path::Path;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use goblin::Object;
 use memmap::Mmap;
@@ -22,11 +22,25 @@ use super::super::StackFrame;
 use super::{Pid, Thread, Process};
 
 pub struct Unwinder {
-    binaries: BTreeMap<u64, BinaryInfo>,
+    binaries: BTreeMap<u64, Rc<BinaryInfo>>,
     process: Process,
     pid: Pid
 }
 
+// Identifies a binary mapped into a process. Processes that have the same file
+// mapped in at the same address can share the parsed unwind info and symbols
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct BinaryKey {
+    filename: String,
+    inode: usize,
+    address: u64,
+}
+
+thread_local! {
+    // parsed binaries, shared between all the unwinders created on this thread
+    static BINARY_CACHE: RefCell<HashMap<BinaryKey, Weak<BinaryInfo>>> = RefCell::new(HashMap::new());
+}
+
 pub struct Cursor<'a> {
     registers: Registers,
     parent: &'a Unwinder,
@@ -41,6 +55,9 @@ impl Unwinder {
         Ok(ret)
     }
 
+    /// Updates the binaries to match the current memory maps of the process. Only binaries that
+    /// have been mapped since the last reload are parsed, and binaries that are no longer
+    /// mapped are dropped
     pub fn reload(&mut self) -> Result<(), Error> {
         info!("reloading process binaries");
 
@@ -48,7 +65,7 @@ impl Unwinder {
         let maps = &proc_maps::get_process_maps(self.pid)?;
         let shared_maps = maps.iter().filter(|m| m.is_exec() && !m.is_write() && m.is_read());
 
-        // Open them up and parse etc
+        let mut mapped = HashSet::new();
         for m in shared_maps {
             // Get the filename if it exists from the map
             let filename = match m.filename() {
@@ -56,81 +73,112 @@ impl Unwinder {
                 None => continue
             };
 
-            // TODO: probably also want to check if the filename/size is the same
+            let key = BinaryKey{filename: filename.to_string(), inode: m.inode, address: m.start() as u64};
+            mapped.insert(key.clone());
+
+            // the map key is the end address of this filename, which lets us do a relatively efficent range
+            // based lookup of the binary
             let address_key = (m.start() + m.size()) as u64;
-            if self.binaries.contains_key(&address_key) {
+            if self.binaries.get(&address_key).map_or(false, |binary| binary.key == key) {
                 debug!("skipping {}", filename);
                 continue;
             }
-            info!("loading debug info from {}", filename);
-
-            // Memory-map the file, special casing [vdso] regions
-            let file;
-            let mmapped_file;
-            let vdso_data;
-
-            let buffer = if Path::new(filename).exists() {
-                file = File::open(Path::new(filename))?;
-                mmapped_file = unsafe { Mmap::map(&file)? };
-                &mmapped_file[..]
-            } else if filename != "[vsyscall]" {
-                // if the filename doesn't exist, its' almost certainly the vdso section
-                // read from the the target processses memory
-                vdso_data = self.process.copy(m.start(), m.size())?;
-                &vdso_data
-            } else {
-                // vsyscall region, can be ignored, but lets not keep on trying to do this
-                info!("skipping {} region", filename);
-
-                // insert a stub for [vsyscall] so that we don't continually try to load it etc
-                self.binaries.insert(address_key,
-                        BinaryInfo{unwind_info: None, offset: 0, address: m.start() as u64, size: m.size() as u64,
-                                   filename: filename.to_string(), symbols: RefCell::new(None)});
-                continue;
-            };
 
-            debug!("loading file {} 0x{:X} 0x{:X}", filename, m.start(), buffer.len());
-            match Object::parse(&buffer) {
-                Ok(Object::Elf(elf)) => {
-                    trace!("filename {} elf {:#?}", filename, elf);
-
-                    let program_header = elf.program_headers
-                        .iter()
-                        .find(|ref header| header.p_type == PT_LOAD && header.p_flags & PF_X != 0);
-
-                    let obj_base = match program_header {
-                        Some(hdr) => { m.start() as u64 - hdr.p_vaddr },
-                        None => {
-                            warn!("Failed to find exectuable PT_LOAD header in {}", filename);
-                            continue;
-                        }
+            // reuse the binary if another unwinder has already parsed it
+            let cached = BINARY_CACHE.with(|cache| cache.borrow().get(&key).and_then(|binary| binary.upgrade()));
+            let binary = match cached {
+                Some(binary) => {
+                    debug!("using cached binary info for {}", filename);
+                    binary
+                },
+                None => {
+                    let binary = match self.load_binary(m, key.clone())? {
+                        Some(binary) => Rc::new(binary),
+                        None => continue
                     };
+                    BINARY_CACHE.with(|cache| cache.borrow_mut().insert(key, Rc::downgrade(&binary)));
+                    binary
+                }
+            };
+            self.binaries.insert(address_key, binary);
+        }
 
-                    let unwind_info = match self.get_unwind_info(filename, &elf, buffer, obj_base) {
-                        Ok(unwind) => Some(unwind),
-                        Err(e) => {
-                            warn!("Failed to get unwind info for '{}': {}", filename, e);
-                            None
-                        }
-                    };
+        // drop anything that has been unmapped since the last reload
+        self.binaries.retain(|_, binary| {
+            let keep = mapped.contains(&binary.key);
+            if !keep {
+                info!("unloading {}", binary.filename);
+            }
+            keep
+        });
+        BINARY_CACHE.with(|cache| cache.borrow_mut().retain(|_, binary| binary.upgrade().is_some()));
+        Ok(())
+    }
 
-                    // the map key is the end address of this filename, which lets us do a relatively efficent range
-                    // based lookup of the binary
-                    self.binaries.insert(address_key,
-                        BinaryInfo{unwind_info, offset: obj_base, address: m.start() as u64, size: m.size() as u64,
-                                   filename: filename.to_string(), symbols: RefCell::new(None)});
-                },
-                Ok(_) => {
-                    warn!("unknown binary type for {}", filename);
-                    continue;
-                }
-                Err(e) => {
-                    warn!("Failed to parse {}: {:?}", filename, e);
-                    continue;
-                }
+    fn load_binary(&self, m: &proc_maps::MapRange, key: BinaryKey) -> Result<Option<BinaryInfo>, Error> {
+        let filename = &key.filename;
+        info!("loading debug info from {}", filename);
+
+        // Memory-map the file, special casing [vdso] regions
+        let file;
+        let mmapped_file;
+        let vdso_data;
+
+        let buffer = if Path::new(filename).exists() {
+            file = File::open(Path::new(filename))?;
+            mmapped_file = unsafe { Mmap::map(&file)? };
+            &mmapped_file[..]
+        } else if filename != "[vsyscall]" {
+            // if the filename doesn't exist, its' almost certainly the vdso section
+            // read from the the target processses memory
+            vdso_data = self.process.copy(m.start(), m.size())?;
+            &vdso_data
+        } else {
+            // vsyscall region, can be ignored, but lets not keep on trying to do this
+            info!("skipping {} region", filename);
+
+            // insert a stub for [vsyscall] so that we don't continually try to load it etc
+            return Ok(Some(BinaryInfo{unwind_info: None, offset: 0, address: m.start() as u64, size: m.size() as u64,
+                                      filename: filename.to_string(), symbols: RefCell::new(None), key}));
+        };
+
+        debug!("loading file {} 0x{:X} 0x{:X}", filename, m.start(), buffer.len());
+        match Object::parse(&buffer) {
+            Ok(Object::Elf(elf)) => {
+                trace!("filename {} elf {:#?}", filename, elf);
+
+                let program_header = elf.program_headers
+                    .iter()
+                    .find(|ref header| header.p_type == PT_LOAD && header.p_flags & PF_X != 0);
+
+                let obj_base = match program_header {
+                    Some(hdr) => { m.start() as u64 - hdr.p_vaddr },
+                    None => {
+                        warn!("Failed to find exectuable PT_LOAD header in {}", filename);
+                        return Ok(None);
+                    }
+                };
+
+                let unwind_info = match self.get_unwind_info(filename, &elf, buffer, obj_base) {
+                    Ok(unwind) => Some(unwind),
+                    Err(e) => {
+                        warn!("Failed to get unwind info for '{}': {}", filename, e);
+                        None
+                    }
+                };
+
+                Ok(Some(BinaryInfo{unwind_info, offset: obj_base, address: m.start() as u64, size: m.size() as u64,
+                                   filename: filename.to_string(), symbols: RefCell::new(None), key}))
+            },
+            Ok(_) => {
+                warn!("unknown binary type for {}", filename);
+                Ok(None)
+            }
+            Err(e) => {
+                warn!("Failed to parse {}: {:?}", filename, e);
+                Ok(None)
             }
         }
-        Ok(())
     }
 
     fn get_unwind_info(&self, filename: &str, elf: &goblin::elf::Elf, buffer: &[u8], obj_base: u64) -> Result<UnwindInfo, Error> {
@@ -209,7 +257,7 @@ impl Unwinder {
 
     fn get_binary(&self, addr: u64) -> Option<&BinaryInfo> {
         match self.binaries.range(addr..).next() {
-            Some((_, binary)) if binary.contains(addr) => Some(&binary),
+            Some((_, binary)) if binary.contains(addr) => Some(binary.as_ref()),
             Some(_) => None,
             _ => None
         }
@@ -273,6 +321,7 @@ impl<'a> Iterator for Cursor<'a> {
 
 // Contains info for a binary on how to unwind/symbolicate a stack trace
 struct BinaryInfo {
+    key: BinaryKey,
     address: u64,
     size: u64,
     offset: u64,
@@ -286,3 +335,29 @@ impl BinaryInfo {
         addr >= self.address && addr < (self.address + self.size)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_shares_binaries() {
+        let pid = std::process::id() as Pid;
+        let mut first = Unwinder::new(pid).unwrap();
+        let second = Unwinder::new(pid).unwrap();
+        assert!(!first.binaries.is_empty());
+
+        // unwinders for the same process should share the parsed binaries
+        for (address, binary) in first.binaries.iter() {
+            assert!(Rc::ptr_eq(binary, &second.binaries[address]));
+        }
+
+        // reloading without any changes to the memory maps shouldn't reparse anything
+        let before: Vec<Rc<BinaryInfo>> = first.binaries.values().cloned().collect();
+        first.reload().unwrap();
+        for binary in before.iter() {
+            let address = binary.address + binary.size;
+            assert!(Rc::ptr_eq(binary, &first.binaries[&address]));
+        }
+    }
+}
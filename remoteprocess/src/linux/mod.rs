@@ -4,6 +4,7 @@ pub mod libunwind;
 mod gimli_unwinder;
 #[cfg(unwind)]
 mod symbolication;
+mod syscall;
 use libc::pid_t;
 #[cfg(unwind)]
 use libc::c_void;
@@ -24,6 +25,7 @@ pub use self::gimli_unwinder::*;
 pub use self::symbolication::*;
 #[cfg(unwind)]
 pub use self::libunwind::{LibUnwind};
+pub use self::syscall::{Syscall, Socket, get_socket};
 
 use read_process_memory::{CopyAddress, ProcessHandle};
 
@@ -54,6 +56,18 @@ impl Process {
         Ok(path.to_string_lossy().to_string())
     }
 
+    /// Returns the name of the process (which can be changed by the process, for instance by setproctitle)
+    pub fn name(&self) -> Result<String, Error> {
+        let name = std::fs::read_to_string(format!("/proc/{}/comm", self.pid))?;
+        Ok(name.trim_end_matches('\n').to_owned())
+    }
+
+    /// Returns the command line arguments of the process
+    pub fn cmdline(&self) -> Result<Vec<String>, Error> {
+        let cmdline = std::fs::read(format!("/proc/{}/cmdline", self.pid))?;
+        Ok(parse_cmdline(&cmdline))
+    }
+
     pub fn lock(&self) -> Result<Lock, Error> {
         let mut locks = Vec::new();
         let mut locked = std::collections::HashSet::new();
@@ -108,6 +122,33 @@ impl super::ProcessMemory for Process {
         let handle: ProcessHandle = self.pid.try_into()?;
         Ok(handle.copy_address(addr, buf)?)
     }
+
+    fn read_vectored(&self, reads: &mut [(usize, &mut [u8])]) -> Result<(), Error> {
+        // process_vm_readv takes at most IOV_MAX (1024) ranges at a time
+        for chunk in reads.chunks_mut(1024) {
+            let mut expected = 0;
+            let mut local = Vec::with_capacity(chunk.len());
+            let mut remote = Vec::with_capacity(chunk.len());
+            for (addr, buf) in chunk.iter_mut() {
+                expected += buf.len();
+                local.push(libc::iovec{iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len()});
+                remote.push(libc::iovec{iov_base: *addr as *mut libc::c_void, iov_len: buf.len()});
+            }
+            let read = unsafe {
+                libc::process_vm_readv(self.pid, local.as_ptr(), local.len() as libc::c_ulong,
+                                       remote.as_ptr(), remote.len() as libc::c_ulong, 0)
+            };
+            if read < 0 {
+                return Err(Error::IOError(std::io::Error::last_os_error()));
+            }
+            // reads stop at the first range that can't be read, which is what a single read of
+            // that range would have failed with
+            if read as usize != expected {
+                return Err(Error::IOError(std::io::Error::from_raw_os_error(libc::EFAULT)));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Thread {
@@ -147,6 +188,42 @@ impl Thread {
             None => Err(Error::Other(format!("Failed to parse /proc/{}/stat", self.tid)))
         }
     }
+
+    /// Returns the kernel function the thread is sleeping in, or None if it's running
+    pub fn wchan(&self) -> Result<Option<String>, Error> {
+        let wchan = std::fs::read_to_string(format!("/proc/{}/wchan", self.tid))?;
+        let wchan = wchan.trim();
+        if wchan.is_empty() || wchan == "0" {
+            Ok(None)
+        } else {
+            Ok(Some(wchan.to_owned()))
+        }
+    }
+
+    /// Returns the system call (and arguments) the thread is blocked in, if any
+    pub fn syscall(&self) -> Result<Option<Syscall>, Error> {
+        syscall::parse_syscall(&std::fs::read_to_string(format!("/proc/{}/syscall", self.tid))?)
+    }
+
+    /// Returns what a file descriptor in the thread refers to: either a filename or
+    /// something like 'socket:[1234]' or 'pipe:[5678]'
+    pub fn fd_target(&self, fd: u64) -> Result<String, Error> {
+        let path = std::fs::read_link(format!("/proc/{}/fd/{}", self.tid, fd))?;
+        Ok(path.to_string_lossy().to_string())
+    }
+}
+
+/// Returns the pids of all the processes running on the system
+pub fn get_pids() -> Result<Vec<Pid>, Error> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        if let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<Pid>().ok()) {
+            pids.push(pid);
+        }
+    }
+    pids.sort();
+    Ok(pids)
 }
 
 /// This locks a target process using ptrace, and prevents it from running while this
@@ -210,6 +287,14 @@ impl Drop for Namespace {
     }
 }
 
+// arguments in /proc/<pid>/cmdline are null terminated
+fn parse_cmdline(cmdline: &[u8]) -> Vec<String> {
+    cmdline.split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
 fn get_active_status(stat: &[u8]) -> Option<u8> {
     // find the first ')' character, and return the active status
     // field which comes after it
@@ -230,3 +315,19 @@ fn test_parse_stat() {
     assert_eq!(get_active_status(b"1234 (bash)S"), None);
     assert_eq!(get_active_status(b"1234)SSSS"), None);
 }
+
+#[test]
+fn test_parse_cmdline() {
+    assert_eq!(parse_cmdline(b"python\0-m\0http.server\0"), vec!["python", "-m", "http.server"]);
+    // kernel threads don't have a command line
+    assert_eq!(parse_cmdline(b""), Vec::<String>::new());
+}
+
+#[test]
+fn test_get_pids() {
+    let pid = std::process::id() as Pid;
+    assert!(get_pids().unwrap().contains(&pid));
+    let process = Process::new(pid).unwrap();
+    assert!(!process.cmdline().unwrap().is_empty());
+    assert!(!process.name().unwrap().is_empty());
+}
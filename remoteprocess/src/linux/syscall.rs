@@ -0,0 +1,164 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::Error;
+
+/// A system call that a thread is currently blocked in
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Syscall {
+    pub number: i64,
+    pub args: [u64; 6],
+}
+
+/// Information about the socket a file descriptor refers to
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Socket {
+    Inet{protocol: &'static str, local: SocketAddr, remote: SocketAddr},
+    Unix{path: Option<String>},
+}
+
+/// Looks up a socket by inode in the network tables for the namespace of a process
+pub fn get_socket(pid: super::Pid, inode: u64) -> Result<Option<Socket>, Error> {
+    for &protocol in ["tcp", "tcp6", "udp", "udp6"].iter() {
+        let table = match std::fs::read_to_string(format!("/proc/{}/net/{}", pid, protocol)) {
+            Ok(table) => table,
+            // ipv6 might not be enabled
+            Err(_) => continue
+        };
+        if let Some((local, remote)) = find_inet_socket(&table, inode) {
+            let protocol = if protocol.starts_with("tcp") { "tcp" } else { "udp" };
+            return Ok(Some(Socket::Inet{protocol, local, remote}));
+        }
+    }
+
+    let table = std::fs::read_to_string(format!("/proc/{}/net/unix", pid))?;
+    Ok(find_unix_socket(&table, inode).map(|path| Socket::Unix{path}))
+}
+
+// Parses the contents of /proc/<tid>/syscall. This contains either 'running', or '-1 sp pc' if
+// the thread is blocked outside of a syscall, or the syscall number followed by the arguments
+pub(crate) fn parse_syscall(contents: &str) -> Result<Option<Syscall>, Error> {
+    let tokens: Vec<&str> = contents.split_whitespace().collect();
+    match tokens.first() {
+        None => Err(Error::Other(format!("Failed to parse syscall '{}'", contents))),
+        Some(&"running") => Ok(None),
+        Some(number) => {
+            let number: i64 = number.parse().map_err(|_| Error::Other(format!("Invalid syscall number '{}'", number)))?;
+            if number < 0 {
+                return Ok(None);
+            }
+            if tokens.len() < 7 {
+                return Err(Error::Other(format!("Failed to parse syscall '{}'", contents)));
+            }
+
+            let mut args = [0; 6];
+            for (arg, token) in args.iter_mut().zip(tokens[1..7].iter()) {
+                *arg = parse_hex(token).ok_or_else(|| Error::Other(format!("Invalid syscall argument '{}'", token)))?;
+            }
+            Ok(Some(Syscall{number, args}))
+        }
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    let value = value.trim_start_matches("0x");
+    u64::from_str_radix(value, 16).ok()
+}
+
+// Finds the local and remote address for a socket in /proc/net/tcp (or udp/tcp6/udp6)
+fn find_inet_socket(table: &str, inode: u64) -> Option<(SocketAddr, SocketAddr)> {
+    for line in table.lines().skip(1) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() < 10 || tokens[9].parse::<u64>().ok() != Some(inode) {
+            continue;
+        }
+        return Some((parse_socket_addr(tokens[1])?, parse_socket_addr(tokens[2])?));
+    }
+    None
+}
+
+// Finds the path for a socket in /proc/net/unix (which is optional, and missing for unnamed sockets)
+fn find_unix_socket(table: &str, inode: u64) -> Option<Option<String>> {
+    for line in table.lines().skip(1) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() < 7 || tokens[6].parse::<u64>().ok() != Some(inode) {
+            continue;
+        }
+        return Some(tokens.get(7).map(|path| path.to_string()));
+    }
+    None
+}
+
+// Parses an address like '0100007F:1538'. The address is in network byte order, but
+// printed out as a series of native endian 32 bit words
+fn parse_socket_addr(value: &str) -> Option<SocketAddr> {
+    let mut parts = value.split(':');
+    let address = parts.next()?;
+    let port = u16::from_str_radix(parts.next()?, 16).ok()?;
+
+    // the bytes of each word in native order are the address in network order
+    let mut bytes = Vec::new();
+    for i in (0..address.len()).step_by(8) {
+        let word = u32::from_str_radix(address.get(i..i + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        },
+        _ => return None
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_syscall() {
+        assert_eq!(parse_syscall("running\n").unwrap(), None);
+        assert_eq!(parse_syscall("-1 0x7ffc4a1b8d18 0x7f0a2c4e1c7a\n").unwrap(), None);
+
+        let syscall = parse_syscall("202 0x7f0a28000b60 0x80 0x0 0x0 0x0 0x0 0x7f0a2b7fdd40 0x7f0a2c6e2ad3\n").unwrap();
+        assert_eq!(syscall, Some(Syscall{number: 202, args: [0x7f0a28000b60, 0x80, 0, 0, 0, 0]}));
+
+        assert!(parse_syscall("").is_err());
+        assert!(parse_syscall("45 0x3").is_err());
+    }
+
+    #[test]
+    fn test_find_inet_socket() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1538 00000000:0000 0A 00000000:00000000 00:00000000 00000000   999        0 21893 1 0000000000000000 100 0 0 10 0
+   1: 0F02000A:D3A4 0500000A:1538 01 00000000:00000000 00:00000000 00000000  1000        0 44871 1 0000000000000000 20 4 30 10 -1
+";
+        let (local, remote) = find_inet_socket(table, 44871).unwrap();
+        assert_eq!(local, "10.0.2.15:54180".parse().unwrap());
+        assert_eq!(remote, "10.0.0.5:5432".parse().unwrap());
+
+        let (local, _) = find_inet_socket(table, 21893).unwrap();
+        assert_eq!(local, "127.0.0.1:5432".parse().unwrap());
+
+        assert_eq!(find_inet_socket(table, 1234), None);
+    }
+
+    #[test]
+    fn test_parse_socket_addr_v6() {
+        assert_eq!(parse_socket_addr("00000000000000000000000001000000:0050"), Some("[::1]:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_find_unix_socket() {
+        let table = "Num       RefCount Protocol Flags    Type St Inode Path
+0000000000000000: 00000002 00000000 00010000 0001 01 15760 /run/systemd/notify
+0000000000000000: 00000003 00000000 00000000 0001 03 42201
+";
+        assert_eq!(find_unix_socket(table, 15760), Some(Some("/run/systemd/notify".to_owned())));
+        assert_eq!(find_unix_socket(table, 42201), Some(None));
+        assert_eq!(find_unix_socket(table, 1), None);
+    }
+}
@@ -0,0 +1,259 @@
+/* Finds the asyncio tasks in a python process, and gets the stack of coroutines each
+task is waiting on.
+
+Tasks are tracked in the 'asyncio.tasks._all_tasks' WeakSet, which we find by looking
+through sys.modules for the current interpreter. This relies on the layout of a bunch of
+private structures (dicts, sets, weakrefs, and the TaskObj from the _asyncio module) that
+aren't in the generated bindings, so these are defined here (and in python_objects), and only
+python 3.7+ is supported.
+*/
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::v3_7_0::{PyBytesObject, PyCodeObject, PyFrameObject, PyUnicodeObject, _typeobject};
+use crate::python_interpreters::BytesObject;
+use crate::python_objects::{Object, TypeNames, copy_array, dict_get};
+use crate::stack_trace::{CodeCache, StackTrace, copy_string, get_frame};
+use crate::version::Version;
+
+// opcode for 'await'/'yield from' in python 3.7 and 3.8
+const YIELD_FROM: u8 = 72;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct ModuleObject {
+    ob_base: Object,
+    md_dict: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct SetObject {
+    ob_base: Object,
+    fill: isize,
+    used: isize,
+    mask: isize,
+    table: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct SetEntry {
+    key: usize,
+    hash: isize,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct WeakReference {
+    ob_base: Object,
+    wr_object: usize,
+}
+
+// the common prefix of the TaskObj struct in 3.7 and 3.8. (3.8 follows this with a task_name)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct TaskObject {
+    ob_base: Object,
+    task_loop: usize,
+    task_callback0: usize,
+    task_context0: usize,
+    task_callbacks: usize,
+    task_exception: usize,
+    task_result: usize,
+    task_source_tb: usize,
+    task_state: i32,
+    task_log_tb: i32,
+    task_blocking: i32,
+    dict: usize,
+    task_weakreflist: usize,
+    task_fut_waiter: usize,
+    task_coro: usize,
+}
+
+// the common prefix of PyGenObject and PyCoroObject
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct GenObject {
+    ob_base: Object,
+    gi_frame: usize,
+    gi_running: i8,
+}
+
+// TaskObj.task_state for tasks that haven't finished yet
+const STATE_PENDING: i32 = 0;
+
+/// Returns whether we can get asyncio tasks for this version of python
+pub fn is_supported(version: &Version) -> bool {
+    matches!(version, Version{major: 3, minor: 7..=8, ..})
+}
+
+/// Finds the asyncio tasks in a python process
+pub struct TaskFinder {
+    has_task_name: bool,
+    // the address of the set containing weakrefs to all the tasks, for each interpreter
+    task_sets: HashMap<i64, usize>,
+    type_names: TypeNames,
+    code_cache: CodeCache,
+}
+
+impl TaskFinder {
+    pub fn new(version: &Version) -> TaskFinder {
+        let has_task_name = version.major == 3 && version.minor >= 8;
+        TaskFinder{has_task_name, task_sets: HashMap::new(), type_names: TypeNames::new(), code_cache: CodeCache::new()}
+    }
+
+    /// Returns a pseudo-thread StackTrace for each pending task in the interpreter, given
+    /// the address of the interpreters sys.modules dict
+    pub fn get_tasks<P: ProcessMemory>(&mut self, process: &P, interpreter_id: i64, modules: usize) -> Result<Vec<StackTrace>, Error> {
+        let mut traces = Vec::new();
+
+        let task_set = match self.task_sets.get(&interpreter_id) {
+            Some(&addr) => addr,
+            None => {
+                match find_task_set(process, modules)? {
+                    Some(addr) => {
+                        info!("Found asyncio task set for interpreter {} at 0x{:016x}", interpreter_id, addr);
+                        self.task_sets.insert(interpreter_id, addr);
+                        addr
+                    },
+                    // asyncio hasn't been imported yet
+                    None => return Ok(traces)
+                }
+            }
+        };
+
+        if self.type_names.get(process, task_set)? != "set" {
+            warn!("asyncio task set at 0x{:016x} is no longer valid", task_set);
+            self.task_sets.remove(&interpreter_id);
+            return Ok(traces);
+        }
+
+        let set: SetObject = process.copy_struct(task_set).context("Failed to copy asyncio task set")?;
+        if set.mask < 0 || set.mask >= 1 << 20 {
+            return Err(format_err!("Invalid size for asyncio task set: {}", set.mask));
+        }
+        let entries: Vec<SetEntry> = copy_array(process, set.table, set.mask as usize + 1)?;
+
+        for entry in entries.iter().filter(|entry| entry.key != 0) {
+            // skip over deleted entries, which point to a dummy object
+            if self.type_names.get(process, entry.key)? != "weakref" {
+                continue;
+            }
+            let weakref: WeakReference = process.copy_struct(entry.key)?;
+            if weakref.wr_object == 0 || self.type_names.get(process, weakref.wr_object)? != "_asyncio.Task" {
+                continue;
+            }
+
+            if let Some(mut trace) = self.get_task(process, weakref.wr_object)? {
+                trace.interpreter_id = interpreter_id;
+                traces.push(trace);
+            }
+        }
+        Ok(traces)
+    }
+
+    // Gets the stack of coroutines for a task, or None if the task has already finished
+    fn get_task<P: ProcessMemory>(&mut self, process: &P, addr: usize) -> Result<Option<StackTrace>, Error> {
+        let task: TaskObject = process.copy_struct(addr).context("Failed to copy asyncio task")?;
+        if task.task_state != STATE_PENDING {
+            return Ok(None);
+        }
+
+        // tasks don't have names before python 3.8, so fall back to the address
+        let name = if self.has_task_name {
+            let name: usize = process.copy_struct(addr + size_of::<TaskObject>())?;
+            copy_string(name as *const PyUnicodeObject, process).context("Failed to copy task name")?
+        } else {
+            format!("0x{:x}", addr)
+        };
+
+        // follow the chain of coroutines the task is awaiting on, from the outermost coroutine in
+        let mut frames = Vec::new();
+        let mut running = false;
+        let mut coro = task.task_coro;
+        while coro != 0 {
+            match self.type_names.get(process, coro)?.as_ref() {
+                // generator based coroutines have the same layout as native coroutines
+                "coroutine" | "generator" => {},
+                // anything else (like a Future) is what the innermost coroutine is waiting on
+                _ => break
+            };
+
+            let gen: GenObject = process.copy_struct(coro).context("Failed to copy coroutine")?;
+            running |= gen.gi_running != 0;
+            if gen.gi_frame == 0 {
+                break;
+            }
+
+            let frame: PyFrameObject = process.copy_struct(gen.gi_frame).context("Failed to copy coroutine frame")?;
+            frames.push(get_frame(&frame, process, &mut self.code_cache)?);
+            if frames.len() > 4096 {
+                return Err(format_err!("Max coroutine recursion depth reached"));
+            }
+            coro = yield_from(process, &frame)?;
+        }
+
+        // stack traces have the innermost frame first
+        frames.reverse();
+
+        Ok(Some(StackTrace{thread_id: addr as u64, os_thread_id: None, active: running, owns_gil: false,
+                           interpreter_id: 0, task_name: Some(name), wait: None, frames}))
+    }
+}
+
+// Finds the 'data' set inside of the asyncio.tasks._all_tasks WeakSet
+fn find_task_set<P: ProcessMemory>(process: &P, modules: usize) -> Result<Option<usize>, Error> {
+    let module = match dict_get(process, modules, "asyncio.tasks")? {
+        Some(module) => module,
+        None => return Ok(None)
+    };
+    let module: ModuleObject = process.copy_struct(module).context("Failed to copy asyncio.tasks module")?;
+
+    let all_tasks = dict_get(process, module.md_dict, "_all_tasks")?
+        .ok_or_else(|| format_err!("Failed to find _all_tasks in asyncio.tasks"))?;
+
+    // WeakSet is a regular python class, so 'data' is in the instance dict
+    let object: Object = process.copy_struct(all_tasks)?;
+    let typeobject: _typeobject = process.copy_struct(object.ob_type)?;
+    if typeobject.tp_dictoffset <= 0 {
+        return Err(format_err!("Unexpected dict offset for _all_tasks: {}", typeobject.tp_dictoffset));
+    }
+    let dict: usize = process.copy_struct(all_tasks + typeobject.tp_dictoffset as usize)?;
+    if dict == 0 {
+        return Err(format_err!("_all_tasks doesn't have an instance dict"));
+    }
+    dict_get(process, dict, "data")
+}
+
+// Returns the address of the object a suspended coroutine frame is awaiting on (or 0)
+fn yield_from<P: ProcessMemory>(process: &P, frame: &PyFrameObject) -> Result<usize, Error> {
+    if frame.f_stacktop.is_null() || frame.f_lasti < 0 {
+        return Ok(0);
+    }
+
+    // the frame is awaiting if the next instruction is a YIELD_FROM, with the awaitable
+    // on the top of the value stack (this is how _PyGen_yf works)
+    let code: PyCodeObject = process.copy_pointer(frame.f_code)?;
+    let bytecode_ptr = code.co_code as *const PyBytesObject;
+    let bytecode = process.copy_pointer(bytecode_ptr)?;
+    let next = frame.f_lasti as usize + 2;
+    if next >= bytecode.size() {
+        return Ok(0);
+    }
+
+    let opcode = process.copy(bytecode.address(bytecode_ptr as usize) + next, 1)?;
+    if opcode[0] != YIELD_FROM {
+        return Ok(0);
+    }
+    Ok(process.copy_struct(frame.f_stacktop as usize - size_of::<usize>())?)
+}
@@ -96,6 +96,16 @@ pub fn parse_binary(filename: &str, addr: u64, size: u64) -> Result<BinaryInfo,
                 let name = elf.strtab[sym.st_name].to_string();
                 symbols.insert(name, sym.st_value + offset);
             }
+
+            // stripped libraries still have their exported symbols in the dynamic symbol table.
+            // (ignoring undefined entries, which are symbols imported from other libraries)
+            for sym in elf.dynsyms.iter() {
+                if sym.st_shndx == goblin::elf::section_header::SHN_UNDEF as usize {
+                    continue;
+                }
+                let name = elf.dynstrtab[sym.st_name].to_string();
+                symbols.entry(name).or_insert(sym.st_value + offset);
+            }
             Ok(BinaryInfo{filename: filename.to_owned(),
                           symbols,
                           bss_addr: bss_header.sh_addr + offset,
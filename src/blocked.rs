@@ -0,0 +1,176 @@
+use std::fmt;
+
+use remoteprocess::{Pid, Tid};
+
+use crate::stack_trace::ThreadWait;
+
+/// Describes what an idle thread is waiting on, like the lock or the socket it is blocked on
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Blocked {
+    pub syscall: String,
+    pub target: Option<String>,
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.target {
+            Some(target) => write!(f, "{} on {}", self.syscall, target),
+            None => write!(f, "{}", self.syscall)
+        }
+    }
+}
+
+/// Returns what an idle thread is blocked on, like the lock or the socket for the system call it
+/// was in when it was sampled
+#[cfg(target_os="linux")]
+pub fn get_blocked(pid: Pid, tid: Tid, wait: &ThreadWait) -> Blocked {
+    match wait {
+        ThreadWait::Syscall{number, args} => {
+            let syscall = remoteprocess::Syscall{number: *number, args: *args};
+            describe_syscall(pid, tid, &syscall)
+        },
+        ThreadWait::Kernel(function) => Blocked{syscall: function.clone(), target: None}
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn get_blocked(_pid: Pid, _tid: Tid, wait: &ThreadWait) -> Blocked {
+    match wait {
+        ThreadWait::Syscall{number, ..} => Blocked{syscall: format!("syscall {}", number), target: None},
+        ThreadWait::Kernel(function) => Blocked{syscall: function.clone(), target: None}
+    }
+}
+
+/// Returns just the system call a thread was blocked in, without looking up the lock or file it
+/// was waiting on. This is cheap enough to do for every sample in 'record', and gives the same name
+/// for every lock or connection so that the samples blocked in it get grouped together
+pub fn get_blocked_syscall(wait: &ThreadWait) -> String {
+    match wait {
+        ThreadWait::Syscall{number, ..} => syscall_label(*number).0,
+        ThreadWait::Kernel(function) => function.clone()
+    }
+}
+
+#[cfg(target_os="linux")]
+fn describe_syscall(pid: Pid, tid: Tid, syscall: &remoteprocess::Syscall) -> Blocked {
+    let (name, fd_arg) = syscall_label(syscall.number);
+    let target = if fd_arg {
+        describe_fd(pid, tid, syscall.args[0])
+    } else {
+        match name.as_ref() {
+            "futex" => Some(format!("lock 0x{:x}", syscall.args[0])),
+            "wait4" => match syscall.args[0] as i32 {
+                -1 => Some("any child".to_owned()),
+                child => Some(format!("child {}", child))
+            },
+            _ => None
+        }
+    };
+    Blocked{syscall: name, target}
+}
+
+// Returns the name of a syscall (or its number if we don't know it), and whether the first
+// argument is a file descriptor
+fn syscall_label(number: i64) -> (String, bool) {
+    match syscall_name(number) {
+        Some((name, fd_arg)) => (name.to_owned(), fd_arg),
+        None => (format!("syscall {}", number), false)
+    }
+}
+
+#[cfg(target_os="linux")]
+fn describe_fd(pid: Pid, tid: Tid, fd: u64) -> Option<String> {
+    let target = remoteprocess::Thread::new(tid).ok()?.fd_target(fd).ok()?;
+
+    let inode = match target.trim_start_matches("socket:[").trim_end_matches(']').parse::<u64>() {
+        Ok(inode) if target.starts_with("socket:[") => inode,
+        _ => return Some(target)
+    };
+
+    match remoteprocess::get_socket(pid, inode) {
+        // show the address of the other end for connected sockets, or what we're listening on otherwise
+        Ok(Some(remoteprocess::Socket::Inet{remote, local, ..})) => {
+            if remote.port() != 0 {
+                Some(format!("socket {}", remote))
+            } else {
+                Some(format!("socket {}", local))
+            }
+        },
+        Ok(Some(remoteprocess::Socket::Unix{path: Some(path)})) => Some(format!("unix socket {}", path)),
+        Ok(Some(remoteprocess::Socket::Unix{path: None})) => Some("unix socket".to_owned()),
+        _ => Some(target)
+    }
+}
+
+// Returns the name of a syscall, and whether the first argument is a file descriptor.
+// This only covers the calls that threads commonly block in
+#[cfg(all(target_os="linux", target_arch="x86_64"))]
+fn syscall_name(number: i64) -> Option<(&'static str, bool)> {
+    Some(match number {
+        0 => ("read", true),
+        1 => ("write", true),
+        7 => ("poll", false),
+        16 => ("ioctl", true),
+        17 => ("pread64", true),
+        18 => ("pwrite64", true),
+        19 => ("readv", true),
+        20 => ("writev", true),
+        23 => ("select", false),
+        34 => ("pause", false),
+        35 => ("nanosleep", false),
+        42 => ("connect", true),
+        43 => ("accept", true),
+        44 => ("sendto", true),
+        45 => ("recvfrom", true),
+        46 => ("sendmsg", true),
+        47 => ("recvmsg", true),
+        61 => ("wait4", false),
+        65 => ("semop", false),
+        73 => ("flock", true),
+        74 => ("fsync", true),
+        75 => ("fdatasync", true),
+        128 => ("rt_sigtimedwait", false),
+        130 => ("rt_sigsuspend", false),
+        202 => ("futex", false),
+        219 => ("restart_syscall", false),
+        230 => ("clock_nanosleep", false),
+        232 => ("epoll_wait", true),
+        247 => ("waitid", false),
+        270 => ("pselect6", false),
+        271 => ("ppoll", false),
+        281 => ("epoll_pwait", true),
+        288 => ("accept4", true),
+        299 => ("recvmmsg", true),
+        307 => ("sendmmsg", true),
+        _ => return None
+    })
+}
+
+#[cfg(not(all(target_os="linux", target_arch="x86_64")))]
+fn syscall_name(_number: i64) -> Option<(&'static str, bool)> {
+    None
+}
+
+#[cfg(all(test, target_os="linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_syscall() {
+        let pid = std::process::id() as Pid;
+        let tid = pid as Tid;
+
+        let futex = remoteprocess::Syscall{number: 202, args: [0x7f0a28000b60, 0x80, 0, 0, 0, 0]};
+        assert_eq!(describe_syscall(pid, tid, &futex).to_string(), "futex on lock 0x7f0a28000b60");
+
+        let unknown = remoteprocess::Syscall{number: 100000, args: [0; 6]};
+        assert_eq!(describe_syscall(pid, tid, &unknown).to_string(), "syscall 100000");
+        assert_eq!(syscall_label(100000), ("syscall 100000".to_owned(), false));
+
+        // fd 1 is stdout, which will resolve to something (a pipe or a tty)
+        let write = remoteprocess::Syscall{number: 1, args: [1, 0, 0, 0, 0, 0]};
+        let blocked = describe_syscall(pid, tid, &write);
+        assert_eq!(blocked.syscall, "write");
+        assert!(blocked.target.is_some());
+    }
+}
@@ -1,4 +1,5 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgGroup};
+use regex::Regex;
 use remoteprocess::Pid;
 
 /// Options on how to collect samples from a python process
@@ -15,6 +16,24 @@ pub struct Config {
     /// the native stack traces
     pub native: bool,
 
+    /// Whether to defer symbolicating native frames. Native frames outside of the python
+    /// interpreter are returned unresolved, with their instruction pointer set in `Frame::addr`,
+    /// which avoids symbol lookups while the target process is paused
+    pub defer_symbols: bool,
+
+    /// Whether to only collect native stack traces. This lets us profile processes
+    /// that don't contain a python interpreter at all (and implies native)
+    pub native_only: bool,
+
+    /// A regex matching the path of the shared library containing the python interpreter.
+    /// Overrides the default libpython detection, for programs that embed python from a
+    /// renamed library
+    pub python_lib: Option<String>,
+
+    /// Whether to also return the coroutine stacks of pending asyncio tasks, as pseudo-threads.
+    /// Only supported for python 3.7+
+    pub asyncio: bool,
+
     // The following config options only apply when using py-spy as an application
     #[doc(hidden)]
     pub command: String,
@@ -46,6 +65,58 @@ pub struct Config {
     pub end_ts: u64,
     #[doc(hidden)]
     pub data_file: Option<String>,
+    #[doc(hidden)]
+    pub input_file: Option<String>,
+    #[doc(hidden)]
+    pub symbol_dirs: Vec<String>,
+    #[doc(hidden)]
+    pub deadlocks: bool,
+    #[doc(hidden)]
+    pub dump_interval: Option<u64>,
+    #[doc(hidden)]
+    pub dump_count: Option<u64>,
+    #[doc(hidden)]
+    pub dump_json: bool,
+    #[doc(hidden)]
+    pub all_processes: bool,
+    #[doc(hidden)]
+    pub process_name: Option<String>,
+    #[doc(hidden)]
+    pub process_cmdline: Option<String>,
+    #[doc(hidden)]
+    pub wait: bool,
+    #[doc(hidden)]
+    pub follow_restarts: bool,
+    #[doc(hidden)]
+    pub imports: bool,
+    #[doc(hidden)]
+    pub control_file: Option<String>,
+    #[doc(hidden)]
+    pub start_paused: bool,
+    #[doc(hidden)]
+    pub trigger_cpu: Option<u64>,
+    #[doc(hidden)]
+    pub trigger_stuck: Option<u64>,
+    #[doc(hidden)]
+    pub trigger_function: Option<String>,
+    #[doc(hidden)]
+    pub trigger_before: u64,
+    #[doc(hidden)]
+    pub trigger_after: u64,
+    #[doc(hidden)]
+    pub rotate: Option<u64>,
+    #[doc(hidden)]
+    pub keep_files: Option<usize>,
+    #[doc(hidden)]
+    pub keep_for: Option<u64>,
+    #[doc(hidden)]
+    pub max_overhead: Option<u64>,
+    #[doc(hidden)]
+    pub save_failed_samples: Option<String>,
+    #[doc(hidden)]
+    pub retain: u64,
+    #[doc(hidden)]
+    pub socket: Option<String>,
 }
 
 arg_enum!{
@@ -70,9 +141,15 @@ impl Default for Config {
         Config{pid: None, python_program: None, filename: None, format: None,
                command: String::from("top"), idlelist: None,
                non_blocking: false, show_line_numbers: false, sampling_rate: 100,
-               duration: RecordDuration::Unlimited, native: false,
+               duration: RecordDuration::Unlimited, native: false, defer_symbols: false,
+               native_only: false, python_lib: None, asyncio: false,
                gil_only: false, include_idle: false, include_thread_ids: false,
-               start_ts: 0, end_ts: 0, data_file: None}
+               start_ts: 0, end_ts: 0, data_file: None, input_file: None, symbol_dirs: Vec::new(),
+               deadlocks: false, dump_interval: None, dump_count: None, dump_json: false,
+               all_processes: false, process_name: None, process_cmdline: None, wait: false,
+               follow_restarts: false, imports: false, control_file: None, start_paused: false,
+               trigger_cpu: None, trigger_stuck: None, trigger_function: None, trigger_before: 10, trigger_after: 5,
+               rotate: None, keep_files: None, keep_for: None, max_overhead: None, save_failed_samples: None, retain: 300, socket: None}
     }
 }
 
@@ -95,16 +172,58 @@ impl Config {
                     .value_name("pid")
                     .help("PID of a running python program to spy on")
                     .takes_value(true)
-                    .required_unless("python_program");
+                    .required_unless_one(&["python_program", "all", "name", "cmdline"]);
         let native = Arg::with_name("native")
                     .short("n")
                     .long("native")
                     .hidden(!allow_native)
                     .help("Collect stack traces from native extensions written in Cython, C or C++");
+        let native_only = Arg::with_name("native_only")
+                    .long("native-only")
+                    .hidden(!allow_native)
+                    .conflicts_with("nonblocking")
+                    .help("Only collect native stack traces, without looking for a python interpreter. \
+                          Allows profiling processes that aren't running python");
         let nonblocking = Arg::with_name("nonblocking")
                     .long("nonblocking")
                     .help("Don't pause the python process when collecting samples. Setting this option will reduce \
                           the perfomance impact of sampling, but may lead to inaccurate results");
+        let python_lib = Arg::with_name("python_lib")
+                    .long("python-lib")
+                    .value_name("regex")
+                    .help("Regex matching the path of the library that contains the python interpreter, \
+                          for programs that embed python in a renamed library")
+                    .takes_value(true)
+                    .validator(validate_regex);
+        let all = Arg::with_name("all")
+                    .long("all")
+                    .help("Show every python process on the system that we have permission to access (linux only)")
+                    .conflicts_with_all(&["pid", "python_program", "name", "cmdline"]);
+        let name = Arg::with_name("name")
+                    .long("name")
+                    .value_name("regex")
+                    .help("Spy on the python process whose name matches this regex (linux only)")
+                    .takes_value(true)
+                    .validator(validate_regex)
+                    .conflicts_with_all(&["pid", "python_program"]);
+        let cmdline = Arg::with_name("cmdline")
+                    .long("cmdline")
+                    .value_name("regex")
+                    .help("Spy on the python process whose command line matches this regex (linux only)")
+                    .takes_value(true)
+                    .validator(validate_regex)
+                    .conflicts_with_all(&["pid", "python_program"]);
+        let selector = ArgGroup::with_name("selector")
+                    .args(&["name", "cmdline"])
+                    .multiple(true);
+        let wait = Arg::with_name("wait")
+                    .long("wait")
+                    .help("Wait for a process matching --name or --cmdline to start. When recording, \
+                          this also waits for a new matching process if the current one exits")
+                    .requires("selector");
+        let asyncio = Arg::with_name("async")
+                    .long("async")
+                    .help("Show the coroutines that each pending asyncio task is waiting on (python 3.7+)");
         let rate = Arg::with_name("rate")
                     .short("r")
                     .long("rate")
@@ -115,9 +234,28 @@ impl Config {
         let program = Arg::with_name("python_program")
                     .help("commandline of a python program to run")
                     .multiple(true);
+        let output = Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("filename")
+                    .help("Output filename")
+                    .takes_value(true)
+                    .required(true);
+        let format = Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("format")
+                    .help("Output file format")
+                    .takes_value(true)
+                    .possible_values(&FileFormat::variants())
+                    .case_insensitive(true)
+                    .default_value("flamegraph");
+        let function = Arg::with_name("function")
+                    .short("F")
+                    .long("function")
+                    .help("Aggregate samples by function name instead of by line number");
         let idlelist = Arg::with_name("idlelist")
-                    .short("i")
-                    .long("idle")
+                    .long("idle-list")
                     .value_name("idlelist")
                     .help("A list of functions representing the current thread is idle.")
                     .takes_value(true);
@@ -133,22 +271,17 @@ impl Config {
                 .about("Records raw stack trace information to file")
                 .arg(program.clone())
                 .arg(pid.clone())
-                .arg(Arg::with_name("output")
-                    .short("o")
-                    .long("output")
-                    .value_name("filename")
-                    .help("Output filename")
-                    .takes_value(true)
-                    .required(true))
-                .arg(Arg::with_name("format")
-                    .short("f")
-                    .long("format")
-                    .value_name("format")
-                    .help("Output file format")
-                    .takes_value(true)
-                    .possible_values(&FileFormat::variants())
-                    .case_insensitive(true)
-                    .default_value("flamegraph"))
+                .arg(name.clone())
+                .arg(cmdline.clone())
+                .arg(wait.clone())
+                .group(selector.clone())
+                .arg(Arg::with_name("follow_restarts")
+                    .long("follow-restarts")
+                    .help("Keep recording when the process exits, by attaching to the process that replaces it \
+                          (a process with the same command line, or matching --name/--cmdline). Linux only")
+                    .conflicts_with("python_program"))
+                .arg(output.clone())
+                .arg(format.clone())
                 .arg(Arg::with_name("duration")
                     .short("d")
                     .long("duration")
@@ -157,10 +290,19 @@ impl Config {
                     .default_value("unlimited")
                     .takes_value(true))
                 .arg(rate.clone())
-                .arg(Arg::with_name("function")
-                    .short("F")
-                    .long("function")
-                    .help("Aggregate samples by function name instead of by line number"))
+                .arg(Arg::with_name("max_overhead")
+                    .long("max-overhead")
+                    .value_name("percent")
+                    .help("Lower the sampling rate when needed to keep the process paused for less than this \
+                          percentage of the time. Samples are weighted so that the results stay accurate")
+                    .validator(validate_percent)
+                    .takes_value(true))
+                .arg(Arg::with_name("save_failed_samples")
+                    .long("save-failed-samples")
+                    .value_name("filename")
+                    .help("Append the errors from samples that failed to this file as JSON lines, for debugging")
+                    .takes_value(true))
+                .arg(function.clone())
                 .arg(Arg::with_name("gil")
                     .short("g")
                     .long("gil")
@@ -174,22 +316,189 @@ impl Config {
                     .long("idle")
                     .help("Include stack traces for idle threads"))
                 .arg(native.clone())
+                .arg(native_only.clone())
+                .arg(Arg::with_name("defer_symbols")
+                    .long("defer-symbols")
+                    .hidden(!allow_native)
+                    .help("Store raw native addresses along with a snapshot of the loaded binaries, \
+                          instead of symbolicating native frames while sampling. Use 'py-spy symbolize' \
+                          to resolve the recording afterwards"))
+                .arg(Arg::with_name("control_file")
+                    .long("control-file")
+                    .value_name("path")
+                    .help("A file or FIFO to read 'start', 'stop' and 'flush' commands from. Stopping or flushing \
+                          writes the samples captured so far to a new numbered output file. SIGUSR1 also \
+                          starts or stops capturing, and SIGUSR2 flushes")
+                    .takes_value(true))
+                .arg(Arg::with_name("paused")
+                    .long("paused")
+                    .help("Don't capture any samples until started by SIGUSR1 or the control file"))
+                .arg(Arg::with_name("trigger_cpu")
+                    .long("trigger-cpu")
+                    .value_name("percent")
+                    .help("Only write out samples when the process uses more than this percentage of a CPU (linux only)")
+                    .validator(validate_percent)
+                    .takes_value(true))
+                .arg(Arg::with_name("trigger_stuck")
+                    .long("trigger-stuck")
+                    .value_name("seconds")
+                    .help("Only write out samples when an active thread stays in the same function for this long")
+                    .validator(validate_seconds)
+                    .takes_value(true))
+                .arg(Arg::with_name("trigger_function")
+                    .long("trigger-function")
+                    .value_name("regex")
+                    .help("Only write out samples when a function matching this regex shows up in a stack")
+                    .validator(validate_regex)
+                    .takes_value(true))
+                .group(ArgGroup::with_name("trigger")
+                    .args(&["trigger_cpu", "trigger_stuck", "trigger_function"])
+                    .multiple(true)
+                    .conflicts_with_all(&["control_file", "paused"]))
+                .arg(Arg::with_name("trigger_before")
+                    .long("trigger-before")
+                    .value_name("seconds")
+                    .help("The number of seconds before a trigger fires to include in the output [default: 10]")
+                    .requires("trigger")
+                    .takes_value(true))
+                .arg(Arg::with_name("trigger_after")
+                    .long("trigger-after")
+                    .value_name("seconds")
+                    .help("The number of seconds after a trigger fires to include in the output [default: 5]")
+                    .requires("trigger")
+                    .takes_value(true))
+                .arg(Arg::with_name("rotate")
+                    .long("rotate")
+                    .value_name("seconds")
+                    .help("Write out a new file every this many seconds. The output filename is used as a template, \
+                          with %Y %m %d %H %M %S replaced by the time the file was started, %p by the pid \
+                          and %n by the file number (like profile-%Y%m%dT%H%M%S-%p.svg)")
+                    .conflicts_with_all(&["control_file", "paused", "trigger"])
+                    .validator(validate_seconds)
+                    .takes_value(true))
+                .arg(Arg::with_name("keep_files")
+                    .long("keep-files")
+                    .value_name("count")
+                    .help("Delete the oldest rotated files, keeping at most this many")
+                    .validator(validate_count)
+                    .requires("rotate")
+                    .takes_value(true))
+                .arg(Arg::with_name("keep_for")
+                    .long("keep-for")
+                    .value_name("age")
+                    .help("Delete rotated files older than this (in seconds, or like 30m, 12h or 7d)")
+                    .validator(|age| parse_age(&age).map(|_| ()))
+                    .requires("rotate")
+                    .takes_value(true))
+                .arg(Arg::with_name("imports")
+                    .long("imports")
+                    .help("Print a table of how long importing each module took. Use this when launching \
+                          the program to profile how long it takes to start up"))
                 .arg(nonblocking.clone())
+                .arg(python_lib.clone())
+                .arg(asyncio.clone())
                 .arg(idlelist.clone())
             )
+            .subcommand(clap::SubCommand::with_name("flight-recorder")
+                .about("Samples continuously, keeping the last few minutes in memory to write out on demand")
+                .arg(program.clone())
+                .arg(pid.clone())
+                .arg(name.clone())
+                .arg(cmdline.clone())
+                .arg(wait.clone())
+                .group(selector.clone())
+                .arg(output.clone()
+                    .help("Output filename. Each dump is written to a new numbered file (like profile.1.svg)"))
+                .arg(format.clone())
+                .arg(rate.clone().default_value("10"))
+                .arg(Arg::with_name("retain")
+                    .long("retain")
+                    .value_name("age")
+                    .help("How much history to keep (in seconds, or like 30m or 1h)")
+                    .default_value("5m")
+                    .validator(|age| parse_age(&age).map(|_| ()))
+                    .takes_value(true))
+                .arg(Arg::with_name("socket")
+                    .long("socket")
+                    .value_name("path")
+                    .help("Listen on a unix socket for 'dump [format] [path]' commands. SIGUSR1 or SIGUSR2 \
+                          also write out the samples")
+                    .takes_value(true))
+                .arg(function.clone())
+                .arg(Arg::with_name("idle")
+                    .short("i")
+                    .long("idle")
+                    .help("Include stack traces for idle threads"))
+                .arg(native.clone())
+                .arg(nonblocking.clone())
+                .arg(python_lib.clone())
+            )
             .subcommand(clap::SubCommand::with_name("top")
                 .about("Displays a top like view of functions consuming CPU")
                 .arg(program.clone())
                 .arg(pid.clone())
+                .arg(all.clone())
+                .arg(name.clone())
+                .arg(cmdline.clone())
+                .arg(wait.clone())
+                .group(selector.clone())
                 .arg(rate.clone())
                 .arg(native.clone())
+                .arg(native_only.clone())
                 .arg(nonblocking.clone())
+                .arg(python_lib.clone())
             )
             .subcommand(clap::SubCommand::with_name("dump")
                 .about("Dumps stack traces for a target program to stdout")
-                .arg(pid.clone().required(true))
+                .arg(pid.clone())
+                .arg(all.clone())
+                .arg(name.clone())
+                .arg(cmdline.clone())
+                .arg(wait.clone())
+                .group(selector.clone())
                 .arg(native.clone())
+                .arg(native_only.clone())
                 .arg(nonblocking.clone())
+                .arg(python_lib.clone())
+                .arg(asyncio.clone())
+                .arg(Arg::with_name("deadlocks")
+                    .long("deadlocks")
+                    .help("Find threads that are deadlocked waiting on locks held by each other (python 3.7+, linux only)")
+                    .conflicts_with("nonblocking"))
+                .arg(Arg::with_name("interval")
+                    .long("interval")
+                    .value_name("secs")
+                    .help("Take a snapshot every 'secs' seconds, only showing the threads whose stacks changed")
+                    .conflicts_with_all(&["deadlocks", "all"])
+                    .validator(validate_seconds)
+                    .takes_value(true))
+                .arg(Arg::with_name("count")
+                    .long("count")
+                    .value_name("count")
+                    .help("Number of snapshots to take with --interval (defaults to taking snapshots until Control-C)")
+                    .requires("interval")
+                    .takes_value(true))
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Format the output as JSON")
+                    .conflicts_with_all(&["interval", "deadlocks", "all"]))
+            )
+            .subcommand(clap::SubCommand::with_name("symbolize")
+                .about("Resolves native frames in a recording made with 'record --defer-symbols'")
+                .arg(Arg::with_name("input")
+                    .help("Recording written by 'record --defer-symbols'")
+                    .required(true))
+                .arg(output.clone())
+                .arg(format.clone())
+                .arg(function.clone())
+                .arg(Arg::with_name("debug_dir")
+                    .long("debug-dir")
+                    .value_name("dir")
+                    .help("Directory to search for the recorded binaries (by build-id or by filename), \
+                          for instance when symbolicating on another machine")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1))
             )
             .subcommand(clap::SubCommand::with_name("display")
                 .about("Shows the result in flamegraph or speedscope")
@@ -214,7 +523,11 @@ impl Config {
                     .default_value("2")
                     .takes_value(true))
             )
-            .get_matches_from_safe(args)?;
+            .get_matches_from_safe(args.iter().enumerate().map(|(i, arg)| {
+                // 'd' is ambiguous between 'dump' and 'display' with InferSubcommands,
+                // and clap doesn't check exact aliases first - so expand it here
+                if i == 1 && arg == "d" { "dump" } else { arg.as_str() }
+            }))?;
         info!("Command line args: {:?}", matches);
 
         let mut config = Config::default();
@@ -232,10 +545,63 @@ impl Config {
                 config.format = Some(value_t!(matches.value_of("format"), FileFormat).unwrap_or_else(|e| e.exit()));
                 config.filename = matches.value_of("output").map(|f| f.to_owned());
                 config.idlelist = matches.value_of("idlelist").map(|f| f.to_owned());
+                config.defer_symbols = matches.occurrences_of("defer_symbols") > 0;
+                config.control_file = matches.value_of("control_file").map(|f| f.to_owned());
+                config.start_paused = matches.occurrences_of("paused") > 0;
+                if matches.is_present("trigger_cpu") {
+                    config.trigger_cpu = Some(value_t!(matches, "trigger_cpu", u64)?);
+                }
+                if matches.is_present("trigger_stuck") {
+                    config.trigger_stuck = Some(value_t!(matches, "trigger_stuck", u64)?);
+                }
+                config.trigger_function = matches.value_of("trigger_function").map(|f| f.to_owned());
+                if matches.is_present("trigger_before") {
+                    config.trigger_before = value_t!(matches, "trigger_before", u64)?;
+                }
+                if matches.is_present("trigger_after") {
+                    config.trigger_after = value_t!(matches, "trigger_after", u64)?;
+                }
+                if matches.is_present("rotate") {
+                    config.rotate = Some(value_t!(matches, "rotate", u64)?);
+                }
+                if matches.is_present("keep_files") {
+                    config.keep_files = Some(value_t!(matches, "keep_files", usize)?);
+                }
+                if let Some(age) = matches.value_of("keep_for") {
+                    config.keep_for = Some(parse_age(age).expect("invalid age"));
+                }
+                if matches.is_present("max_overhead") {
+                    config.max_overhead = Some(value_t!(matches, "max_overhead", u64)?);
+                }
+                config.save_failed_samples = matches.value_of("save_failed_samples").map(|f| f.to_owned());
+            },
+            "flight-recorder" => {
+                config.sampling_rate = value_t!(matches, "rate", u64)?;
+                config.format = Some(value_t!(matches.value_of("format"), FileFormat).unwrap_or_else(|e| e.exit()));
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.retain = parse_age(matches.value_of("retain").unwrap()).expect("invalid age");
+                config.socket = matches.value_of("socket").map(|f| f.to_owned());
+            },
+            "symbolize" => {
+                config.format = Some(value_t!(matches.value_of("format"), FileFormat).unwrap_or_else(|e| e.exit()));
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.input_file = matches.value_of("input").map(|f| f.to_owned());
+                config.symbol_dirs = matches.values_of("debug_dir").map(|vals| {
+                    vals.map(|v| v.to_owned()).collect()
+                }).unwrap_or_default();
             },
             "top" => {
                 config.sampling_rate = value_t!(matches, "rate", u64)?;
             },
+            "dump" => {
+                if matches.is_present("interval") {
+                    config.dump_interval = Some(value_t!(matches, "interval", u64)?);
+                }
+                if matches.is_present("count") {
+                    config.dump_count = Some(value_t!(matches, "count", u64)?);
+                }
+                config.dump_json = matches.occurrences_of("json") > 0;
+            },
             "display" => {
                 config.start_ts = value_t!(matches, "start_timestamp", u64)?;
                 config.end_ts = value_t!(matches, "end_timestamp", u64)?;
@@ -253,26 +619,78 @@ impl Config {
         config.show_line_numbers = matches.occurrences_of("function") == 0;
         config.include_idle = matches.occurrences_of("idle") > 0;
         config.gil_only = matches.occurrences_of("gil") > 0;
+        config.all_processes = matches.occurrences_of("all") > 0;
+        config.process_name = matches.value_of("name").map(|f| f.to_owned());
+        config.process_cmdline = matches.value_of("cmdline").map(|f| f.to_owned());
+        config.wait = matches.occurrences_of("wait") > 0;
+        config.follow_restarts = matches.occurrences_of("follow_restarts") > 0;
+        config.imports = matches.occurrences_of("imports") > 0;
         config.include_thread_ids = matches.occurrences_of("threads") > 0;
 
         config.non_blocking = matches.occurrences_of("nonblocking") > 0;
-        config.native = matches.occurrences_of("native") > 0;
+        config.python_lib = matches.value_of("python_lib").map(|f| f.to_owned());
+        config.asyncio = matches.occurrences_of("async") > 0;
+        config.deadlocks = matches.occurrences_of("deadlocks") > 0;
+        config.native_only = matches.occurrences_of("native_only") > 0;
+        config.native = matches.occurrences_of("native") > 0 || config.defer_symbols || config.native_only;
 
         // disable native profiling if invalidly asked for
         if !allow_native && config.native {
             error!("Native stack traces are not yet supported on this OS. Disabling");
             config.native = false;
+            config.defer_symbols = false;
+            config.native_only = false;
         }
 
         if config.native && config.non_blocking {
             error!("Can't get native stack traces with the --nonblocking option. Disabling native.");
             config.native = false;
+            config.defer_symbols = false;
         }
 
         Ok(config)
     }
 }
 
+fn validate_regex(pattern: String) -> Result<(), String> {
+    Regex::new(&pattern).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn validate_percent(percent: String) -> Result<(), String> {
+    match percent.parse::<u64>() {
+        Ok(percent) if (1..=100).contains(&percent) => Ok(()),
+        _ => Err(format!("Invalid percentage '{}', expected a number from 1 to 100", percent))
+    }
+}
+
+fn validate_seconds(seconds: String) -> Result<(), String> {
+    match seconds.parse::<u64>() {
+        Ok(seconds) if seconds >= 1 => Ok(()),
+        _ => Err(format!("Invalid number of seconds '{}', expected a whole number of at least 1", seconds))
+    }
+}
+
+fn validate_count(count: String) -> Result<(), String> {
+    match count.parse::<usize>() {
+        Ok(count) if count >= 1 => Ok(()),
+        _ => Err(format!("Invalid count '{}', expected a whole number of at least 1", count))
+    }
+}
+
+// Parses an age like '90', '30m', '12h' or '7d' into seconds
+fn parse_age(age: &str) -> Result<u64, String> {
+    let (number, multiplier) = match age.chars().last() {
+        Some('s') => (&age[..age.len() - 1], 1),
+        Some('m') => (&age[..age.len() - 1], 60),
+        Some('h') => (&age[..age.len() - 1], 60 * 60),
+        Some('d') => (&age[..age.len() - 1], 24 * 60 * 60),
+        _ => (age, 1)
+    };
+    number.parse::<u64>()
+        .map(|number| number * multiplier)
+        .map_err(|_| format!("Invalid age '{}', expected a number of seconds or a value like 30m, 12h or 7d", age))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -345,6 +763,298 @@ mod tests {
         assert_eq!(config, short_config);
     }
 
+    #[test]
+    fn test_parse_symbolize_args() {
+        let config = Config::from_args(&split("py-spy symbolize recording.json -o foo -f speedscope --debug-dir /a --debug-dir /b")).unwrap();
+        assert_eq!(config.command, String::from("symbolize"));
+        assert_eq!(config.input_file, Some(String::from("recording.json")));
+        assert_eq!(config.filename, Some(String::from("foo")));
+        assert_eq!(config.format, Some(FileFormat::speedscope));
+        assert_eq!(config.symbol_dirs, vec![String::from("/a"), String::from("/b")]);
+
+        // the input recording is required
+        assert_eq!(Config::from_args(&split("py-spy symbolize -o foo")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+
+        // deferring symbols implies native profiling
+        let config = Config::from_args(&split("py-spy record -p 1234 -o foo --defer-symbols")).unwrap();
+        assert_eq!(config.defer_symbols, true);
+        assert_eq!(config.native, true);
+    }
+
+    #[test]
+    fn test_parse_native_only_args() {
+        let config = Config::from_args(&split("py-spy dump -p 1234 --native-only")).unwrap();
+        assert_eq!(config.native_only, true);
+        assert_eq!(config.native, true);
+
+        let config = Config::from_args(&split("py-spy top -p 1234 --native-only")).unwrap();
+        assert_eq!(config.native_only, true);
+
+        // we have to pause the process to unwind native stacks
+        assert_eq!(Config::from_args(&split("py-spy record -p 1234 -o foo --native-only --nonblocking")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_python_lib_args() {
+        let config = Config::from_args(&split("py-spy dump -p 1234 --python-lib libmyapp_py")).unwrap();
+        assert_eq!(config.python_lib, Some(String::from("libmyapp_py")));
+
+        let config = Config::from_args(&split("py-spy top -p 1234")).unwrap();
+        assert_eq!(config.python_lib, None);
+
+        // the regex is validated while parsing
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --python-lib lib(")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_parse_async_args() {
+        let config = Config::from_args(&split("py-spy dump -p 1234 --async")).unwrap();
+        assert_eq!(config.asyncio, true);
+
+        let config = Config::from_args(&split("py-spy record -p 1234 -o foo --async")).unwrap();
+        assert_eq!(config.asyncio, true);
+
+        let config = Config::from_args(&split("py-spy record -p 1234 -o foo")).unwrap();
+        assert_eq!(config.asyncio, false);
+    }
+
+    #[test]
+    fn test_parse_deadlocks_args() {
+        let config = Config::from_args(&split("py-spy dump -p 1234 --deadlocks")).unwrap();
+        assert_eq!(config.deadlocks, true);
+
+        let config = Config::from_args(&split("py-spy dump -p 1234")).unwrap();
+        assert_eq!(config.deadlocks, false);
+
+        // we need to pause the process to get a consistent view of the locks
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --deadlocks --nonblocking")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_dump_interval_args() {
+        let config = Config::from_args(&split("py-spy dump -p 1234 --interval 5 --count 10")).unwrap();
+        assert_eq!(config.dump_interval, Some(5));
+        assert_eq!(config.dump_count, Some(10));
+
+        let config = Config::from_args(&split("py-spy dump -p 1234 --interval 2")).unwrap();
+        assert_eq!(config.dump_interval, Some(2));
+        assert_eq!(config.dump_count, None);
+
+        let config = Config::from_args(&split("py-spy dump -p 1234")).unwrap();
+        assert_eq!(config.dump_interval, None);
+
+        // count only makes sense when taking multiple snapshots
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --count 10")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --interval soon")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --interval 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_parse_dump_json_args() {
+        let config = Config::from_args(&split("py-spy dump -p 1234 --json")).unwrap();
+        assert_eq!(config.dump_json, true);
+
+        let config = Config::from_args(&split("py-spy dump -p 1234")).unwrap();
+        assert_eq!(config.dump_json, false);
+
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --json --interval 1")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_all_args() {
+        let config = Config::from_args(&split("py-spy dump --all")).unwrap();
+        assert_eq!(config.all_processes, true);
+        assert_eq!(config.pid, None);
+
+        let config = Config::from_args(&split("py-spy top --all")).unwrap();
+        assert_eq!(config.all_processes, true);
+
+        let config = Config::from_args(&split("py-spy dump -p 1234")).unwrap();
+        assert_eq!(config.all_processes, false);
+
+        // we still need either a pid or --all
+        assert_eq!(Config::from_args(&split("py-spy dump")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy dump --all -p 1234")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_selector_args() {
+        let config = Config::from_args(&split("py-spy record -o foo --name gunicorn --wait")).unwrap();
+        assert_eq!(config.process_name, Some("gunicorn".to_owned()));
+        assert_eq!(config.process_cmdline, None);
+        assert_eq!(config.wait, true);
+        assert_eq!(config.pid, None);
+
+        let config = Config::from_args(&split("py-spy dump --cmdline manage.py.*runserver")).unwrap();
+        assert_eq!(config.process_cmdline, Some("manage.py.*runserver".to_owned()));
+        assert_eq!(config.wait, false);
+
+        let config = Config::from_args(&split("py-spy top --name celery --cmdline worker")).unwrap();
+        assert_eq!(config.process_name, Some("celery".to_owned()));
+        assert_eq!(config.process_cmdline, Some("worker".to_owned()));
+
+        // --wait only makes sense with a selector
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --wait")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --name python")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+        assert_eq!(Config::from_args(&split("py-spy dump --name (")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_parse_follow_restarts_args() {
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234 --follow-restarts")).unwrap();
+        assert_eq!(config.follow_restarts, true);
+        assert_eq!(config.pid, Some(1234));
+
+        let config = Config::from_args(&split("py-spy record -o foo --name gunicorn --follow-restarts")).unwrap();
+        assert_eq!(config.follow_restarts, true);
+
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234")).unwrap();
+        assert_eq!(config.follow_restarts, false);
+
+        // there is nothing to follow when we launch the process ourselves
+        assert_eq!(Config::from_args(&split("py-spy record -o foo --follow-restarts -- python foo.py")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --follow-restarts")).unwrap_err().kind,
+                   clap::ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn test_parse_imports_args() {
+        let config = Config::from_args(&split("py-spy record -o foo --imports -- python -m mycli")).unwrap();
+        assert_eq!(config.imports, true);
+        assert_eq!(config.python_program, Some(vec![String::from("python"), String::from("-m"), String::from("mycli")]));
+
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234")).unwrap();
+        assert_eq!(config.imports, false);
+
+        assert_eq!(Config::from_args(&split("py-spy top -p 1234 --imports")).unwrap_err().kind,
+                   clap::ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn test_parse_control_args() {
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234 --control-file /tmp/pyspy.fifo --paused")).unwrap();
+        assert_eq!(config.control_file, Some("/tmp/pyspy.fifo".to_owned()));
+        assert_eq!(config.start_paused, true);
+
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234")).unwrap();
+        assert_eq!(config.control_file, None);
+        assert_eq!(config.start_paused, false);
+    }
+
+    #[test]
+    fn test_parse_trigger_args() {
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234 --trigger-cpu 80 --trigger-after 10")).unwrap();
+        assert_eq!(config.trigger_cpu, Some(80));
+        assert_eq!(config.trigger_stuck, None);
+        assert_eq!(config.trigger_before, 10);
+        assert_eq!(config.trigger_after, 10);
+
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234 --trigger-stuck 3 --trigger-function ^slow_ --trigger-before 30")).unwrap();
+        assert_eq!(config.trigger_stuck, Some(3));
+        assert_eq!(config.trigger_function, Some("^slow_".to_owned()));
+        assert_eq!(config.trigger_before, 30);
+
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --trigger-cpu high")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --trigger-cpu 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --trigger-stuck 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --trigger-before 30")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --trigger-cpu 80 --paused")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_rotate_args() {
+        let config = Config::from_args(&split("py-spy record -o profile-%Y%m%dT%H%M%S-%p.svg -p 1234 --rotate 600 --keep-files 24 --keep-for 7d")).unwrap();
+        assert_eq!(config.filename, Some("profile-%Y%m%dT%H%M%S-%p.svg".to_owned()));
+        assert_eq!(config.rotate, Some(600));
+        assert_eq!(config.keep_files, Some(24));
+        assert_eq!(config.keep_for, Some(7 * 24 * 60 * 60));
+
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234 --rotate 60 --keep-for 90")).unwrap();
+        assert_eq!(config.keep_files, None);
+        assert_eq!(config.keep_for, Some(90));
+
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --keep-files 3")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --rotate 60 --keep-for soon")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --rotate 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        // keeping no files would delete the one that was just written
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --rotate 60 --keep-files 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo -p 1234 --rotate 60 --trigger-cpu 90")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_max_overhead_args() {
+        let config = Config::from_args(&split("py-spy record -o foo --native --max-overhead 5 -p 1234")).unwrap();
+        assert_eq!(config.max_overhead, Some(5));
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234")).unwrap();
+        assert_eq!(config.max_overhead, None);
+
+        assert_eq!(Config::from_args(&split("py-spy record -o foo --max-overhead 0 -p 1234")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy record -o foo --max-overhead lots -p 1234")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_parse_save_failed_samples_args() {
+        let config = Config::from_args(&split("py-spy record -o foo --save-failed-samples failed.jsonl -p 1234")).unwrap();
+        assert_eq!(config.save_failed_samples, Some("failed.jsonl".to_owned()));
+        let config = Config::from_args(&split("py-spy record -o foo -p 1234")).unwrap();
+        assert_eq!(config.save_failed_samples, None);
+    }
+
+    #[test]
+    fn test_parse_flight_recorder_args() {
+        let config = Config::from_args(&split("py-spy flight-recorder -o incident.json -f speedscope -p 1234 --retain 10m --socket /tmp/py-spy.sock")).unwrap();
+        assert_eq!(config.command, "flight-recorder");
+        assert_eq!(config.pid, Some(1234));
+        assert_eq!(config.filename, Some("incident.json".to_owned()));
+        assert_eq!(config.format, Some(FileFormat::speedscope));
+        assert_eq!(config.retain, 600);
+        assert_eq!(config.socket, Some("/tmp/py-spy.sock".to_owned()));
+        assert_eq!(config.sampling_rate, 10);
+
+        let config = Config::from_args(&split("py-spy flight-recorder -o incident.svg -p 1234 -r 50")).unwrap();
+        assert_eq!(config.retain, 300);
+        assert_eq!(config.socket, None);
+        assert_eq!(config.sampling_rate, 50);
+
+        assert_eq!(Config::from_args(&split("py-spy flight-recorder -o incident.svg -p 1234 --retain forever")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90"), Ok(90));
+        assert_eq!(parse_age("30m"), Ok(1800));
+        assert_eq!(parse_age("12h"), Ok(12 * 3600));
+        assert!(parse_age("soon").is_err());
+        assert!(parse_age("").is_err());
+    }
+
     #[test]
     fn test_parse_args() {
         assert_eq!(Config::from_args(&split("py-spy dude")).unwrap_err().kind,
@@ -8,6 +8,8 @@ use std::thread;
 use console::{Term, style};
 use failure::Error;
 
+use crate::sample_error::SampleErrorKind;
+use crate::sampling_stats::{SampleCost, SamplingStats};
 use crate::stack_trace::{StackTrace, Frame};
 
 pub struct ConsoleViewer {
@@ -125,7 +127,7 @@ impl ConsoleViewer {
             ($($arg:tt)*) => { term.clear_line()?; term.write_line(&format!($($arg)*))?; }
         }
         self.console_config.reset_cursor()?;
-        let mut header_lines = if options.usage { 18 } else { 8 };
+        let mut header_lines = if options.usage { 19 } else { 9 };
 
         if let Some(delay) = self.stats.last_delay {
             let late_rate = self.stats.late_samples as f64 / self.stats.overall_samples as f64;
@@ -137,7 +139,7 @@ impl ConsoleViewer {
         }
 
         // Display aggregate stats about the process
-        out!("Collecting samples from '{}' (python v{})", style(&self.command).green(), &self.version);
+        out!("Collecting samples from '{}' ({})", style(&self.command).green(), &self.version);
 
         let error_rate = self.stats.errors as f64 / self.stats.overall_samples as f64;
         if error_rate >= 0.01 && self.stats.overall_samples > 100 {
@@ -155,6 +157,8 @@ impl ConsoleViewer {
             style(100.0 * self.stats.active as f64 / self.stats.current_samples as f64).bold(),
             style(self.stats.threads).bold());
 
+        out!("Overhead: {}", self.stats.sampling.summary());
+
         out!();
 
         // Build up the header for the table
@@ -225,10 +229,14 @@ impl ConsoleViewer {
     pub fn increment_error(&mut self, err: &Error) ->  Result<(), Error> {
         self.maybe_reset();
         self.stats.errors += 1;
-        self.stats.last_error = Some(format!("{}", err));
+        self.stats.last_error = Some(format!("{} ({})", err, SampleErrorKind::of(err)));
         self.increment_common()
     }
 
+    pub fn increment_cost(&mut self, cost: &SampleCost) {
+        self.stats.sampling.add(cost);
+    }
+
     pub fn increment_late_sample(&mut self, delay: std::time::Duration) {
         self.stats.late_samples += 1;
         self.stats.last_delay = Some(delay);
@@ -322,6 +330,7 @@ struct Stats {
     line_counts: HashMap<String, FunctionStatistics>,
     last_error: Option<String>,
     last_delay: Option<std::time::Duration>,
+    sampling: SamplingStats,
 }
 
 impl Options {
@@ -335,7 +344,7 @@ impl Stats {
         Stats{current_samples: 0, overall_samples: 0, elapsed: 0.,
               errors: 0, late_samples: 0, threads: 0, gil: 0, active: 0,
               line_counts: HashMap::new(), function_counts: HashMap::new(),
-              last_error: None, last_delay: None}
+              last_error: None, last_delay: None, sampling: SamplingStats::new()}
     }
 
     pub fn reset_current(&mut self) {
@@ -0,0 +1,203 @@
+/* Lets a running 'py-spy record' be controlled from outside, so that it only captures the
+interesting parts of a long running program.
+
+SIGUSR1 toggles whether samples are being captured, and SIGUSR2 writes out the samples captured
+so far to a new file. The same commands can be written to a control file or FIFO, one per line:
+'start', 'stop' and 'flush'.
+*/
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Command {
+    /// Start capturing if stopped, or stop if capturing (SIGUSR1)
+    Toggle,
+    Start,
+    Stop,
+    /// Write out the current window and start a new one (SIGUSR2)
+    Flush,
+}
+
+impl Command {
+    fn parse(line: &str) -> Option<Command> {
+        match line.trim() {
+            "start" => Some(Command::Start),
+            "stop" => Some(Command::Stop),
+            "flush" => Some(Command::Flush),
+            _ => None
+        }
+    }
+}
+
+static TOGGLE_PENDING: AtomicBool = AtomicBool::new(false);
+static FLUSH_PENDING: AtomicBool = AtomicBool::new(false);
+
+pub struct Controller {
+    commands: Receiver<Command>,
+}
+
+impl Controller {
+    /// Installs the signal handlers, and starts reading commands from the control file if given
+    pub fn new(control_file: Option<&str>) -> Result<Controller, Error> {
+        install_signal_handlers()?;
+
+        let (sender, commands) = channel();
+        if let Some(path) = control_file {
+            let path = PathBuf::from(path);
+            // fail early if the control file doesn't exist, rather than in the reader thread
+            let start = std::fs::metadata(&path).context(format!("Failed to open control file '{}'", path.display()))?.len();
+            std::thread::spawn(move || {
+                if let Err(err) = read_commands(&path, start, &sender) {
+                    eprintln!("Failed to read commands from '{}': {}", path.display(), err);
+                }
+            });
+        }
+        Ok(Controller{commands})
+    }
+
+    /// Returns the commands received since the last call
+    pub fn poll(&self) -> Vec<Command> {
+        let mut commands = Vec::new();
+        if TOGGLE_PENDING.swap(false, Ordering::SeqCst) {
+            commands.push(Command::Toggle);
+        }
+        if FLUSH_PENDING.swap(false, Ordering::SeqCst) {
+            commands.push(Command::Flush);
+        }
+        commands.extend(self.commands.try_iter());
+        commands
+    }
+}
+
+#[cfg(unix)]
+fn install_signal_handlers() -> Result<(), Error> {
+    extern "C" fn handle_signal(signal: libc::c_int) {
+        match signal {
+            libc::SIGUSR1 => TOGGLE_PENDING.store(true, Ordering::SeqCst),
+            libc::SIGUSR2 => FLUSH_PENDING.store(true, Ordering::SeqCst),
+            _ => {}
+        }
+    }
+
+    for &signal in &[libc::SIGUSR1, libc::SIGUSR2] {
+        if unsafe { libc::signal(signal, handle_signal as *const () as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(format_err!("Failed to install signal handler: {}", std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers() -> Result<(), Error> {
+    Ok(())
+}
+
+// Reads commands from the control file. FIFOs get reopened whenever the writer closes them, and
+// regular files are followed like 'tail -f' does, starting from the given position so that
+// commands left over in the file from before we started aren't run
+fn read_commands(path: &Path, start: u64, sender: &Sender<Command>) -> Result<(), Error> {
+    loop {
+        let file = File::open(path)?;
+        let is_fifo = is_fifo(&file)?;
+        let mut position = if is_fifo { 0 } else { start };
+        let mut reader = BufReader::new(file);
+        if !is_fifo {
+            reader.seek(SeekFrom::Start(position))?;
+        }
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 || !line.ends_with('\n') {
+                if is_fifo {
+                    break;
+                }
+                // wait for the rest of the line to be written, and start over if the file has been truncated
+                std::thread::sleep(Duration::from_millis(100));
+                let length = reader.get_ref().metadata()?.len();
+                position = if length < position { 0 } else { position };
+                reader.seek(SeekFrom::Start(position))?;
+                continue;
+            }
+            position += read as u64;
+
+            match Command::parse(&line) {
+                Some(command) => {
+                    if sender.send(command).is_err() {
+                        // we've stopped recording
+                        return Ok(());
+                    }
+                },
+                None if line.trim().is_empty() => {},
+                None => eprintln!("Unknown command '{}', expected 'start', 'stop' or 'flush'", line.trim())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_fifo(file: &File) -> Result<bool, Error> {
+    use std::os::unix::fs::FileTypeExt;
+    Ok(file.metadata()?.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_file: &File) -> Result<bool, Error> {
+    Ok(false)
+}
+
+/// Returns the filename to write a capture window to, by adding the window number before the
+/// extension (so profile.svg becomes profile.1.svg)
+pub fn window_filename(filename: &str, window: u64) -> String {
+    let path = Path::new(filename);
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!("{}.{}.{}", stem.to_string_lossy(), window, extension.to_string_lossy()),
+        _ => format!("{}.{}", path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(), window)
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_window_filename() {
+        assert_eq!(window_filename("profile.svg", 1), "profile.1.svg");
+        assert_eq!(window_filename("/tmp/out/profile.speedscope.json", 12), "/tmp/out/profile.speedscope.12.json");
+        assert_eq!(window_filename("profile", 2), "profile.2");
+    }
+
+    #[test]
+    fn test_read_commands() {
+        let mut control = tempfile::NamedTempFile::new().unwrap();
+        // commands written before we start aren't replayed
+        writeln!(control, "stop").unwrap();
+        control.flush().unwrap();
+
+        let controller = Controller::new(Some(control.path().to_str().unwrap())).unwrap();
+        write!(control, "start\nbogus\n\nflu").unwrap();
+        control.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        // partial lines are picked up once they're finished
+        writeln!(control, "sh").unwrap();
+        control.flush().unwrap();
+
+        let mut commands = Vec::new();
+        for _ in 0..50 {
+            commands.extend(controller.poll());
+            if commands.len() >= 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(commands, vec![Command::Start, Command::Flush]);
+    }
+}
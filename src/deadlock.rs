@@ -0,0 +1,339 @@
+/* Finds deadlocks between python threads.
+
+This builds a wait-for graph of the threads in the process, with the lock (or GIL) that each
+blocked thread is waiting on, and the threads that hold each lock. Any cycle in this graph
+is a deadlock.
+
+The lock a thread is waiting on comes from the futex address of the syscall it is blocked in.
+The owners depend on the type of lock:
+ * RLock objects store the thread id of their owner
+ * Lock objects don't know their owner, but locks held in a 'with' statement have their bound
+   __exit__ method stored on the value stack of the frame, underneath the 'with' block
+ * the GIL is held by the thread in _PyRuntime.gilstate.tstate_current
+
+Like the asyncio code, this relies on the layout of private structures from the _thread
+module, and only python 3.7+ is supported.
+*/
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem::size_of;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::v3_7_0::{PyCodeObject, PyFrameObject};
+use crate::python_objects::{Object, TypeNames, copy_array};
+use crate::version::Version;
+
+// block type used for 'with' statements in python 3.7 and 3.8
+const SETUP_FINALLY: i32 = 122;
+
+// the futex waited on is somewhere inside of the sem_t used to implement each lock
+const SEM_SIZE: usize = 4 * size_of::<usize>();
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct LockObject {
+    ob_base: Object,
+    lock_lock: usize,
+    in_weakreflist: usize,
+    locked: i8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct RLockObject {
+    ob_base: Object,
+    rlock_lock: usize,
+    rlock_owner: u64,
+    rlock_count: u64,
+    in_weakreflist: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+struct CFunctionObject {
+    ob_base: Object,
+    m_ml: usize,
+    m_self: usize,
+    m_module: usize,
+}
+
+/// Returns whether we can find deadlocks for this version of python
+pub fn is_supported(version: &Version) -> bool {
+    matches!(version, Version{major: 3, minor: 7..=8, ..})
+}
+
+/// Something a thread can be waiting on
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Resource {
+    Gil,
+    /// A threading.Lock or threading.RLock, given the address of the lock object
+    Lock{addr: usize, reentrant: bool},
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::Gil => write!(f, "the GIL"),
+            Resource::Lock{addr, reentrant: false} => write!(f, "Lock 0x{:x}", addr),
+            Resource::Lock{addr, reentrant: true} => write!(f, "RLock 0x{:x}", addr),
+        }
+    }
+}
+
+/// Which resource each thread is waiting on, and which threads hold each resource
+#[derive(Debug, Default)]
+pub struct WaitGraph {
+    pub waiting: BTreeMap<u64, Resource>,
+    pub owners: BTreeMap<Resource, BTreeSet<u64>>,
+}
+
+impl WaitGraph {
+    pub fn new() -> WaitGraph {
+        WaitGraph{waiting: BTreeMap::new(), owners: BTreeMap::new()}
+    }
+
+    pub fn add_waiting(&mut self, thread_id: u64, resource: Resource) {
+        self.waiting.insert(thread_id, resource);
+    }
+
+    pub fn add_owner(&mut self, resource: Resource, thread_id: u64) {
+        self.owners.entry(resource).or_default().insert(thread_id);
+    }
+
+    /// Returns each cycle in the graph, as a list of threads and the resource each is waiting
+    /// on (which is held by the next thread in the list)
+    pub fn find_cycles(&self) -> Vec<Vec<(u64, Resource)>> {
+        let mut cycles = Vec::new();
+        let mut path = Vec::new();
+        for &thread_id in self.waiting.keys() {
+            self.visit(thread_id, thread_id, &mut path, &mut cycles);
+        }
+        cycles
+    }
+
+    // Depth first search for paths leading back to the start thread. So that each cycle is
+    // only reported once, this only visits threads with a larger id than the start
+    fn visit(&self, start: u64, thread_id: u64, path: &mut Vec<(u64, Resource)>, cycles: &mut Vec<Vec<(u64, Resource)>>) {
+        let resource = match self.waiting.get(&thread_id) {
+            Some(resource) => *resource,
+            None => return
+        };
+        if path.iter().any(|&(visited, _)| visited == thread_id) {
+            return;
+        }
+
+        path.push((thread_id, resource));
+        if let Some(owners) = self.owners.get(&resource) {
+            for &owner in owners {
+                if owner == start {
+                    cycles.push(path.clone());
+                } else if owner > start {
+                    self.visit(start, owner, path, cycles);
+                }
+            }
+        }
+        path.pop();
+    }
+}
+
+/// Finds the locks referenced by python threads
+pub struct LockFinder {
+    type_names: TypeNames,
+    // the address range of the GIL's mutexes and condition variables, if known
+    gil: Option<(usize, usize)>,
+    // the locks we've found, keyed by the address of the underlying semaphore
+    locks: BTreeMap<usize, Resource>,
+}
+
+impl LockFinder {
+    pub fn new(gil: Option<(usize, usize)>) -> LockFinder {
+        LockFinder{type_names: TypeNames::new(), gil, locks: BTreeMap::new()}
+    }
+
+    /// Finds the locks referenced by the local variables and 'with' statements of a thread,
+    /// returning each lock that is held along with the thread id of the owner
+    pub fn get_locks<P: ProcessMemory>(&mut self, process: &P, thread_id: u64, mut frame_ptr: *mut PyFrameObject)
+            -> Result<Vec<(Resource, u64)>, Error> {
+        let mut held = Vec::new();
+        let mut depth = 0;
+        while !frame_ptr.is_null() {
+            let frame: PyFrameObject = process.copy_pointer(frame_ptr).context("Failed to copy PyFrameObject")?;
+            let code: PyCodeObject = process.copy_pointer(frame.f_code).context("Failed to copy PyCodeObject")?;
+
+            // local variables are stored in f_localsplus, at the end of the frame
+            if code.co_nlocals < 0 || code.co_nlocals > 4096 {
+                return Err(format_err!("Invalid number of locals: {}", code.co_nlocals));
+            }
+            let offset = &frame.f_localsplus as *const _ as usize - &frame as *const _ as usize;
+            let locals: Vec<usize> = copy_array(process, frame_ptr as usize + offset, code.co_nlocals as usize)?;
+            for &local in locals.iter().filter(|&&local| local != 0) {
+                if let Some((resource, Some(owner))) = self.add_lock(process, local)? {
+                    held.push((resource, owner));
+                }
+            }
+
+            // 'with' blocks store the bound __exit__ method of the context manager on the value stack
+            let blocks = frame.f_iblock.max(0).min(frame.f_blockstack.len() as i32) as usize;
+            for block in frame.f_blockstack[..blocks].iter() {
+                if block.b_type != SETUP_FINALLY || block.b_level < 1 {
+                    continue;
+                }
+                let exit: usize = process.copy_struct(frame.f_valuestack as usize + (block.b_level as usize - 1) * size_of::<usize>())?;
+                if exit == 0 || self.type_names.get(process, exit)? != "builtin_function_or_method" {
+                    continue;
+                }
+                let method: CFunctionObject = process.copy_struct(exit)?;
+                if method.m_self == 0 {
+                    continue;
+                }
+                match self.add_lock(process, method.m_self)? {
+                    Some((resource, Some(owner))) => held.push((resource, owner)),
+                    Some((resource, None)) => held.push((resource, thread_id)),
+                    None => {}
+                }
+            }
+
+            depth += 1;
+            if depth > 4096 {
+                return Err(format_err!("Max frame recursion depth reached"));
+            }
+            frame_ptr = frame.f_back;
+        }
+        Ok(held)
+    }
+
+    /// Returns the lock (or the GIL) that a thread blocked on a futex is waiting on
+    pub fn get_futex_resource(&self, addr: usize) -> Option<Resource> {
+        if let Some((start, end)) = self.gil {
+            if addr >= start && addr < end {
+                return Some(Resource::Gil);
+            }
+        }
+
+        let (&sem, &resource) = self.locks.range(..=addr).next_back()?;
+        if addr < sem + SEM_SIZE {
+            Some(resource)
+        } else {
+            None
+        }
+    }
+
+    // Records the lock at addr if the object is a lock, returning the lock and the owner (if known)
+    fn add_lock<P: ProcessMemory>(&mut self, process: &P, addr: usize) -> Result<Option<(Resource, Option<u64>)>, Error> {
+        let lock = match self.type_names.get(process, addr)?.as_ref() {
+            "_thread.lock" => {
+                let lock: LockObject = process.copy_struct(addr).context("Failed to copy lock")?;
+                let resource = Resource::Lock{addr, reentrant: false};
+                self.locks.insert(lock.lock_lock, resource);
+                (resource, None)
+            },
+            "_thread.RLock" => {
+                let lock: RLockObject = process.copy_struct(addr).context("Failed to copy RLock")?;
+                let resource = Resource::Lock{addr, reentrant: true};
+                self.locks.insert(lock.rlock_lock, resource);
+                let owner = if lock.rlock_count > 0 { Some(lock.rlock_owner) } else { None };
+                (resource, owner)
+            },
+            _ => return Ok(None)
+        };
+        Ok(Some(lock))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+    use crate::python_bindings::v3_7_0::{PyTryBlock, _typeobject};
+
+    fn lock(addr: usize) -> Resource {
+        Resource::Lock{addr, reentrant: false}
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        // thread 1 holds lock a and waits on b, thread 2 holds b and waits on a
+        let mut graph = WaitGraph::new();
+        graph.add_owner(lock(0xa), 1);
+        graph.add_owner(lock(0xb), 2);
+        graph.add_waiting(1, lock(0xb));
+        graph.add_waiting(2, lock(0xa));
+        // thread 3 is waiting on thread 1, but isn't part of the cycle
+        graph.add_waiting(3, lock(0xa));
+        assert_eq!(graph.find_cycles(), vec![vec![(1, lock(0xb)), (2, lock(0xa))]]);
+
+        // a chain of threads waiting on each other isn't a deadlock
+        let mut graph = WaitGraph::new();
+        graph.add_owner(lock(0xa), 1);
+        graph.add_owner(Resource::Gil, 2);
+        graph.add_waiting(1, Resource::Gil);
+        graph.add_waiting(3, lock(0xa));
+        assert!(graph.find_cycles().is_empty());
+
+        // a thread trying to acquire a lock it already holds deadlocks itself
+        let mut graph = WaitGraph::new();
+        graph.add_owner(lock(0xa), 7);
+        graph.add_waiting(7, lock(0xa));
+        assert_eq!(graph.find_cycles(), vec![vec![(7, lock(0xa))]]);
+    }
+
+    #[test]
+    fn test_find_cycles_gil() {
+        // thread 2 holds the GIL while waiting on a lock held by thread 5, which needs the GIL
+        let mut graph = WaitGraph::new();
+        graph.add_owner(Resource::Gil, 2);
+        graph.add_owner(lock(0xa), 5);
+        graph.add_waiting(5, Resource::Gil);
+        graph.add_waiting(2, lock(0xa));
+        graph.add_waiting(9, Resource::Gil);
+        assert_eq!(graph.find_cycles(), vec![vec![(2, lock(0xa)), (5, Resource::Gil)]]);
+    }
+
+    #[repr(C)]
+    struct LocalFrame {
+        frame: PyFrameObject,
+        // storage for the rest of f_localsplus
+        locals: [usize; 1],
+    }
+
+    #[test]
+    fn test_get_locks() {
+        let lock_type = _typeobject{tp_name: b"_thread.lock\0".as_ptr() as *const _, ..Default::default()};
+        let rlock_type = _typeobject{tp_name: b"_thread.RLock\0".as_ptr() as *const _, ..Default::default()};
+        let method_type = _typeobject{tp_name: b"builtin_function_or_method\0".as_ptr() as *const _, ..Default::default()};
+
+        let semaphores = [0u64; 8];
+        let with_lock = LockObject{ob_base: Object{ob_refcnt: 1, ob_type: &lock_type as *const _ as usize},
+                                   lock_lock: &semaphores[0] as *const _ as usize, in_weakreflist: 0, locked: 1};
+        let local_rlock = RLockObject{ob_base: Object{ob_refcnt: 1, ob_type: &rlock_type as *const _ as usize},
+                                      rlock_lock: &semaphores[4] as *const _ as usize, rlock_owner: 42,
+                                      rlock_count: 1, in_weakreflist: 0};
+        let exit = CFunctionObject{ob_base: Object{ob_refcnt: 1, ob_type: &method_type as *const _ as usize},
+                                   m_ml: 0, m_self: &with_lock as *const _ as usize, m_module: 0};
+
+        let code = PyCodeObject{co_nlocals: 2, ..Default::default()};
+        let valuestack = [&exit as *const _ as usize, 0];
+        let mut frame = LocalFrame{frame: PyFrameObject::default(), locals: [&local_rlock as *const _ as usize]};
+        frame.frame.f_code = &code as *const _ as *mut _;
+        frame.frame.f_valuestack = valuestack.as_ptr() as *mut _;
+        frame.frame.f_iblock = 1;
+        frame.frame.f_blockstack[0] = PyTryBlock{b_type: SETUP_FINALLY, b_handler: 0, b_level: 1};
+
+        let mut finder = LockFinder::new(Some((0x1000, 0x1100)));
+        let held = finder.get_locks(&LocalProcess, 7, &mut frame.frame).unwrap();
+        let with_resource = lock(&with_lock as *const _ as usize);
+        let rlock_resource = Resource::Lock{addr: &local_rlock as *const _ as usize, reentrant: true};
+        assert_eq!(held, vec![(rlock_resource, 42), (with_resource, 7)]);
+
+        // futexes inside of each semaphore map back to the lock object
+        assert_eq!(finder.get_futex_resource(&semaphores[0] as *const _ as usize), Some(with_resource));
+        assert_eq!(finder.get_futex_resource(&semaphores[4] as *const _ as usize), Some(rlock_resource));
+        assert_eq!(finder.get_futex_resource(0x1010), Some(Resource::Gil));
+        assert_eq!(finder.get_futex_resource(0x2000), None);
+    }
+}
@@ -0,0 +1,110 @@
+/* The JSON output format for 'py-spy dump --json'.
+
+This is meant to be consumed by other programs, so these structs are kept separate from
+StackTrace/Frame to keep the output stable as the internals change. Adding new fields is fine,
+but renaming or removing a field (or changing what it means) requires bumping SCHEMA_VERSION.
+*/
+use std::io::Write;
+
+use failure::Error;
+use serde_json;
+
+use crate::stack_trace::{StackTrace, Frame};
+
+/// The version of the output format
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Dump {
+    pub schema_version: u32,
+    pub pid: i64,
+    pub exe: String,
+    /// The python version, or null for 'dump --native-only'
+    pub python_version: Option<String>,
+    pub threads: Vec<Thread>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Thread {
+    pub thread_id: u64,
+    pub os_thread_id: Option<u64>,
+    pub interpreter_id: i64,
+    /// The name of the asyncio task, for pseudo-threads showing the coroutines of a task
+    pub task_name: Option<String>,
+    pub active: bool,
+    pub owns_gil: bool,
+    /// The frames of the thread, with the innermost frame first
+    pub frames: Vec<ThreadFrame>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ThreadFrame {
+    pub name: String,
+    pub filename: String,
+    pub short_filename: Option<String>,
+    pub module: Option<String>,
+    /// The line number, or null for native frames without line information
+    pub line: Option<i32>,
+}
+
+impl Dump {
+    pub fn new(pid: i64, exe: String, python_version: Option<String>, traces: &[StackTrace]) -> Dump {
+        let threads = traces.iter().map(Thread::from).collect();
+        Dump{schema_version: SCHEMA_VERSION, pid, exe, python_version, threads}
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        serde_json::to_writer_pretty(&mut *w, self)?;
+        writeln!(w)?;
+        Ok(())
+    }
+}
+
+impl From<&StackTrace> for Thread {
+    fn from(trace: &StackTrace) -> Thread {
+        Thread{thread_id: trace.thread_id, os_thread_id: trace.os_thread_id, interpreter_id: trace.interpreter_id,
+               task_name: trace.task_name.clone(), active: trace.active, owns_gil: trace.owns_gil,
+               frames: trace.frames.iter().map(ThreadFrame::from).collect()}
+    }
+}
+
+impl From<&Frame> for ThreadFrame {
+    fn from(frame: &Frame) -> ThreadFrame {
+        ThreadFrame{name: frame.name.clone(), filename: frame.filename.clone(),
+                    short_filename: frame.short_filename.clone(), module: frame.module.clone(),
+                    line: if frame.line != 0 { Some(frame.line) } else { None }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_json() {
+        let frames = vec![Frame{name: "wait".to_owned(), filename: "/usr/lib/python3.7/threading.py".to_owned(),
+                                short_filename: Some("threading.py".to_owned()), module: None, line: 296, addr: None},
+                          Frame{name: "PyEval_EvalFrameEx".to_owned(), filename: "ceval.c".to_owned(),
+                                short_filename: None, module: Some("libpython3.7m.so".to_owned()), line: 0, addr: None}];
+        let traces = vec![StackTrace{thread_id: 0x7f00, os_thread_id: Some(1234), active: false, owns_gil: false,
+                                     interpreter_id: 0, task_name: None, wait: None, frames}];
+        let dump = Dump::new(1234, "/usr/bin/python3.7".to_owned(), Some("3.7.3".to_owned()), &traces);
+
+        let mut output = Vec::new();
+        dump.write(&mut output).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+        // check the field names directly, since these are what consumers depend on
+        assert_eq!(value["schema_version"], 1);
+        assert_eq!(value["python_version"], "3.7.3");
+        assert_eq!(value["threads"][0]["os_thread_id"], 1234);
+        assert_eq!(value["threads"][0]["active"], false);
+        assert_eq!(value["threads"][0]["frames"][0]["short_filename"], "threading.py");
+        assert_eq!(value["threads"][0]["frames"][0]["line"], 296);
+        assert_eq!(value["threads"][0]["frames"][1]["module"], "libpython3.7m.so");
+        assert!(value["threads"][0]["frames"][1]["line"].is_null());
+
+        let parsed: Dump = serde_json::from_slice(&output).unwrap();
+        assert_eq!(parsed, dump);
+    }
+}
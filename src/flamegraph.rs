@@ -45,14 +45,17 @@ type Records = HashMap<String, BTreeMap<u64, usize>>;
 pub struct Flamegraph {
     pub counts: Records,
     pub show_linenumbers: bool,
+    /// What sampling cost the process, from SamplingStats::metadata
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl Flamegraph {
     pub fn new(show_linenumbers: bool) -> Flamegraph {
-        Flamegraph { counts: HashMap::new(), show_linenumbers }
+        Flamegraph { counts: HashMap::new(), show_linenumbers, metadata: BTreeMap::new() }
     }
 
-    pub fn increment(&mut self, time_stamp: u64, trace: &StackTrace) -> std::io::Result<()> {
+    pub fn increment(&mut self, time_stamp: u64, trace: &StackTrace, count: usize) -> std::io::Result<()> {
         // convert the frame into a single ';' delimited String
         let frame = trace.frames.iter().rev().map(|frame| {
             let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
@@ -65,13 +68,14 @@ impl Flamegraph {
 
         // update counts for that frame
         let statistics = self.counts.entry(frame).or_insert(BTreeMap::new());
-        *statistics.entry(time_stamp).or_insert(0) += 1;
+        *statistics.entry(time_stamp).or_insert(0) += count;
         Ok(())
     }
 
     pub fn output_raw_data(&self, filename: &String) -> Result<(), Error> {
         let mut out_file = File::create(filename)?;
-        let ret = serde_json::to_string(&Self { counts: self.counts.clone(), show_linenumbers: self.show_linenumbers }).unwrap();
+        let ret = serde_json::to_string(&Self { counts: self.counts.clone(), show_linenumbers: self.show_linenumbers,
+                                                metadata: self.metadata.clone() }).unwrap();
         out_file.write_all(ret.as_bytes()).expect("Fail to write raw data");
         Ok(())
     }
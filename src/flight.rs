@@ -0,0 +1,269 @@
+/* The in-memory ring buffer for 'py-spy flight-recorder'.
+
+This keeps the samples from the last few minutes, so that they can be written out after
+something has gone wrong. Since this runs forever, samples are stored compactly: each distinct
+frame is only stored once, and samples just hold the ids of their frames. Frames aren't removed
+from the table when the samples using them expire, but the number of distinct frames in a
+program is small compared to the number of samples.
+
+Besides SIGUSR1, the samples can be written out by connecting to a unix socket and sending a
+line like 'dump [flamegraph|speedscope] [path]', which replies with the file written.
+*/
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use failure::Error;
+
+use crate::Recorder;
+use crate::config::FileFormat;
+use crate::stack_trace::{StackTrace, Frame};
+
+pub struct FlightRecorder {
+    frames: Vec<Frame>,
+    frame_ids: HashMap<Frame, u32>,
+    samples: VecDeque<CompactSample>,
+    // the number of seconds of samples to keep
+    retain: u64,
+}
+
+struct CompactSample {
+    time_stamp: u64,
+    thread_id: u64,
+    frames: Box<[u32]>,
+}
+
+impl FlightRecorder {
+    pub fn new(retain: u64) -> FlightRecorder {
+        FlightRecorder{frames: Vec::new(), frame_ids: HashMap::new(), samples: VecDeque::new(), retain}
+    }
+
+    pub fn add(&mut self, time_stamp: u64, trace: &StackTrace) {
+        let frames = trace.frames.iter().map(|frame| self.intern(frame)).collect();
+        self.samples.push_back(CompactSample{time_stamp, thread_id: trace.thread_id, frames});
+    }
+
+    /// Drops samples that are older than the retention period
+    pub fn expire(&mut self, time_stamp: u64) {
+        while let Some(sample) = self.samples.front() {
+            if sample.time_stamp + self.retain >= time_stamp {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Writes out the retained samples to a recorder
+    pub fn write(&self, output: &mut dyn Recorder) -> Result<(), Error> {
+        for sample in &self.samples {
+            let frames = sample.frames.iter().map(|&id| self.frames[id as usize].clone()).collect();
+            let trace = StackTrace{thread_id: sample.thread_id, os_thread_id: None, active: true, owns_gil: false,
+                                   interpreter_id: 0, task_name: None, wait: None, frames};
+            output.increment(sample.time_stamp, &trace)?;
+        }
+        Ok(())
+    }
+
+    fn intern(&mut self, frame: &Frame) -> u32 {
+        if let Some(&id) = self.frame_ids.get(frame) {
+            return id;
+        }
+        let id = self.frames.len() as u32;
+        self.frames.push(frame.clone());
+        self.frame_ids.insert(frame.clone(), id);
+        id
+    }
+}
+
+/// A request to write out the samples, received over the unix socket
+pub struct DumpRequest {
+    pub format: Option<FileFormat>,
+    pub filename: Option<String>,
+    reply: Sender<String>,
+}
+
+impl DumpRequest {
+    /// Sends the result of the dump back over the socket
+    pub fn reply(&self, message: &str) {
+        // the client may have gone away, which doesn't matter
+        let _ = self.reply.send(message.to_owned());
+    }
+}
+
+/// Parses a 'dump [format] [path]' command
+fn parse_command(line: &str) -> Result<(Option<FileFormat>, Option<String>), String> {
+    let mut tokens = line.split_whitespace();
+    if tokens.next() != Some("dump") {
+        return Err(format!("unknown command '{}', expected 'dump [flamegraph|speedscope] [path]'", line.trim()));
+    }
+    let mut format = None;
+    let mut filename = None;
+    for token in tokens {
+        match token.parse::<FileFormat>() {
+            Ok(value) if format.is_none() && filename.is_none() => format = Some(value),
+            _ if filename.is_none() => filename = Some(token.to_owned()),
+            _ => return Err(format!("unexpected argument '{}'", token))
+        }
+    }
+    Ok((format, filename))
+}
+
+// How long to wait for a client to send its command before dropping the connection
+#[cfg(unix)]
+const COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Listens for commands on a unix socket, returning the requests that are received
+#[cfg(unix)]
+pub fn listen(path: &str) -> Result<Receiver<DumpRequest>, Error> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    // clean up the socket left behind by a previous run, but don't delete anything else
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format_err!("{} already exists and isn't a socket", path));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| format_err!("Failed to listen on socket {}: {}", path, e))?;
+
+    // each connection is handled on its own thread, so that a client that doesn't send anything
+    // can't hold up requests from other clients
+    let (sender, requests) = channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue
+            };
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let mut line = String::new();
+                if stream.set_read_timeout(Some(COMMAND_TIMEOUT)).is_err() ||
+                   BufReader::new(&stream).read_line(&mut line).is_err() {
+                    return;
+                }
+                let message = match parse_command(&line) {
+                    Ok((format, filename)) => {
+                        let (reply, response) = channel();
+                        if sender.send(DumpRequest{format, filename, reply}).is_err() {
+                            // we've stopped recording
+                            return;
+                        }
+                        response.recv().unwrap_or_else(|_| "error: stopped recording".to_owned())
+                    },
+                    Err(err) => format!("error: {}", err)
+                };
+                let _ = writeln!(stream, "{}", message);
+            });
+        }
+    });
+    Ok(requests)
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &str) -> Result<Receiver<DumpRequest>, Error> {
+    Err(format_err!("Listening on a unix socket is only supported on unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    struct Counts {
+        samples: Vec<(u64, u64, Vec<String>)>,
+    }
+
+    impl Recorder for Counts {
+        fn increment_by(&mut self, time_stamp: u64, trace: &StackTrace, _count: u64) -> Result<(), Error> {
+            let names = trace.frames.iter().map(|frame| frame.name.clone()).collect();
+            self.samples.push((time_stamp, trace.thread_id, names));
+            Ok(())
+        }
+
+        fn set_metadata(&mut self, _metadata: BTreeMap<String, String>) {}
+
+        fn output_result(&self, _filename: &String) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn trace(thread_id: u64, names: &[&str]) -> StackTrace {
+        let frames = names.iter().map(|name| Frame{name: name.to_string(), filename: "app.py".to_owned(), module: None,
+                                                    short_filename: None, line: 1, addr: None}).collect();
+        StackTrace{thread_id, os_thread_id: None, active: true, owns_gil: false, interpreter_id: 0,
+                   task_name: None, wait: None, frames}
+    }
+
+    #[test]
+    fn test_flight_recorder() {
+        let mut recorder = FlightRecorder::new(60);
+        recorder.add(0, &trace(1, &["read", "main"]));
+        recorder.add(30, &trace(2, &["write", "main"]));
+        recorder.add(60, &trace(1, &["read", "main"]));
+        assert_eq!(recorder.len(), 3);
+        // frames are only stored once
+        assert_eq!(recorder.frames.len(), 3);
+
+        // samples older than the retention period are dropped
+        recorder.add(61, &trace(1, &["parse", "main"]));
+        recorder.expire(61);
+        assert_eq!(recorder.len(), 3);
+        recorder.expire(121);
+        assert_eq!(recorder.len(), 1);
+
+        let mut counts = Counts{samples: Vec::new()};
+        recorder.write(&mut counts).unwrap();
+        assert_eq!(counts.samples, vec![(61, 1, vec!["parse".to_owned(), "main".to_owned()])]);
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("dump\n"), Ok((None, None)));
+        assert_eq!(parse_command("dump speedscope\n"), Ok((Some(FileFormat::speedscope), None)));
+        assert_eq!(parse_command("dump flamegraph /tmp/incident.svg\n"),
+                   Ok((Some(FileFormat::flamegraph), Some("/tmp/incident.svg".to_owned()))));
+        assert_eq!(parse_command("dump /tmp/incident.svg"), Ok((None, Some("/tmp/incident.svg".to_owned()))));
+        assert!(parse_command("dump /tmp/a.svg /tmp/b.svg").is_err());
+        assert!(parse_command("stop").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listen() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixStream;
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("py-spy.sock").to_string_lossy().into_owned();
+        let requests = listen(&path).unwrap();
+
+        // a client that connects and doesn't send anything doesn't stop others being served
+        let _idle = UnixStream::connect(&path).unwrap();
+
+        let client = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut stream = UnixStream::connect(&path).unwrap();
+                writeln!(stream, "dump speedscope /tmp/incident.json").unwrap();
+                let mut reply = String::new();
+                BufReader::new(stream).read_line(&mut reply).unwrap();
+                reply
+            }
+        });
+        let request = requests.recv().unwrap();
+        assert_eq!(request.format, Some(FileFormat::speedscope));
+        assert_eq!(request.filename, Some("/tmp/incident.json".to_owned()));
+        request.reply("wrote /tmp/incident.json (3 samples)");
+        assert_eq!(client.join().unwrap(), "wrote /tmp/incident.json (3 samples)\n");
+
+        // a stale socket from a previous run gets replaced
+        drop(requests);
+        assert!(listen(&path).is_ok());
+    }
+}
@@ -0,0 +1,193 @@
+/* Attributes sampled time to the modules being imported, for 'py-spy record --imports'.
+
+While a module is being imported its top level code runs in a '<module>' frame, called from
+importlib._bootstrap. Every sample gets attributed to the chain of modules being imported at
+the time, which gives a nested breakdown of import cost like 'python -X importtime' does. Time
+spent by importlib finding and loading a module before it starts running is counted against
+the module importing it, since we don't know which module is being looked for at that point.
+*/
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use failure::Error;
+
+use crate::stack_trace::{StackTrace, Frame};
+
+pub struct ImportProfile {
+    root: ImportNode,
+    samples: u64,
+}
+
+#[derive(Default)]
+struct ImportNode {
+    children: HashMap<String, ImportNode>,
+    cumulative: u64,
+    own: u64,
+}
+
+impl ImportProfile {
+    pub fn new() -> ImportProfile {
+        ImportProfile{root: ImportNode::default(), samples: 0}
+    }
+
+    /// Counts a sample taken from the process, which can have stack traces for several threads
+    pub fn add_sample(&mut self, weight: u64) {
+        self.samples += weight;
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace, weight: u64) {
+        let modules = imported_modules(&trace.frames);
+        if modules.is_empty() {
+            return;
+        }
+
+        let mut node = &mut self.root;
+        node.cumulative += weight;
+        for module in modules {
+            node = node.children.entry(module).or_default();
+            node.cumulative += weight;
+        }
+        node.own += weight;
+    }
+
+    /// Writes out a table of the cumulative and self time taken importing each module, with
+    /// the modules imported by a module nested underneath it
+    pub fn write<W: Write>(&self, w: &mut W, sampling_rate: u64) -> Result<(), Error> {
+        if self.root.cumulative == 0 {
+            writeln!(w, "No imports were sampled")?;
+            return Ok(());
+        }
+
+        let seconds = |samples: u64| samples as f64 / sampling_rate as f64;
+        writeln!(w, "Importing took {:.3}s of {:.3}s sampled", seconds(self.root.cumulative), seconds(self.samples))?;
+        writeln!(w, "{:>10} {:>10}  module", "cumulative", "self")?;
+
+        // walk the tree depth first, showing the most expensive imports first at each level
+        let mut stack = vec![(0, "", &self.root)];
+        while let Some((depth, name, node)) = stack.pop() {
+            if depth > 0 {
+                writeln!(w, "{:>9.3}s {:>9.3}s  {:width$}{}", seconds(node.cumulative), seconds(node.own), "", name,
+                         width=(depth - 1) * 2)?;
+            }
+            let mut children: Vec<_> = node.children.iter().collect();
+            children.sort_by(|(a_name, a), (b_name, b)| (b.cumulative, a_name).cmp(&(a.cumulative, b_name)));
+            stack.extend(children.into_iter().rev().map(|(name, child)| (depth + 1, name.as_str(), child)));
+        }
+        Ok(())
+    }
+}
+
+// Returns the modules being imported in a stack, starting with the outermost import
+fn imported_modules(frames: &[Frame]) -> Vec<String> {
+    // frames are ordered with the innermost frame first, so the caller of frames[i] is frames[i + 1]
+    frames.iter().zip(frames.iter().skip(1))
+        .filter(|(frame, caller)| frame.name == "<module>" && is_importlib(caller))
+        .map(|(frame, _)| module_name(frame))
+        .rev()
+        .collect()
+}
+
+fn is_importlib(frame: &Frame) -> bool {
+    frame.filename.starts_with("<frozen importlib._bootstrap") ||
+        frame.filename.ends_with("importlib/_bootstrap.py") ||
+        frame.filename.ends_with("importlib/_bootstrap_external.py")
+}
+
+// Gets the name of the module from the short filename of its '<module>' frame, which has
+// already had the path up to the top level package stripped off (like json/decoder.py)
+fn module_name(frame: &Frame) -> String {
+    let filename = match frame.short_filename.as_ref() {
+        Some(filename) => Path::new(filename).with_extension(""),
+        // without the package information we only know the name of the file
+        None => return Path::new(&frame.filename).file_stem()
+                    .map_or_else(|| frame.filename.clone(), |stem| stem.to_string_lossy().into_owned())
+    };
+
+    // packages are run from their __init__.py file
+    let path = match filename.file_name() {
+        Some(name) if name == "__init__" => filename.parent().unwrap_or(&filename),
+        _ => &filename
+    };
+    path.iter().map(|component| component.to_string_lossy()).collect::<Vec<_>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, filename: &str, short_filename: Option<&str>) -> Frame {
+        Frame{name: name.to_owned(), filename: filename.to_owned(), module: None,
+              short_filename: short_filename.map(|f| f.to_owned()), line: 1, addr: None}
+    }
+
+    fn importlib() -> Frame {
+        frame("_call_with_frames_removed", "<frozen importlib._bootstrap>", None)
+    }
+
+    fn trace(frames: Vec<Frame>) -> StackTrace {
+        StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, interpreter_id: 0,
+                   task_name: None, wait: None, frames}
+    }
+
+    #[test]
+    fn test_module_name() {
+        let lib = "/usr/lib/python3.7";
+        assert_eq!(module_name(&frame("<module>", &format!("{}/json/decoder.py", lib), Some("json/decoder.py"))), "json.decoder");
+        assert_eq!(module_name(&frame("<module>", &format!("{}/json/__init__.py", lib), Some("json/__init__.py"))), "json");
+        assert_eq!(module_name(&frame("<module>", &format!("{}/os.py", lib), Some("os.py"))), "os");
+        assert_eq!(module_name(&frame("<module>", "/home/user/app/settings.py", None)), "settings");
+    }
+
+    #[test]
+    fn test_import_profile() {
+        let main = frame("<module>", "/app/main.py", Some("main.py"));
+        let requests = frame("<module>", "/lib/requests/__init__.py", Some("requests/__init__.py"));
+        let adapters = frame("<module>", "/lib/requests/adapters.py", Some("requests/adapters.py"));
+        let json = frame("<module>", "/lib/json/__init__.py", Some("json/__init__.py"));
+
+        let mut profile = ImportProfile::new();
+        // importing requests.adapters from requests, from the main script
+        for _ in 0..3 {
+            profile.increment(&trace(vec![frame("compile", "/lib/re.py", Some("re.py")), adapters.clone(), importlib(),
+                                          requests.clone(), importlib(), main.clone()]), 1);
+            profile.add_sample(1);
+        }
+        // finding a module to import from requests gets counted against requests
+        profile.increment(&trace(vec![frame("_find_spec", "<frozen importlib._bootstrap>", None), importlib(),
+                                      requests.clone(), importlib(), main.clone()]), 1);
+        profile.add_sample(1);
+        profile.increment(&trace(vec![json.clone(), importlib(), main.clone()]), 1);
+        profile.add_sample(1);
+        // the main script isn't being imported
+        profile.increment(&trace(vec![frame("run", "/app/main.py", Some("main.py")), main.clone()]), 1);
+        profile.add_sample(1);
+
+        let mut output = Vec::new();
+        profile.write(&mut output, 10).unwrap();
+        let expected = "Importing took 0.500s of 0.600s sampled\n\
+                        cumulative       self  module\n\
+                        \x20   0.400s     0.100s  requests\n\
+                        \x20   0.300s     0.300s    requests.adapters\n\
+                        \x20   0.100s     0.100s  json\n";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+    #[test]
+    fn test_weighted_samples() {
+        let main = frame("<module>", "/app/main.py", Some("main.py"));
+        let json = frame("<module>", "/lib/json/__init__.py", Some("json/__init__.py"));
+
+        // a sample standing in for two, with one thread importing json and another running the server
+        let mut profile = ImportProfile::new();
+        profile.increment(&trace(vec![json.clone(), importlib(), main.clone()]), 2);
+        profile.increment(&trace(vec![frame("serve_forever", "/lib/socketserver.py", Some("socketserver.py"))]), 2);
+        profile.add_sample(2);
+
+        let mut output = Vec::new();
+        profile.write(&mut output, 10).unwrap();
+        let expected = "Importing took 0.200s of 0.200s sampled\n\
+                        cumulative       self  module\n\
+                        \x20   0.200s     0.200s  json\n";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
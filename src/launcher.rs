@@ -0,0 +1,77 @@
+/* Launches the python program for 'py-spy record -- python myprogram.py' etc.
+
+The program is started stopped, so that we don't miss the start of it. On linux this is done by
+having the child call PTRACE_TRACEME before exec'ing, which makes the kernel stop it right after
+the exec. At that point the process is running the python binary rather than a copy of py-spy,
+but hasn't run any code yet, and we can detach and start watching for the interpreter.
+*/
+use std::process::{Child, Command};
+
+use failure::Error;
+
+/// Spawns the command, returning once the child has exec'ed the program but before it has
+/// started running it. Call resume on the child to let it run.
+#[cfg(target_os="linux")]
+pub fn spawn_stopped(command: &mut Command) -> Result<Child, Error> {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        command.pre_exec(|| {
+            if libc::ptrace(libc::PTRACE_TRACEME, 0, std::ptr::null_mut::<libc::c_void>(),
+                            std::ptr::null_mut::<libc::c_void>()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+
+    // the child gets a SIGTRAP when it exec's, wait for it to stop
+    let mut status = 0;
+    if unsafe { libc::waitpid(child.id() as libc::pid_t, &mut status, 0) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if !unsafe { libc::WIFSTOPPED(status) } {
+        return Err(format_err!("Process {} exited before it started running", child.id()));
+    }
+    Ok(child)
+}
+
+/// Lets a child started by spawn_stopped run
+#[cfg(target_os="linux")]
+pub fn resume(child: &Child) -> Result<(), Error> {
+    if unsafe { libc::ptrace(libc::PTRACE_DETACH, child.id() as libc::pid_t, std::ptr::null_mut::<libc::c_void>(),
+                             std::ptr::null_mut::<libc::c_void>()) } != 0 {
+        return Err(format_err!("Failed to resume process {}: {}", child.id(), std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// other platforms don't have a way to start the process stopped, and rely on polling quickly
+// for the interpreter instead
+#[cfg(not(target_os="linux"))]
+pub fn spawn_stopped(command: &mut Command) -> Result<Child, Error> {
+    Ok(command.spawn()?)
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn resume(_child: &Child) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(all(test, target_os="linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_stopped() {
+        let mut child = spawn_stopped(Command::new("sh").args(["-c", "exit 3"])).unwrap();
+
+        // the child shouldn't run until we resume it
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(std::path::Path::new(&format!("/proc/{}/exe", child.id())).exists());
+
+        resume(&child).unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(3));
+    }
+}
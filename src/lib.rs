@@ -47,7 +47,9 @@ extern crate cpp_demangle;
 extern crate rand;
 extern crate remoteprocess;
 
+mod asyncio;
 mod config;
+mod deadlock;
 mod binary_parser;
 #[cfg(unwind)]
 mod cython;
@@ -56,7 +58,11 @@ mod native_stack_trace;
 mod idle_list;
 mod python_bindings;
 mod python_interpreters;
+mod python_objects;
 mod python_spy;
+mod page_cache;
+mod sample_error;
+mod sampling_stats;
 mod stack_trace;
 mod utils;
 mod version;
@@ -65,6 +71,9 @@ pub use python_spy::PythonSpy;
 pub use config::Config;
 pub use stack_trace::StackTrace;
 pub use stack_trace::Frame;
+pub use stack_trace::ThreadWait;
+pub use sample_error::{SampleError, SampleErrorKind, ErrorBreakdown};
+pub use sampling_stats::{SampleCost, SamplingStats};
 pub use remoteprocess::Pid;
 pub use idle_list::{load_idle_list, check_idle};
 
@@ -32,7 +32,11 @@ extern crate serde_json;
 
 extern crate remoteprocess;
 
+mod asyncio;
+mod blocked;
 mod config;
+mod deadlock;
+mod dump_json;
 mod binary_parser;
 #[cfg(unwind)]
 mod cython;
@@ -41,7 +45,20 @@ mod native_stack_trace;
 mod idle_list;
 mod python_bindings;
 mod python_interpreters;
+mod python_objects;
 mod python_spy;
+mod page_cache;
+mod sample_error;
+mod sampling_stats;
+mod process_selector;
+mod rotate;
+mod trigger;
+mod flight;
+mod control;
+mod imports;
+mod launcher;
+#[cfg(all(target_os="linux", unwind))]
+mod raw_native;
 mod stack_trace;
 mod console_viewer;
 mod flamegraph;
@@ -49,15 +66,16 @@ mod speedscope;
 mod timer;
 mod utils;
 mod version;
+mod watch;
 
 #[cfg(feature = "validation")]
 mod old_flame;
 
+use std::collections::BTreeMap;
 use std::io::Read;
 use std::fs::File;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use failure::Error;
 
@@ -66,27 +84,141 @@ use python_spy::PythonSpy;
 use stack_trace::{StackTrace, Frame};
 use console_viewer::ConsoleViewer;
 use config::{Config, FileFormat, RecordDuration};
+use sample_error::SampleErrorKind;
+
+fn print_traces(pid: remoteprocess::Pid, traces: &[StackTrace], show_idle: bool) {
+    // traces are grouped by interpreter, only call these out if there are subinterpreters
+    let subinterpreters = traces.iter().any(|trace| trace.interpreter_id != 0);
+    let mut interpreter_id = None;
 
-fn print_traces(traces: &[StackTrace], show_idle: bool) {
     for trace in traces {
         if !show_idle && !trace.active {
             continue;
         }
 
-        if let Some(os_thread_id) = trace.os_thread_id {
-            println!("Thread {:#X}/{} ({})", trace.thread_id,  os_thread_id, trace.status_str());
+        if subinterpreters && interpreter_id != Some(trace.interpreter_id) {
+            println!("Interpreter {}", trace.interpreter_id);
+            interpreter_id = Some(trace.interpreter_id);
+        }
+        print_trace(pid, trace, 0);
+    }
+}
+
+// Prints out a single stack trace, highlighting the 'stable' outermost frames
+fn print_trace(pid: remoteprocess::Pid, trace: &StackTrace, stable: usize) {
+    if let Some(task_name) = &trace.task_name {
+        println!("Task {} ({})", task_name, if trace.active { "running" } else { "waiting" });
+    } else if let Some(os_thread_id) = trace.os_thread_id {
+        // for idle threads, try to figure out what they are waiting on
+        let blocked = trace.wait.as_ref().map(|wait| blocked::get_blocked(pid, os_thread_id as remoteprocess::Tid, wait));
+        match blocked {
+            Some(blocked) => println!("Thread {:#X}/{} ({}): blocked in {}", trace.thread_id, os_thread_id,
+                                      trace.status_str(), blocked),
+            None => println!("Thread {:#X}/{} ({})", trace.thread_id,  os_thread_id, trace.status_str())
+        }
+    } else {
+        println!("Thread {:#X} ({})", trace.thread_id, trace.status_str());
+    }
+
+    let first_stable = trace.frames.len().saturating_sub(stable);
+    for (i, frame) in trace.frames.iter().enumerate() {
+        let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
+        let location = if frame.line != 0 {
+            format!("{} ({}:{})", frame.name, filename, frame.line)
+        } else {
+            format!("{} ({})", frame.name, filename)
+        };
+        if i >= first_stable {
+            println!("\t*{}", console::style(location).bold());
         } else {
-            println!("Thread {:#X} ({})", trace.thread_id, trace.status_str());
+            println!("\t {}", location);
         }
-        for frame in &trace.frames {
-            let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
-            if frame.line != 0 {
-                println!("\t {} ({}:{})", frame.name, filename, frame.line);
-            } else {
-                println!("\t {} ({})", frame.name, filename);
+    }
+}
+
+fn print_deadlocks(graph: &deadlock::WaitGraph, traces: &[StackTrace]) {
+    let describe_thread = |thread_id: u64| {
+        match traces.iter().find(|trace| trace.thread_id == thread_id && trace.task_name.is_none()) {
+            Some(StackTrace{os_thread_id: Some(os_thread_id), ..}) => format!("Thread {:#X}/{}", thread_id, os_thread_id),
+            _ => format!("Thread {:#X}", thread_id)
+        }
+    };
+
+    let cycles = graph.find_cycles();
+    if cycles.is_empty() {
+        println!("\nNo deadlocks found");
+        return;
+    }
+
+    for cycle in cycles {
+        println!("\nDeadlock found between {} threads:", cycle.len());
+        for (i, (thread_id, resource)) in cycle.iter().enumerate() {
+            let (owner, _) = cycle[(i + 1) % cycle.len()];
+            println!("\t{} is waiting on {} held by {}", describe_thread(*thread_id), resource, describe_thread(owner));
+        }
+    }
+}
+
+// Set to false once Control-C has been pressed
+static RUNNING: AtomicBool = AtomicBool::new(true);
+
+// Installs the Control-C handler, returning the flag it clears. Only one handler can be set, so
+// this is shared by waiting for a process to start and then sampling it
+fn ctrlc_handler() -> Result<&'static AtomicBool, Error> {
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+    if !INSTALLED.swap(true, Ordering::SeqCst) {
+        ctrlc::set_handler(|| RUNNING.store(false, Ordering::SeqCst))?;
+    }
+    Ok(&RUNNING)
+}
+
+// Repeatedly dumps out the threads whose stacks have changed, for 'dump --interval'
+fn watch_traces(process: &mut PythonSpy, config: &Config, interval: u64) -> Result<(), Error> {
+    let running = ctrlc_handler()?;
+
+    let mut history = watch::StackHistory::new();
+    let mut traces = Vec::new();
+    while running.load(Ordering::SeqCst) {
+        traces = match process.get_stack_traces() {
+            Ok(traces) => traces,
+            Err(_) if process_exitted(&process.process) => {
+                println!("\nprocess {} ended", process.pid);
+                return Ok(());
+            },
+            Err(e) => return Err(e)
+        };
+
+        let changed = history.update(&traces);
+        if history.snapshots == 1 {
+            print_traces(process.pid, &traces, true);
+        } else {
+            println!("\nSnapshot {} ({} of {} threads changed, * marks frames that haven't changed in any snapshot)",
+                     history.snapshots, changed.len(), traces.len());
+            for (trace, stable) in changed {
+                print_trace(process.pid, trace, stable);
             }
         }
+
+        if config.dump_count.is_some_and(|count| history.snapshots >= count) {
+            break;
+        }
+
+        // sleep in short increments, so that we can respond to Control-C
+        let deadline = std::time::Instant::now() + Duration::from_secs(interval);
+        while running.load(Ordering::SeqCst) && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    // show the threads that were stuck the whole time
+    if history.snapshots > 1 {
+        let stuck: Vec<&StackTrace> = traces.iter().filter(|trace| history.is_stuck(trace)).collect();
+        println!("\n{} threads didn't change in {} snapshots", stuck.len(), history.snapshots);
+        for trace in stuck {
+            print_trace(process.pid, trace, 0);
+        }
     }
+    Ok(())
 }
 
 fn process_exitted(process: &remoteprocess::Process) -> bool {
@@ -106,47 +238,152 @@ fn permission_denied(err: &Error) -> bool {
     })
 }
 
-fn sample_console(process: &mut PythonSpy,
+fn version_description(process: &PythonSpy) -> String {
+    if process.config.native_only {
+        "native only".to_owned()
+    } else {
+        format!("python v{}", process.version)
+    }
+}
+
+fn sample_console(processes: &mut [PythonSpy],
                   display: &str,
                   config: &Config) -> Result<(), Error> {
     let rate = config.sampling_rate;
 
+    let version = match &*processes {
+        [process] => version_description(process),
+        _ => format!("{} python processes", processes.len())
+    };
+
     // Console related
-    let mut console = ConsoleViewer::new(config.show_line_numbers, display,
-                                         &format!("{}", process.version),
+    let mut console = ConsoleViewer::new(config.show_line_numbers, display, &version,
                                          1.0 / rate as f64)?;
 
+    let mut ended = vec![false; processes.len()];
     for sleep in timer::Timer::new(rate as f64) {
         if let Err(elapsed) = sleep {
             console.increment_late_sample(elapsed);
         }
 
-        match process.get_stack_traces() {
-            Ok(traces) => {
-                console.increment(&traces)?;
-            },
-            Err(err) => {
-                if process_exitted(&process.process) {
-                    println!("\nprocess {} ended", process.pid);
-                    break;
-                } else {
-                    console.increment_error(&err)?;
+        // with --all, the traces from every process are combined into a single sample
+        let mut traces = Vec::new();
+        let mut sampled = false;
+        for (process, ended) in processes.iter_mut().zip(ended.iter_mut()) {
+            if *ended {
+                continue;
+            }
+            match process.get_stack_traces() {
+                Ok(process_traces) => {
+                    traces.extend(process_traces);
+                    console.increment_cost(&process.last_sample);
+                    sampled = true;
+                },
+                Err(err) => {
+                    if process_exitted(&process.process) {
+                        println!("\nprocess {} ended", process.pid);
+                        *ended = true;
+                    } else {
+                        console.increment_error(&err)?;
+                    }
                 }
             }
         }
 
+        if ended.iter().all(|&ended| ended) {
+            break;
+        }
+        if sampled {
+            console.increment(&traces)?;
+        }
     }
     Ok(())
 }
 
+// Runs the command against a list of python processes, for '--all' and the process selectors
+fn run_processes(found: process_selector::ProcessList, description: &str, config: &Config) -> Result<(), Error> {
+    let mut processes = Vec::new();
+    for candidate in found.candidates {
+        match PythonSpy::new(candidate.pid, config) {
+            Ok(process) => processes.push((candidate, process)),
+            Err(err) => eprintln!("Skipping process {} ({}): {}", candidate.pid, candidate.cmdline, err)
+        }
+    }
+    for (candidate, err) in &found.inaccessible {
+        eprintln!("Skipping process {} ({}): {}", candidate.pid, candidate.cmdline, err);
+    }
+    if processes.is_empty() {
+        return Err(format_err!("Failed to find any python processes"));
+    }
+
+    if config.command == "top" {
+        let mut processes: Vec<PythonSpy> = processes.into_iter().map(|(_, process)| process).collect();
+        return sample_console(&mut processes, description, config);
+    }
+
+    for (candidate, mut process) in processes {
+        println!("\nProcess {}: {}", candidate.pid, candidate.cmdline);
+        if let Err(err) = run_spy_command(&mut process, config) {
+            eprintln!("Failed to get stack traces for process {}: {}", candidate.pid, err);
+        }
+    }
+    Ok(())
+}
+
+// Runs the command against the processes matching --name/--cmdline. Record only supports
+// sampling a single process, so it requires the selector to be unambiguous
+fn run_selected_processes(selector: &process_selector::ProcessSelector, config: &Config) -> Result<(), Error> {
+    let found = if config.wait {
+        let running = ctrlc_handler()?;
+        let found = selector.wait(config, running)?;
+        if !running.load(Ordering::SeqCst) {
+            println!("Stopped waiting because Control-C pressed");
+            return Ok(());
+        }
+        found
+    } else {
+        selector.find(config)?
+    };
+    if found.candidates.is_empty() {
+        return Err(format_err!("Failed to find a python process with {}", selector));
+    }
+
+    if config.command == "record" || config.command == "flight-recorder" {
+        if found.candidates.len() > 1 {
+            let matches: Vec<String> = found.candidates.iter()
+                .map(|candidate| format!("\t{}: {}", candidate.pid, candidate.cmdline))
+                .collect();
+            return Err(format_err!("Found {} python processes with {}, use --pid to pick one:\n{}",
+                                   found.candidates.len(), selector, matches.join("\n")));
+        }
+        let mut process = PythonSpy::retry_new(found.candidates[0].pid, config, 3)?;
+        return run_spy_command(&mut process, config);
+    }
+
+    run_processes(found, &format!("python processes with {}", selector), config)
+}
+
 pub trait Recorder {
-    fn increment(&mut self, time_stamp: u64, trace: &StackTrace) -> Result<(), Error>;
+    fn increment(&mut self, time_stamp: u64, trace: &StackTrace) -> Result<(), Error> {
+        self.increment_by(time_stamp, trace, 1)
+    }
+    /// Adds a trace that counts as 'count' samples, for when each sample stands for several
+    /// samples at the requested rate (like with --max-overhead)
+    fn increment_by(&mut self, time_stamp: u64, trace: &StackTrace, count: u64) -> Result<(), Error>;
+    /// Sets the metadata to include in the output, like what sampling cost the process
+    fn set_metadata(&mut self, metadata: BTreeMap<String, String>);
     fn output_result(&self, filename: &String) -> Result<(), Error>;
+    /// Continues recording in another process, for when --follow-restarts attaches to a new one
+    fn set_pid(&mut self, _pid: remoteprocess::Pid) {}
 }
 
 impl Recorder for speedscope::Stats {
-    fn increment(&mut self, _time_stamp: u64, trace: &StackTrace) -> Result<(), Error> {
-        Ok(self.record(trace)?)
+    fn increment_by(&mut self, _time_stamp: u64, trace: &StackTrace, count: u64) -> Result<(), Error> {
+        Ok(self.record(trace, count)?)
+    }
+
+    fn set_metadata(&mut self, metadata: BTreeMap<String, String>) {
+        self.metadata = metadata;
     }
 
     fn output_result(&self, filename: &String) -> Result<(), Error> {
@@ -156,8 +393,12 @@ impl Recorder for speedscope::Stats {
 }
 
 impl Recorder for flamegraph::Flamegraph {
-    fn increment(&mut self, time_stamp: u64, trace: &StackTrace) -> Result<(), Error> {
-        Ok(self.increment(time_stamp, trace)?)
+    fn increment_by(&mut self, time_stamp: u64, trace: &StackTrace, count: u64) -> Result<(), Error> {
+        Ok(self.increment(time_stamp, trace, count as usize)?)
+    }
+
+    fn set_metadata(&mut self, metadata: BTreeMap<String, String>) {
+        self.metadata = metadata;
     }
 
     fn output_result(&self, filename: &String) -> Result<(), Error> {
@@ -165,18 +406,173 @@ impl Recorder for flamegraph::Flamegraph {
     }
 }
 
-fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error> {
-    let mut output: Box<dyn Recorder> = match config.format {
-        Some(FileFormat::flamegraph) => Box::new(flamegraph::Flamegraph::new(config.show_line_numbers)),
-        Some(FileFormat::speedscope) =>  Box::new(speedscope::Stats::new()),
-        None => return Err(format_err!("A file format is required to record samples"))
+#[cfg(all(target_os="linux", unwind))]
+impl Recorder for raw_native::RawRecording {
+    fn increment_by(&mut self, time_stamp: u64, trace: &StackTrace, count: u64) -> Result<(), Error> {
+        self.increment(time_stamp, trace, count)
+    }
+
+    fn set_metadata(&mut self, metadata: BTreeMap<String, String>) {
+        self.metadata = metadata;
+    }
+
+    fn output_result(&self, filename: &String) -> Result<(), Error> {
+        self.write(filename)
+    }
+
+    fn set_pid(&mut self, pid: remoteprocess::Pid) {
+        raw_native::RawRecording::set_pid(self, pid);
+    }
+}
+
+fn new_recorder(config: &Config) -> Result<Box<dyn Recorder>, Error> {
+    match config.format {
+        Some(FileFormat::flamegraph) => Ok(Box::new(flamegraph::Flamegraph::new(config.show_line_numbers))),
+        Some(FileFormat::speedscope) =>  Ok(Box::new(speedscope::Stats::new())),
+        None => Err(format_err!("A file format is required to record samples"))
+    }
+}
+
+// Creates the recorder for 'py-spy record', which stores the raw addresses of native frames
+// instead of the symbols for --defer-symbols
+fn new_output(process: &PythonSpy, config: &Config) -> Result<Box<dyn Recorder>, Error> {
+    #[cfg(all(target_os="linux", unwind))]
+    {
+        if config.defer_symbols {
+            return Ok(Box::new(raw_native::RawRecording::new(process.pid)?));
+        }
+    }
+    #[cfg(not(all(target_os="linux", unwind)))]
+    let _ = process;
+
+    new_recorder(config)
+}
+
+// Writes out the samples from before and after a trigger fired
+fn write_window(captured: &trigger::Window, mut output: Box<dyn Recorder>, filename: &str,
+                metadata: BTreeMap<String, String>, progress: &indicatif::ProgressBar) -> Result<(), Error> {
+    for sample in &captured.samples {
+        for trace in &sample.traces {
+            output.increment_by(sample.time_stamp, trace, sample.weight)?;
+        }
+    }
+    output.set_metadata(metadata);
+    output.output_result(&filename.to_owned())?;
+    progress.println(format!("Wrote {} samples from when {} triggered into file '{}'", captured.samples.len(), captured.reason, filename));
+    Ok(())
+}
+
+// Returns whether a trace should be included in the output, from the --idle and --gil options
+fn should_record(trace: &StackTrace, config: &Config) -> bool {
+    // asyncio tasks that are running are already included in the stack of the thread
+    // running them, so we only want the ones waiting here
+    if trace.task_name.is_some() {
+        if trace.active {
+            return false;
+        }
+    } else if !(config.include_idle || trace.active) {
+        return false;
+    }
+    !config.gil_only || trace.owns_gil
+}
+
+// Finds the process that replaced one that exited, and attaches to it once its interpreter is
+// ready. Returns None if Control-C was pressed before then
+fn attach_successor(selector: &process_selector::ProcessSelector, config: &Config, running: &AtomicBool,
+                    since_alive: Duration) -> Result<Option<PythonSpy>, Error> {
+    // start times are only accurate to a clock tick, so allow for a process started just after
+    // the last sample we took
+    let started_after = process_selector::uptime()?.checked_sub(since_alive + Duration::from_millis(10)).unwrap_or_default();
+    let found = selector.wait_for_new(config, running, started_after)?;
+    let candidate = match found.candidates.as_slice() {
+        [] => return Ok(None),
+        [candidate] => candidate,
+        candidates => {
+            let candidates: Vec<String> = candidates.iter().map(|c| format!("{} ({})", c.pid, c.cmdline)).collect();
+            return Err(format_err!("{} new processes match: {}", candidates.len(), candidates.join(", ")));
+        }
     };
 
+    // the new process can take a while to start up its interpreter, so keep trying until it does
+    loop {
+        let err = match PythonSpy::retry_new(candidate.pid, config, 1) {
+            Ok(process) => return Ok(Some(process)),
+            Err(err) => err
+        };
+        if !running.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if remoteprocess::Process::new(candidate.pid).ok().is_none_or(|process| process_exitted(&process)) {
+            return Err(format_err!("process {} exited before python was initialized: {}", candidate.pid, err));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// A sample that failed, as written out by --save-failed-samples
+#[derive(Serialize)]
+struct FailedSample {
+    time_stamp: u64,
+    pid: remoteprocess::Pid,
+    kind: &'static str,
+    // the error followed by each of its causes
+    errors: Vec<String>,
+}
+
+fn save_failed_sample(file: &mut File, time_stamp: u64, pid: remoteprocess::Pid, kind: SampleErrorKind, err: &Error) -> Result<(), Error> {
+    use std::io::Write;
+    let sample = FailedSample{time_stamp, pid, kind: kind.description(),
+                              errors: err.iter_chain().map(|cause| cause.to_string()).collect()};
+    writeln!(file, "{}", serde_json::to_string(&sample)?)?;
+    Ok(())
+}
+
+fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error> {
+    let mut output = new_output(process, config)?;
+
     let filename = match config.filename.as_ref() {
         Some(filename) => filename,
         None => return Err(format_err!("A filename is required to record samples"))
     };
 
+    // how to find the process that replaces this one when it exits, for --follow-restarts and --wait
+    let successor = match process_selector::ProcessSelector::new(config)? {
+        Some(selector) if config.wait || config.follow_restarts => Some(selector),
+        _ if config.follow_restarts => Some(process_selector::ProcessSelector::same_command(&process.process)?),
+        _ => None
+    };
+    let mut generation = 1;
+    // when we last got a sample from the process, which its replacement has to have started after
+    let mut last_alive = Instant::now();
+
+    let mut imports = if config.imports { Some(imports::ImportProfile::new()) } else { None };
+
+    // capture windows controlled by signals and the control file
+    let controller = control::Controller::new(config.control_file.as_deref())?;
+    let mut capturing = !config.start_paused;
+    let mut window = 0;
+    let mut window_samples = 0;
+
+    // with --trigger-*, samples are buffered and only written out when a trigger fires
+    let mut triggered = trigger::TriggeredCapture::new(config, process.pid)?;
+
+    // with --rotate, a new file gets written out every interval
+    let mut rotation = rotate::Rotation::new(config, process.pid)?;
+
+    // what sampling costs the process, reported at the end and included in the output files
+    let mut sampling_stats = sampling_stats::SamplingStats::new();
+
+    // with --max-overhead, the sampling rate gets lowered when samples take too long
+    let mut adaptive_rate = config.max_overhead.map(|max_overhead| timer::AdaptiveRate::new(config.sampling_rate, max_overhead));
+
+    // why samples failed, reported at the end and optionally saved with --save-failed-samples
+    let mut error_breakdown = sample_error::ErrorBreakdown::new();
+    let mut failed_samples = match config.save_failed_samples.as_ref() {
+        Some(path) => Some(std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format_err!("Failed to open {}: {}", path, e))?),
+        None => None
+    };
+
     let mut max_samples = None;
     use indicatif::ProgressBar;
 
@@ -194,8 +590,9 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
         }
     };
 
-    let mut errors = 0;
     let mut samples = 0;
+    // the number of samples we'd have taken at the requested rate
+    let mut weighted_samples = 0;
     let mut exit_message = "";
     let mut time_stamp: u64 = 0;
     let mut sample_counter: u64 = 0;
@@ -205,13 +602,11 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
     let mut flame_old = old_flame::OldFlamegraph::new(config.show_line_numbers);
 
     // Ctrl-C handler
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })?;
+    let running = ctrlc_handler()?;
 
-    for sleep in timer::Timer::new(config.sampling_rate as f64) {
+    let mut timer = timer::Timer::new(config.sampling_rate as f64);
+    while let Some(sleep) = timer.next() {
+        let weight = adaptive_rate.as_ref().map_or(1, |rate| rate.weight());
         if let Err(delay) = sleep {
             if delay > Duration::from_secs(1) {
                 let term = console::Term::stdout();
@@ -227,58 +622,205 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
             break;
         }
 
+        for command in controller.poll() {
+            let (start, write) = match command {
+                control::Command::Toggle => (!capturing, capturing),
+                control::Command::Start => (true, false),
+                control::Command::Stop => (false, capturing),
+                control::Command::Flush => (capturing, true)
+            };
+            if write {
+                if window_samples > 0 {
+                    window += 1;
+                    let window_filename = control::window_filename(filename, window);
+                    output.set_metadata(sampling_stats.metadata());
+                    output.output_result(&window_filename)?;
+                    progress.println(format!("Wrote {} samples into file '{}'", window_samples, window_filename));
+                    output = new_output(process, config)?;
+                    window_samples = 0;
+                } else {
+                    progress.println("No samples captured since the last window, not writing a file");
+                }
+            }
+            if start != capturing {
+                capturing = start;
+                progress.println(if capturing { "Started capturing samples" } else { "Stopped capturing samples" });
+            }
+        }
+
+        if let Some(rotation) = rotation.as_mut() {
+            let now = Instant::now();
+            if rotation.is_due(now) {
+                let rotated = rotation.rotate(now)?;
+                output.set_metadata(sampling_stats.metadata());
+                output.output_result(&rotated)?;
+                progress.println(format!("Wrote {} samples into file '{}'", window_samples, rotated));
+                output = new_output(process, config)?;
+                window_samples = 0;
+
+                if let Err(err) = rotation.remove_old_files() {
+                    progress.println(format!("Failed to remove old files: {}", err));
+                }
+            }
+        }
+
+        if !capturing {
+            progress.set_message("Paused");
+            progress.tick();
+            continue;
+        }
+
         // Process stack traces
         match process.get_stack_traces() {
             Ok(traces) => {
+                last_alive = Instant::now();
+                let mut sample_traces = Vec::new();
                 for mut trace in traces {
-                    if !(config.include_idle || trace.active) {
+                    if !should_record(&trace, config) {
                         continue;
                     }
 
-                    if config.gil_only && !trace.owns_gil {
-                        continue;
+                    // record the system call idle threads are blocked in as the leaf frame. Unlike
+                    // dump, this leaves out the lock or socket so that samples get grouped together
+                    if config.include_idle && !trace.active {
+                        if let Some(wait) = &trace.wait {
+                            let syscall = blocked::get_blocked_syscall(wait);
+                            trace.frames.insert(0, Frame{name: syscall,
+                                filename: String::from(""),
+                                module: None, short_filename: None, line: 0, addr: None});
+                        }
                     }
 
                     if config.include_thread_ids {
-                        trace.frames.push(Frame{name: format!("thread {}", trace.thread_id),
+                        let name = match &trace.task_name {
+                            Some(task_name) => format!("task {}", task_name),
+                            None => format!("thread {}", trace.thread_id)
+                        };
+                        trace.frames.push(Frame{name,
+                            filename: String::from(""),
+                            module: None, short_filename: None, line: 0, addr: None});
+                    }
+
+                    // group threads from subinterpreters under their own root frame
+                    if trace.interpreter_id != 0 {
+                        trace.frames.push(Frame{name: format!("interpreter {}", trace.interpreter_id),
                             filename: String::from(""),
-                            module: None, short_filename: None, line: 0});
+                            module: None, short_filename: None, line: 0, addr: None});
+                    }
+
+                    // separate the samples from each process we've followed across restarts
+                    if config.follow_restarts {
+                        trace.frames.push(Frame{name: format!("process {} (generation {})", process.pid, generation),
+                            filename: String::from(""),
+                            module: None, short_filename: None, line: 0, addr: None});
+                    }
+
+                    if let Some(imports) = imports.as_mut() {
+                        imports.increment(&trace, weight);
                     }
 
-                    output.increment(time_stamp, &trace)?;
+                    if triggered.is_some() {
+                        sample_traces.push(trace);
+                    } else {
+                        output.increment_by(time_stamp, &trace, weight)?;
+                    }
+                }
+                window_samples += 1;
+                if let Some(imports) = imports.as_mut() {
+                    imports.add_sample(weight);
+                }
+
+                sampling_stats.add(&process.last_sample);
+
+                if let Some(adaptive_rate) = adaptive_rate.as_mut() {
+                    if let Some(rate) = adaptive_rate.update(process.last_sample.pause) {
+                        info!("sampling {:.1} times a second, process paused for {:.2?} per sample", rate, process.last_sample.pause);
+                        timer.set_rate(rate);
+                    }
+                }
+
+                if let Some(capture) = triggered.as_mut() {
+                    match capture.add(Instant::now(), trigger::Sample{time_stamp, weight, traces: sample_traces}) {
+                        Some(trigger::Event::Triggered(reason)) => {
+                            progress.println(format!("Triggered by {}, recording for another {} seconds", reason, config.trigger_after));
+                        },
+                        Some(trigger::Event::Window(captured)) => {
+                            window += 1;
+                            write_window(&captured, new_output(process, config)?, &control::window_filename(filename, window),
+                                         sampling_stats.metadata(), &progress)?;
+                        },
+                        None => {}
+                    }
                 }
 
                 samples += 1;
+                weighted_samples += weight;
                 if let Some(max_samples) = max_samples {
-                    if samples >= max_samples {
+                    if weighted_samples >= max_samples {
                         break;
                     }
                 }
             },
-            Err(_) => {
+            Err(err) => {
                 if process_exitted(&process.process) {
                     println!("\nprocess {} ended", process.pid);
+
+                    // keep recording once the process has been restarted. Whatever happens here,
+                    // the samples we already have still get written out
+                    if let Some(selector) = successor.as_ref() {
+                        match attach_successor(selector, config, running, last_alive.elapsed()) {
+                            Ok(Some(replacement)) => {
+                                *process = replacement;
+                                generation += 1;
+                                output.set_pid(process.pid);
+                                if let Some(rotation) = rotation.as_mut() {
+                                    rotation.set_pid(process.pid);
+                                }
+                                println!("Sampling process {}", process.pid);
+                                if let Some(capture) = triggered.as_mut() {
+                                    if let Err(err) = capture.set_pid(process.pid) {
+                                        println!("Failed to measure the CPU usage of process {}: {}", process.pid, err);
+                                    }
+                                }
+                                continue;
+                            },
+                            Ok(None) => exit_message = "Stopped sampling because Control-C pressed",
+                            Err(err) => {
+                                println!("Failed to find the process that replaced it: {}", err);
+                                exit_message = "Stopped sampling because the process ended";
+                            }
+                        }
+                        break;
+                    }
+
                     exit_message = "Stopped sampling because the process ended";
                     break;
                 } else {
-                    errors += 1; 
+                    let kind = error_breakdown.add(&err);
+                    if let Some(file) = failed_samples.as_mut() {
+                        save_failed_sample(file, time_stamp, process.pid, kind, &err)?;
+                    }
                 }
             }
         }
         if config.duration == RecordDuration::Unlimited {
-            let msg = if errors > 0 {
+            let errors = error_breakdown.total();
+            let mut msg = if errors > 0 {
                 format!("Collected {} samples ({} errors)", samples, errors)
             } else {
                 format!("Collected {} samples", samples)
             };
+            if let Some(adaptive_rate) = adaptive_rate.as_ref() {
+                msg.push_str(&format!(" at {:.1}Hz", adaptive_rate.rate()));
+            }
             progress.set_message(&msg);
         }
 
-        progress.inc(1);
-        sample_counter += 1;
-        if sample_counter == config.sampling_rate {
-            sample_counter = 0;
-            time_stamp += 1;
+        progress.inc(weight);
+        sample_counter += weight;
+        if sample_counter >= config.sampling_rate {
+            time_stamp += sample_counter / config.sampling_rate;
+            sample_counter %= config.sampling_rate;
         }
     }
 
@@ -289,9 +831,72 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
         println!("{}", exit_message);
     }
 
+    for line in sampling_stats.report() {
+        println!("{}", line);
+    }
+    for line in error_breakdown.report(samples) {
+        println!("{}", line);
+    }
+    if config.non_blocking {
+        let torn = error_breakdown.count(SampleErrorKind::Torn);
+        println!("Discarded {} samples ({:.2}%) where a stack changed while reading it without pausing the process",
+                 torn, 100.0 * torn as f64 / (samples + error_breakdown.total()).max(1) as f64);
+    }
+    if let Some(path) = config.save_failed_samples.as_ref() {
+        if error_breakdown.total() > 0 {
+            println!("Saved the errors from failed samples to '{}'", path);
+        }
+    }
+    let errors = error_breakdown.total();
+
+    if let Some(adaptive_rate) = adaptive_rate {
+        if adaptive_rate.lowest_rate() < config.sampling_rate as f64 {
+            println!("Lowered the sampling rate to as little as {:.1} samples a second to keep the overhead under {}%",
+                     adaptive_rate.lowest_rate(), config.max_overhead.unwrap_or_default());
+        }
+    }
+
+    if let Some(imports) = imports {
+        println!();
+        imports.write(&mut std::io::stdout(), config.sampling_rate)?;
+        println!();
+    }
+
+    if let Some(mut rotation) = rotation {
+        let rotated = rotation.rotate(Instant::now())?;
+        output.set_metadata(sampling_stats.metadata());
+        output.output_result(&rotated)?;
+        println!("Wrote {} samples into file '{}'. Samples: {} Errors: {}", window_samples, rotated, samples, errors);
+        rotation.remove_old_files()?;
+        return Ok(());
+    }
+
+    if let Some(mut capture) = triggered {
+        if let Some(captured) = capture.finish() {
+            window += 1;
+            write_window(&captured, new_output(process, config)?, &control::window_filename(filename, window),
+                         sampling_stats.metadata(), &progress)?;
+        }
+        println!("Wrote {} triggered windows of samples. Samples: {} Errors: {}", window, samples, errors);
+        return Ok(());
+    }
+
+    // everything has already been written out to the window files if the last window was stopped
+    if window > 0 && window_samples == 0 {
+        println!("Wrote {} windows of samples. Samples: {} Errors: {}", window, samples, errors);
+        return Ok(());
+    }
+
+    output.set_metadata(sampling_stats.metadata());
     output.output_result(filename)?;
     println!("Wrote result into file '{}'. Samples: {} Errors: {}", filename, samples, errors);
 
+    if config.defer_symbols {
+        println!("Native frames haven't been symbolicated yet, run 'py-spy symbolize {} -o <filename>' to resolve them",
+                 filename);
+        return Ok(());
+    }
+
     match config.format.as_ref().unwrap() {
         FileFormat::flamegraph => {
             println!("Wrote flamegraph data to '{}'. Samples: {} Errors: {}", filename, samples, errors);
@@ -311,21 +916,183 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
     Ok(())
 }
 
+// Writes out the samples held by the flight recorder, returning the number of samples written
+fn dump_flight_recorder(recorder: &flight::FlightRecorder, config: &Config, format: Option<FileFormat>,
+                        filename: &str, metadata: BTreeMap<String, String>) -> Result<usize, Error> {
+    let mut config = config.clone();
+    if format.is_some() {
+        config.format = format;
+    }
+    let mut output = new_recorder(&config)?;
+    recorder.write(output.as_mut())?;
+    output.set_metadata(metadata);
+    output.output_result(&filename.to_owned())?;
+    Ok(recorder.len())
+}
+
+fn flight_record(process: &mut PythonSpy, config: &Config) -> Result<(), Error> {
+    let filename = match config.filename.as_ref() {
+        Some(filename) => filename,
+        None => return Err(format_err!("A filename is required to record samples"))
+    };
+
+    // SIGUSR1 and SIGUSR2 both dump the samples, there are no capture windows to control here
+    let controller = control::Controller::new(None)?;
+    let requests = match config.socket.as_ref() {
+        Some(path) => Some(flight::listen(path)?),
+        None => None
+    };
+
+    let running = ctrlc_handler()?;
+
+    println!("Sampling process {} times a second, keeping the last {} seconds of samples. \
+              Send SIGUSR1 to pid {} to write them out, or press Control-C to write them out and exit.",
+             config.sampling_rate, config.retain, std::process::id());
+
+    let mut recorder = flight::FlightRecorder::new(config.retain);
+    let mut sampling_stats = sampling_stats::SamplingStats::new();
+    let mut error_breakdown = sample_error::ErrorBreakdown::new();
+    let mut dumps = 0;
+    let mut samples = 0;
+    let start = Instant::now();
+
+    for sleep in timer::Timer::new(config.sampling_rate as f64) {
+        if let Err(delay) = sleep {
+            if delay > Duration::from_secs(1) {
+                println!("{:.2?} behind in sampling, results may be inaccurate. Try reducing the sampling rate.", delay);
+            }
+        }
+
+        if !running.load(Ordering::SeqCst) {
+            println!("Stopped sampling because Control-C pressed");
+            break;
+        }
+
+        let time_stamp = start.elapsed().as_secs();
+
+        if !controller.poll().is_empty() {
+            dumps += 1;
+            let dump_filename = control::window_filename(filename, dumps);
+            // keep sampling if this fails, otherwise we'd lose everything that's been recorded
+            match dump_flight_recorder(&recorder, config, None, &dump_filename, sampling_stats.metadata()) {
+                Ok(written) => println!("Wrote {} samples into file '{}'", written, dump_filename),
+                Err(err) => println!("Failed to write samples into file '{}': {}", dump_filename, err)
+            }
+        }
+
+        if let Some(requests) = requests.as_ref() {
+            for request in requests.try_iter() {
+                let dump_filename = match request.filename.as_ref() {
+                    Some(filename) => filename.clone(),
+                    None => {
+                        dumps += 1;
+                        control::window_filename(filename, dumps)
+                    }
+                };
+                match dump_flight_recorder(&recorder, config, request.format.clone(), &dump_filename, sampling_stats.metadata()) {
+                    Ok(written) => {
+                        println!("Wrote {} samples into file '{}'", written, dump_filename);
+                        request.reply(&format!("wrote {} ({} samples)", dump_filename, written));
+                    },
+                    Err(err) => request.reply(&format!("error: {}", err))
+                }
+            }
+        }
+
+        match process.get_stack_traces() {
+            Ok(traces) => {
+                for trace in traces.iter().filter(|trace| should_record(trace, config)) {
+                    recorder.add(time_stamp, trace);
+                }
+                sampling_stats.add(&process.last_sample);
+                recorder.expire(time_stamp);
+                samples += 1;
+            },
+            Err(err) => {
+                if process_exitted(&process.process) {
+                    println!("process {} ended", process.pid);
+                    break;
+                }
+                error_breakdown.add(&err);
+            }
+        }
+    }
+
+    let written = dump_flight_recorder(&recorder, config, None, filename, sampling_stats.metadata())?;
+    println!("Wrote {} samples into file '{}'", written, filename);
+    for line in sampling_stats.report() {
+        println!("{}", line);
+    }
+    for line in error_breakdown.report(samples) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(all(target_os="linux", unwind))]
+fn symbolize_recording(config: &Config) -> Result<(), Error> {
+    let input = config.input_file.as_ref().ok_or_else(|| format_err!("A recording is required to symbolize"))?;
+    let filename = config.filename.as_ref().ok_or_else(|| format_err!("A filename is required to write results"))?;
+
+    let recording = raw_native::RawRecording::load(input)?;
+    println!("Resolving {} samples from pid {} ({} binaries)", recording.samples.len(), recording.pid,
+             recording.objects.len());
+
+    let mut output = new_recorder(config)?;
+    output.set_metadata(recording.metadata.clone());
+    let mut samples = 0;
+    raw_native::symbolize(&recording, &config.symbol_dirs, |time_stamp, trace, count| {
+        output.increment_by(time_stamp, trace, count)?;
+        samples += count;
+        Ok(())
+    })?;
+
+    output.output_result(filename)?;
+    println!("Wrote symbolicated result into file '{}'. Samples: {}", filename, samples);
+    Ok(())
+}
+
+#[cfg(not(all(target_os="linux", unwind)))]
+fn symbolize_recording(_config: &Config) -> Result<(), Error> {
+    Err(format_err!("Deferred symbolication is only supported on x86_64 linux"))
+}
+
 fn run_spy_command(process: &mut PythonSpy, config: &config::Config) -> Result<(), Error> {
     match config.command.as_ref() {
+        "dump" if config.dump_json => {
+            let traces = process.get_stack_traces()?;
+            let python_version = if process.config.native_only { None } else { Some(process.version.to_string()) };
+            let dump = dump_json::Dump::new(process.pid as i64, process.process.exe()?, python_version, &traces);
+            dump.write(&mut std::io::stdout())?;
+        },
         "dump" =>  {
-            println!("{}\nPython version {}", process.process.exe()?, process.version);
-            print_traces(&process.get_stack_traces()?, true);
+            if process.config.native_only {
+                println!("{}\nNative stack traces", process.process.exe()?);
+            } else {
+                println!("{}\nPython version {}", process.process.exe()?, process.version);
+            }
+            if let Some(interval) = config.dump_interval {
+                watch_traces(process, config, interval)?;
+            } else {
+                let traces = process.get_stack_traces()?;
+                print_traces(process.pid, &traces, true);
+                if config.deadlocks {
+                    print_deadlocks(&process.get_wait_graph()?, &traces);
+                }
+            }
         },
         "record" => {
             record_samples(process, config)?;
         },
+        "flight-recorder" => {
+            flight_record(process, config)?;
+        },
         "top" => {
             let display = match config.python_program.as_ref() {
                 Some(subprocess) => subprocess.join(" "),
                 None => format!("pid: {}", config.pid.unwrap())
             };
-            sample_console(process, &display, config)?;
+            sample_console(std::slice::from_mut(process), &display, config)?;
         }
         _ => {
             // shouldn't happen
@@ -349,13 +1116,20 @@ fn pyspy_main() -> Result<(), Error> {
 
     load_idle_list(&config.idlelist);
 
-    if let Some(ref filename) = config.data_file {
+    if config.command == "symbolize" {
+        symbolize_recording(&config)?;
+    }
+
+    else if let Some(ref filename) = config.data_file {
         println!("Try to open the file {}", filename);
         let mut input_file = File::open(&filename)?;
         let mut content_string = String::new();
         input_file.read_to_string(&mut content_string)?;
         let content: flamegraph::Flamegraph = serde_json::from_str(&content_string).unwrap();
         println!("The raw data contains {} different stack traces.", content.counts.len());
+        for (key, value) in &content.metadata {
+            println!("\t{}: {}", key, value);
+        }
         let mut flame_name = String::new();
         flame_name.push_str(filename);
         flame_name.push_str(".svg");
@@ -364,6 +1138,15 @@ fn pyspy_main() -> Result<(), Error> {
         content.write(target, config.start_ts, config.end_ts)?;
     }
 
+    else if config.all_processes {
+        let found = process_selector::find_python_processes(&config, None)?;
+        run_processes(found, "all python processes", &config)?;
+    }
+
+    else if let Some(selector) = process_selector::ProcessSelector::new(&config)? {
+        run_selected_processes(&selector, &config)?;
+    }
+
     else if let Some(pid) = config.pid {
         let mut process = PythonSpy::retry_new(pid, &config, 3)?;
         run_spy_command(&mut process, &config)?;
@@ -386,11 +1169,11 @@ fn pyspy_main() -> Result<(), Error> {
             }
         }
 
-        let mut command = command.args(&subprocess[1..])
+        command.args(&subprocess[1..])
             .stdin(std::process::Stdio::null())
             .stdout(process_output.reopen()?)
-            .stderr(process_output.reopen()?)
-            .spawn()
+            .stderr(process_output.reopen()?);
+        let mut command = launcher::spawn_stopped(&mut command)
             .map_err(|e| format_err!("Failed to create process '{}': {}", subprocess[0], e))?;
 
         #[cfg(target_os="macos")]
@@ -398,7 +1181,8 @@ fn pyspy_main() -> Result<(), Error> {
             // sleep just in case: https://jvns.ca/blog/2018/01/28/mac-freeze/
             std::thread::sleep(Duration::from_millis(50));
         }
-        let result = match PythonSpy::retry_new(command.id() as remoteprocess::Pid, &config, 8) {
+        let pid = command.id() as remoteprocess::Pid;
+        let result = match PythonSpy::wait_for_interpreter(pid, &config, Duration::from_secs(5), || launcher::resume(&command)) {
             Ok(mut process) => {
                 run_spy_command(&mut process, &config)
             },
@@ -7,13 +7,18 @@ use lru::LruCache;
 
 use crate::binary_parser::BinaryInfo;
 use crate::cython;
+use crate::sample_error::{SampleError, SampleErrorKind};
 use crate::stack_trace::{Frame};
 use crate::utils::resolve_filename;
 use crate::cpp_demangle::{DemangleOptions, BorrowedSymbol};
 
 pub struct NativeStack {
     should_reload: bool,
-    python: BinaryInfo,
+    // when set, only frames inside the python binary are symbolicated while sampling
+    // (so that we can merge the python stack), everything else is left as a raw address
+    defer_symbols: bool,
+    // the python binary isn't known when only collecting native stack traces
+    python: Option<BinaryInfo>,
     libpython: Option<BinaryInfo>,
     cython_maps: cython::SourceMaps,
     unwinder: remoteprocess::Unwinder,
@@ -27,7 +32,7 @@ pub struct NativeStack {
 }
 
 impl NativeStack {
-    pub fn new(pid: Pid, python: BinaryInfo, libpython: Option<BinaryInfo>) -> Result<NativeStack, Error> {
+    pub fn new(pid: Pid, python: Option<BinaryInfo>, libpython: Option<BinaryInfo>, defer_symbols: bool) -> Result<NativeStack, Error> {
         let cython_maps = cython::SourceMaps::new();
 
         let process = remoteprocess::Process::new(pid)?;
@@ -37,7 +42,7 @@ impl NativeStack {
         #[cfg(target_os="linux")]
         let libunwinder = remoteprocess::libunwind::LibUnwind::new()?;
 
-        return Ok(NativeStack{cython_maps, unwinder, should_reload: false,
+        return Ok(NativeStack{cython_maps, unwinder, should_reload: false, defer_symbols,
                               python,
                               libpython,
                               #[cfg(target_os="linux")]
@@ -61,7 +66,8 @@ impl NativeStack {
         #[cfg(target_os="linux")]
         let native_stack = match self.get_thread(&thread) {
             Ok(x) => x,
-            Err(_) =>  self.get_libunwind_thread(&thread)?
+            Err(_) =>  self.get_libunwind_thread(&thread)
+                .map_err(|err| SampleError::wrap(SampleErrorKind::NativeUnwind, "Failed to unwind native stack", err))?
         };
 
         // TODO: merging the two stack together could happen outside of thread lock
@@ -72,7 +78,8 @@ impl NativeStack {
         match self.merge_native_stack(frames, native_stack) {
             Ok(merged) => return Ok(merged),
             Err(_) => {
-                let native_stack = self.get_libunwind_thread(&thread)?;
+                let native_stack = self.get_libunwind_thread(&thread)
+                    .map_err(|err| SampleError::wrap(SampleErrorKind::NativeUnwind, "Failed to unwind native stack", err))?;
                 return self.merge_native_stack(frames, native_stack);
             }
         }
@@ -83,16 +90,26 @@ impl NativeStack {
 
         // merge the native_stack and python stack together
         for addr in native_stack {
+            let is_python_addr = self.python.as_ref().is_some_and(|m| m.contains(addr)) ||
+                                 self.libpython.as_ref().is_some_and(|m| m.contains(addr));
+
+            // frames outside of python are always merged in, so there is no need to symbolicate
+            // them here if they are going to be resolved offline
+            if self.defer_symbols && !is_python_addr {
+                merged.push(Frame{filename: String::new(), name: format!("0x{:x}", addr),
+                                  line: 0, short_filename: None, module: None, addr: Some(addr)});
+                continue;
+            }
+
             // check in the symbol cache if we have looked up this symbol yet
             let cached_symbol = self.symbol_cache.get(&addr).map(|f| f.clone());
 
             // merges a remoteprocess::StackFrame into the current merged vec
-            let is_python_addr = self.python.contains(addr) || self.libpython.as_ref().map_or(false, |m| m.contains(addr));
             let merge_frame = &mut |frame: &remoteprocess::StackFrame| {
                 match self.get_merge_strategy(is_python_addr, frame) {
                     MergeType::Ignore => {},
                     MergeType::MergeNativeFrame => {
-                        if let Some(python_frame) = self.translate_native_frame(frame) {
+                        if let Some(python_frame) = translate_native_frame(frame) {
                             merged.push(python_frame);
                         }
                     },
@@ -134,7 +151,7 @@ impl NativeStack {
                 // if we can't symbolicate, just insert a stub here.
                 merged.push(Frame{filename: "?".to_owned(),
                                   name: format!("0x{:x}", addr),
-                                  line: 0, short_filename: None, module: None});
+                                  line: 0, short_filename: None, module: None, addr: None});
             });
 
             if symbolicated_count == 1 {
@@ -161,8 +178,9 @@ impl NativeStack {
                 info!("Have {} native and {} python threads in stack - allowing for now",
                     python_frame_index, frames.len());
             } else {
-                 return Err(format_err!("Failed to merge native and python frames (Have {} native and {} python)",
-                                       python_frame_index, frames.len()));
+                 return Err(SampleError::new(SampleErrorKind::NativeMerge,
+                                             format!("Failed to merge native and python frames (Have {} native and {} python)",
+                                                     python_frame_index, frames.len())).into());
             }
         }
 
@@ -175,7 +193,7 @@ impl NativeStack {
     }
 
     fn get_merge_strategy(&self, check_python: bool, frame: &remoteprocess::StackFrame) -> MergeType {
-        if check_python || frame.module == self.python.filename {
+        if check_python || self.python.as_ref().is_some_and(|python| frame.module == python.filename) {
             if let Some(ref function) = frame.function {
                 // ugh, probably could do a better job of figuring this out
                 // (also the symbols are different for each OS)
@@ -203,50 +221,6 @@ impl NativeStack {
         }
     }
 
-    /// translates a native frame into a optional frame. none indicates we should ignore this frame
-    fn translate_native_frame(&self, frame: &remoteprocess::StackFrame) -> Option<Frame> {
-        match &frame.function {
-            Some(func) =>  {
-                if ignore_frame(func, &frame.module) {
-                    return None;
-                }
-
-                // Get the filename/line/function name here
-                let line = frame.line.unwrap_or(0) as i32;
-
-                // try to resolve the filename relative to the module if given
-                let filename = match frame.filename.as_ref() {
-                    Some(filename) => {
-                        resolve_filename(filename, &frame.module)
-                            .unwrap_or_else(|| filename.clone())
-                    },
-                    None => frame.module.clone()
-                };
-
-                let mut demangled = None;
-                if func.starts_with('_') {
-                    if let Ok((sym, _)) = BorrowedSymbol::with_tail(func.as_bytes()) {
-                        let options = DemangleOptions{no_params: true, ..Default::default()};
-                        if let Ok(sym) = sym.demangle(&options) {
-                            demangled = Some(sym);
-                        }
-                    }
-                }
-                let name = demangled.as_ref().unwrap_or_else(|| &func);
-                if cython::ignore_frame(name) {
-                    return None;
-                }
-                let name = cython::demangle(&name).to_owned();
-                Some(Frame{filename, line, name, short_filename: None, module: Some(frame.module.clone())})
-            },
-            None => {
-                Some(Frame{filename: frame.module.clone(),
-                           name: format!("0x{:x}", frame.addr),
-                           line: 0, short_filename: None, module: Some(frame.module.clone())})
-            }
-        }
-    }
-
     fn get_thread(&mut self, thread: &remoteprocess::Thread) -> Result<Vec<u64>, Error> {
         let mut stack = Vec::new();
         let mut cursor = self.unwinder.cursor(thread)?;
@@ -290,6 +264,50 @@ impl NativeStack {
     }
 }
 
+/// translates a native frame into a optional frame. none indicates we should ignore this frame
+pub fn translate_native_frame(frame: &remoteprocess::StackFrame) -> Option<Frame> {
+    match &frame.function {
+        Some(func) =>  {
+            if ignore_frame(func, &frame.module) {
+                return None;
+            }
+
+            // Get the filename/line/function name here
+            let line = frame.line.unwrap_or(0) as i32;
+
+            // try to resolve the filename relative to the module if given
+            let filename = match frame.filename.as_ref() {
+                Some(filename) => {
+                    resolve_filename(filename, &frame.module)
+                        .unwrap_or_else(|| filename.clone())
+                },
+                None => frame.module.clone()
+            };
+
+            let mut demangled = None;
+            if func.starts_with('_') {
+                if let Ok((sym, _)) = BorrowedSymbol::with_tail(func.as_bytes()) {
+                    let options = DemangleOptions{no_params: true, ..Default::default()};
+                    if let Ok(sym) = sym.demangle(&options) {
+                        demangled = Some(sym);
+                    }
+                }
+            }
+            let name = demangled.as_ref().unwrap_or_else(|| &func);
+            if cython::ignore_frame(name) {
+                return None;
+            }
+            let name = cython::demangle(&name).to_owned();
+            Some(Frame{filename, line, name, short_filename: None, module: Some(frame.module.clone()), addr: None})
+        },
+        None => {
+            Some(Frame{filename: frame.module.clone(),
+                       name: format!("0x{:x}", frame.addr),
+                       line: 0, short_filename: None, module: Some(frame.module.clone()), addr: None})
+        }
+    }
+}
+
 enum MergeType {
     Ignore,
     MergePythonFrame,
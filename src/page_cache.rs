@@ -0,0 +1,195 @@
+/* Caches the pages of the target process that are read while taking a sample.
+
+Walking a python stack follows a pointer from each frame to its code object, and from that to
+the function name, filename and line number table. Most of these objects are close to each other
+in memory, so reading the whole page containing each one means that most reads don't need a
+system call. The pages that a read needs and that aren't cached yet are all read with a single
+vectored read, and the stack walk prefetches the objects it's about to read so that the pages
+for several objects get read at once.
+
+The cache has to be cleared before each sample, and can only be used while the process is paused:
+otherwise we'd return memory that has since changed.
+*/
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use remoteprocess::ProcessMemory;
+
+// The smallest page size on the platforms we support. If the real page size is bigger this still
+// works, since mappings always start and end on a page boundary
+const PAGE_SIZE: usize = 4096;
+
+// Reads spanning more pages than this go straight to the process, rather than through the cache
+const MAX_CACHED_PAGES: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct PageCache {
+    pages: RefCell<HashMap<usize, Box<[u8]>>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl PageCache {
+    pub fn new() -> PageCache {
+        PageCache::default()
+    }
+
+    /// Drops all the cached pages, returning the number of reads that were served from the
+    /// cache and the number that weren't since the last call
+    pub fn clear(&self) -> (u64, u64) {
+        self.pages.borrow_mut().clear();
+        (self.hits.replace(0), self.misses.replace(0))
+    }
+}
+
+/// Wraps another process's memory, reading it through a page cache
+pub struct CachedMemory<'a, P: ProcessMemory> {
+    process: P,
+    cache: &'a PageCache,
+}
+
+impl<'a, P: ProcessMemory> CachedMemory<'a, P> {
+    pub fn new(process: P, cache: &'a PageCache) -> CachedMemory<'a, P> {
+        CachedMemory{process, cache}
+    }
+}
+
+impl<'a, P: ProcessMemory> CachedMemory<'a, P> {
+    // Reads the pages that aren't cached yet with a single vectored read
+    fn load(&self, pages: &mut HashMap<usize, Box<[u8]>>, missing: Vec<usize>) -> Result<(), remoteprocess::Error> {
+        let mut buffers: Vec<Box<[u8]>> = missing.iter().map(|_| vec![0; PAGE_SIZE].into_boxed_slice()).collect();
+        {
+            let mut reads: Vec<(usize, &mut [u8])> = missing.iter().cloned().zip(buffers.iter_mut().map(|b| &mut b[..])).collect();
+            self.process.read_vectored(&mut reads)?;
+        }
+        pages.extend(missing.into_iter().zip(buffers));
+        Ok(())
+    }
+}
+
+// Returns the first and last pages of a range of memory, or None if it's too big to cache (or
+// runs past the end of the address space, which happens with pointers read from garbage)
+fn page_range(addr: usize, length: usize) -> Option<(usize, usize)> {
+    let end = addr.checked_add(length)?.checked_sub(1)?;
+    let first = addr - addr % PAGE_SIZE;
+    let last = end - end % PAGE_SIZE;
+    if (last - first) / PAGE_SIZE + 1 > MAX_CACHED_PAGES {
+        return None;
+    }
+    Some((first, last))
+}
+
+impl<'a, P: ProcessMemory> ProcessMemory for CachedMemory<'a, P> {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let (first, last) = match page_range(addr, buf.len()) {
+            Some(pages) => pages,
+            None => return self.process.read(addr, buf)
+        };
+
+        let mut pages = self.cache.pages.borrow_mut();
+        let missing: Vec<usize> = (first..=last).step_by(PAGE_SIZE).filter(|page| !pages.contains_key(page)).collect();
+        if missing.is_empty() {
+            self.cache.hits.set(self.cache.hits.get() + 1);
+        } else {
+            self.cache.misses.set(self.cache.misses.get() + 1);
+            self.load(&mut pages, missing)?;
+        }
+
+        let mut copied = 0;
+        for page in (first..=last).step_by(PAGE_SIZE) {
+            let start = (addr + copied) - page;
+            let length = (PAGE_SIZE - start).min(buf.len() - copied);
+            buf[copied..copied + length].copy_from_slice(&pages[&page][start..start + length]);
+            copied += length;
+        }
+        Ok(())
+    }
+
+    fn prefetch(&self, ranges: &[(usize, usize)]) {
+        let mut pages = self.cache.pages.borrow_mut();
+        let mut missing = Vec::new();
+        for &(addr, length) in ranges.iter().filter(|(addr, length)| *addr != 0 && *length != 0) {
+            if let Some((first, last)) = page_range(addr, length) {
+                for page in (first..=last).step_by(PAGE_SIZE) {
+                    if !pages.contains_key(&page) && !missing.contains(&page) {
+                        missing.push(page);
+                    }
+                }
+            }
+        }
+        // if this fails, the reads that need the memory will fail and report the error
+        if !missing.is_empty() && self.load(&mut pages, missing).is_err() {
+            debug!("Failed to prefetch {} ranges of memory", ranges.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+    use crate::sampling_stats::{CountingMemory, MemoryReads};
+
+    #[test]
+    fn test_page_cache() {
+        // a buffer that spans a few pages, so that reads can cross page boundaries
+        let values: Vec<u64> = (0..2048).collect();
+        let addr = values.as_ptr() as usize;
+        let counts = MemoryReads::default();
+        let cache = PageCache::new();
+        let memory = CachedMemory::new(CountingMemory::new(&LocalProcess, &counts), &cache);
+
+        // the index of the first value on a page boundary
+        let start = ((addr / PAGE_SIZE + 1) * PAGE_SIZE - addr) / 8;
+        let value = |i: usize| -> u64 { memory.copy_struct(addr + 8 * (start + i)).unwrap() };
+
+        // everything on the same page is read from the cache
+        assert_eq!(value(10), (start + 10) as u64);
+        assert_eq!(value(511), (start + 511) as u64);
+        assert_eq!(counts.take(), (1, PAGE_SIZE as u64));
+
+        // a read across a page boundary only reads the page that isn't cached yet
+        let copied: [u64; 4] = memory.copy_struct(addr + 8 * (start + 510)).unwrap();
+        let expected = (start + 510) as u64;
+        assert_eq!(copied, [expected, expected + 1, expected + 2, expected + 3]);
+        assert_eq!(counts.take(), (1, PAGE_SIZE as u64));
+        assert_eq!(cache.clear(), (1, 2));
+
+        // large reads bypass the cache
+        let copied = memory.copy(addr, 8 * 2048).unwrap();
+        assert_eq!(copied.len(), 8 * 2048);
+        assert_eq!(&copied[8..16], &1_u64.to_ne_bytes());
+        assert_eq!(cache.clear(), (0, 0));
+    }
+    #[test]
+    fn test_prefetch() {
+        // start on a page boundary, so that the pages read don't include the cache's own buffers
+        let values: Vec<u64> = (0..4096).collect();
+        let start = ((values.as_ptr() as usize / PAGE_SIZE + 1) * PAGE_SIZE - values.as_ptr() as usize) / 8;
+        let addr = values.as_ptr() as usize + 8 * start;
+        let counts = MemoryReads::default();
+        let cache = PageCache::new();
+        let memory = CachedMemory::new(CountingMemory::new(&LocalProcess, &counts), &cache);
+
+        // objects on different pages are all read with one system call, and null pointers skipped
+        let ranges = [(addr, 8), (addr + 8 * 1024, 16), (addr + 8 * 1024, 8), (0, 8)];
+        memory.prefetch(&ranges);
+        let (reads, bytes) = counts.take();
+        assert_eq!(reads, 1);
+        assert!(bytes >= 2 * PAGE_SIZE as u64 && bytes <= 4 * PAGE_SIZE as u64);
+
+        let value: u64 = memory.copy_struct(addr + 8 * 1024).unwrap();
+        assert_eq!(value, (start + 1024) as u64);
+        assert_eq!(memory.copy_struct::<u64>(addr).unwrap(), start as u64);
+        assert_eq!(counts.take(), (0, 0));
+        assert_eq!(cache.clear(), (2, 0));
+
+        // ranges that wrap around the address space aren't cached
+        assert_eq!(page_range(usize::MAX - 8, 16), None);
+        memory.prefetch(&[(usize::MAX - 8, 16)]);
+        assert_eq!(counts.take(), (0, 0));
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use failure::Error;
+use regex::Regex;
+use remoteprocess::Pid;
+
+use crate::config::Config;
+use crate::python_spy::{PythonSpy, is_python_lib};
+
+/// A process on the system that might be running python
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub pid: Pid,
+    pub cmdline: String,
+}
+
+/// The processes found by find_python_processes
+pub struct ProcessList {
+    pub candidates: Vec<Candidate>,
+    // processes we couldn't look at and why, generally because they belong to another user
+    pub inaccessible: Vec<(Candidate, Error)>,
+}
+
+/// Selects processes by their name or command line, for --name and --cmdline
+pub struct ProcessSelector {
+    name: Option<Regex>,
+    cmdline: Option<Regex>,
+}
+
+impl ProcessSelector {
+    /// Creates a selector from the config, or returns None if no selector was given
+    pub fn new(config: &Config) -> Result<Option<ProcessSelector>, Error> {
+        let name = match config.process_name.as_ref() {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None
+        };
+        let cmdline = match config.process_cmdline.as_ref() {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None
+        };
+        if name.is_none() && cmdline.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(ProcessSelector{name, cmdline}))
+    }
+
+    /// Creates a selector matching processes with the same command line as a process, for
+    /// finding the process that replaces it when it gets restarted
+    #[cfg(target_os="linux")]
+    pub fn same_command(process: &remoteprocess::Process) -> Result<ProcessSelector, Error> {
+        let cmdline = process.cmdline()?.join(" ");
+        let cmdline = Regex::new(&format!("^{}$", regex::escape(&cmdline)))?;
+        Ok(ProcessSelector{name: None, cmdline: Some(cmdline)})
+    }
+
+    #[cfg(not(target_os="linux"))]
+    pub fn same_command(_process: &remoteprocess::Process) -> Result<ProcessSelector, Error> {
+        Err(format_err!("Following process restarts is only supported on linux"))
+    }
+
+    pub fn matches(&self, name: &str, cmdline: &str) -> bool {
+        self.name.as_ref().is_none_or(|regex| regex.is_match(name)) &&
+            self.cmdline.as_ref().is_none_or(|regex| regex.is_match(cmdline))
+    }
+
+    /// Returns the python processes that match
+    pub fn find(&self, config: &Config) -> Result<ProcessList, Error> {
+        find_processes(config, Some(self), |_| true)
+    }
+
+    /// Waits until there is at least one matching python process, or until running is set
+    /// to false (in which case this returns an empty list)
+    pub fn wait(&self, config: &Config, running: &AtomicBool) -> Result<ProcessList, Error> {
+        self.wait_for(config, running, |_| true)
+    }
+
+    /// Like wait, but only for processes that started after a time (measured from when the system
+    /// booted). This finds the process that replaced one that exited, and not the other processes
+    /// with the same command line that were already running
+    pub fn wait_for_new(&self, config: &Config, running: &AtomicBool, started_after: Duration) -> Result<ProcessList, Error> {
+        self.wait_for(config, running, |candidate| {
+            start_time(candidate.pid).is_ok_and(|started| started >= started_after)
+        })
+    }
+
+    fn wait_for<F: Fn(&Candidate) -> bool>(&self, config: &Config, running: &AtomicBool, filter: F) -> Result<ProcessList, Error> {
+        let mut waiting = false;
+        loop {
+            let found = find_processes(config, Some(self), &filter)?;
+            if !found.candidates.is_empty() || !running.load(Ordering::SeqCst) {
+                return Ok(found);
+            }
+            if !waiting {
+                println!("Waiting for a python process with {}", self);
+                waiting = true;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl fmt::Display for ProcessSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.name, &self.cmdline) {
+            (Some(name), Some(cmdline)) => write!(f, "a name matching '{}' and a command line matching '{}'", name, cmdline),
+            (Some(name), None) => write!(f, "a name matching '{}'", name),
+            (None, Some(cmdline)) => write!(f, "a command line matching '{}'", cmdline),
+            (None, None) => write!(f, "any name")
+        }
+    }
+}
+
+/// Returns every process on the system that is running python (and matches the selector, if
+/// given). This checks the filenames of the executable and the loaded libraries. Parsing the
+/// binaries to look for an embedded interpreter is much slower, so that is only done for the
+/// processes picked out by a selector.
+pub fn find_python_processes(config: &Config, selector: Option<&ProcessSelector>) -> Result<ProcessList, Error> {
+    find_processes(config, selector, |_| true)
+}
+
+#[cfg(target_os="linux")]
+fn find_processes<F: Fn(&Candidate) -> bool>(config: &Config, selector: Option<&ProcessSelector>, filter: F) -> Result<ProcessList, Error> {
+    let python_lib = match config.python_lib.as_ref() {
+        Some(pattern) => Some(Regex::new(pattern)?),
+        None => None
+    };
+
+    let current = std::process::id() as Pid;
+    let mut candidates = Vec::new();
+    let mut inaccessible = Vec::new();
+    for pid in remoteprocess::get_pids()? {
+        if pid == current {
+            continue;
+        }
+
+        let process = match remoteprocess::Process::new(pid) {
+            Ok(process) => process,
+            Err(_) => continue
+        };
+        let cmdline = match process.cmdline() {
+            Ok(cmdline) => cmdline,
+            // the process probably exited
+            Err(_) => continue
+        };
+        // kernel threads don't have a command line
+        if cmdline.is_empty() {
+            continue;
+        }
+        let cmdline = cmdline.join(" ");
+
+        if let Some(selector) = selector {
+            let name = process.name().unwrap_or_default();
+            if !selector.matches(&name, &cmdline) {
+                continue;
+            }
+        }
+
+        let candidate = Candidate{pid, cmdline};
+        if !filter(&candidate) {
+            continue;
+        }
+
+        let maps = match proc_maps::get_process_maps(pid) {
+            Ok(maps) => maps,
+            Err(err) => {
+                let err = format_err!("Failed to read memory maps: {}", err);
+                inaccessible.push((candidate, err));
+                continue;
+            }
+        };
+
+        let exe = process.exe().unwrap_or_default();
+        let is_python = is_python_exe(&exe) || maps.iter().any(|map| {
+            match (map.filename(), python_lib.as_ref()) {
+                (Some(filename), Some(python_lib)) => python_lib.is_match(filename),
+                (Some(filename), None) => is_python_lib(filename),
+                (None, _) => false
+            }
+        }) || (selector.is_some() && PythonSpy::python_version(&process, config).is_ok());
+
+        if is_python {
+            candidates.push(candidate);
+        }
+    }
+    Ok(ProcessList{candidates, inaccessible})
+}
+
+#[cfg(not(target_os="linux"))]
+fn find_processes<F: Fn(&Candidate) -> bool>(_config: &Config, _selector: Option<&ProcessSelector>, _filter: F) -> Result<ProcessList, Error> {
+    Err(format_err!("Finding all python processes is only supported on linux"))
+}
+
+/// Returns how long after the system booted a process was started
+#[cfg(target_os="linux")]
+pub fn start_time(pid: Pid) -> Result<Duration, Error> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let ticks = parse_start_time(&stat).ok_or_else(|| format_err!("Failed to parse /proc/{}/stat", pid))?;
+    Ok(ticks_to_duration(ticks))
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn start_time(_pid: Pid) -> Result<Duration, Error> {
+    Err(format_err!("Getting the start time of a process is only supported on linux"))
+}
+
+/// Returns how long it has been since the system booted
+#[cfg(target_os="linux")]
+pub fn uptime() -> Result<Duration, Error> {
+    let uptime = std::fs::read_to_string("/proc/uptime")?;
+    let seconds: f64 = uptime.split_whitespace().next().unwrap_or_default().parse()?;
+    Ok(Duration::from_millis((seconds * 1000.0) as u64))
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn uptime() -> Result<Duration, Error> {
+    Err(format_err!("Getting the uptime is only supported on linux"))
+}
+
+// Returns the starttime field from /proc/<pid>/stat, in clock ticks since boot. The process name
+// comes before it in parentheses, and can contain spaces and parentheses itself
+#[cfg(target_os="linux")]
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let fields = &stat[stat.rfind(')')? + 1..];
+    // starttime is the 22nd field, and the first one after the name is the 3rd
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(target_os="linux")]
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    Duration::from_millis(ticks * 1000 / ticks_per_second)
+}
+
+// Returns whether the executable looks like a python interpreter (like /usr/bin/python3.7)
+fn is_python_exe(exe: &str) -> bool {
+    std::path::Path::new(exe)
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("python"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_python_exe() {
+        assert!(is_python_exe("/usr/bin/python3.7"));
+        assert!(is_python_exe("/opt/venv/bin/python"));
+        assert!(!is_python_exe("/usr/bin/bash"));
+        assert!(!is_python_exe("/home/python/bin/node"));
+        assert!(!is_python_exe(""));
+    }
+
+    #[test]
+    fn test_selector_matches() {
+        let config = Config{process_name: Some("^celery$".to_owned()), ..Default::default()};
+        let selector = ProcessSelector::new(&config).unwrap().unwrap();
+        assert!(selector.matches("celery", "python -m celery worker"));
+        assert!(!selector.matches("python3", "python -m celery worker"));
+
+        let config = Config{process_name: Some("python".to_owned()), process_cmdline: Some("manage.py (runserver|shell)".to_owned()),
+                            ..Default::default()};
+        let selector = ProcessSelector::new(&config).unwrap().unwrap();
+        assert!(selector.matches("python3", "python3 manage.py runserver 0.0.0.0:8000"));
+        assert!(!selector.matches("python3", "python3 manage.py migrate"));
+        assert!(!selector.matches("bash", "bash -c python3 manage.py runserver"));
+
+        assert!(ProcessSelector::new(&Config::default()).unwrap().is_none());
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn test_same_command() {
+        let process = remoteprocess::Process::new(std::process::id() as Pid).unwrap();
+        let cmdline = process.cmdline().unwrap().join(" ");
+        let selector = ProcessSelector::same_command(&process).unwrap();
+        assert!(selector.matches("", &cmdline));
+        assert!(!selector.matches("", &format!("{} --extra", cmdline)));
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn test_start_time() {
+        let stat = "1234 (python (worker) 2) S 1 1234 1234 0 -1 4194560 1520 0 0 0 12 3 0 0 20 0 1 0 987654 \
+                    26513408 2450 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("1234 (python"), None);
+
+        let started = start_time(std::process::id() as Pid).unwrap();
+        assert!(started <= uptime().unwrap());
+    }
+}
@@ -15,6 +15,9 @@ use std;
 pub trait InterpreterState {
     type ThreadState: ThreadState;
     fn head(&self) -> * mut Self::ThreadState;
+    fn next(&self) -> * mut Self;
+    // the interpreter id is only stored in the interpreter state in python 3.7+
+    fn id(&self) -> Option<i64>;
 }
 
 pub trait ThreadState {
@@ -65,11 +68,6 @@ fn offset_of<T, M>(object: *const T, member: *const M) -> usize {
 /// String handling changes substantially between python versions, and is handled separately.
 macro_rules! PythonCommonImpl {
     ($py: ident, $bytesobject: ident, $stringobject: ident) => (
-        impl InterpreterState for $py::PyInterpreterState {
-            type ThreadState = $py::PyThreadState;
-            fn head(&self) -> * mut Self::ThreadState { self.tstate_head }
-        }
-
         impl ThreadState for $py::PyThreadState {
             type FrameObject = $py::PyFrameObject;
             type InterpreterState = $py::PyInterpreterState;
@@ -97,6 +95,26 @@ macro_rules! PythonCommonImpl {
     )
 }
 
+/// PyInterpreterState impl, taking the name of the interpreter id field for versions that have one
+macro_rules! InterpreterStateImpl {
+    ($py: ident) => (
+        impl InterpreterState for $py::PyInterpreterState {
+            type ThreadState = $py::PyThreadState;
+            fn head(&self) -> * mut Self::ThreadState { self.tstate_head }
+            fn next(&self) -> * mut Self { self.next }
+            fn id(&self) -> Option<i64> { None }
+        }
+    );
+    ($py: ident, $id: ident) => (
+        impl InterpreterState for $py::PyInterpreterState {
+            type ThreadState = $py::PyThreadState;
+            fn head(&self) -> * mut Self::ThreadState { self.tstate_head }
+            fn next(&self) -> * mut Self { self.next }
+            fn id(&self) -> Option<i64> { Some(self.$id as i64) }
+        }
+    )
+}
+
 // String/Byte handling for Python 3.3+
 macro_rules! Python3StringImpl {
     ($py: ident) => (
@@ -146,20 +164,25 @@ macro_rules! Python2StringImpl {
 
 // Python 3.7
 PythonCommonImpl!(v3_7_0, PyBytesObject, PyUnicodeObject);
+InterpreterStateImpl!(v3_7_0, id);
 Python3StringImpl!(v3_7_0);
 
 // Python 3.6
 PythonCommonImpl!(v3_6_6, PyBytesObject, PyUnicodeObject);
+InterpreterStateImpl!(v3_6_6);
 Python3StringImpl!(v3_6_6);
 
 // python 3.5 and python 3.4
 PythonCommonImpl!(v3_5_5, PyBytesObject, PyUnicodeObject);
+InterpreterStateImpl!(v3_5_5);
 Python3StringImpl!(v3_5_5);
 
 // python 3.3
 PythonCommonImpl!(v3_3_7, PyBytesObject, PyUnicodeObject);
+InterpreterStateImpl!(v3_3_7);
 Python3StringImpl!(v3_3_7);
 
 // Python 2.7
 PythonCommonImpl!(v2_7_15, PyStringObject, PyStringObject);
+InterpreterStateImpl!(v2_7_15);
 Python2StringImpl!(v2_7_15);
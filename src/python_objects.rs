@@ -0,0 +1,219 @@
+/* Helpers for reading python objects that aren't covered by the python_interpreters traits.
+
+The layouts of these (dicts, the object header etc) aren't in the generated bindings in a
+form that is easy to use, so these are defined here. Like the code using them, they only
+match the layout of python 3.7+.
+*/
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::v3_7_0::{PyUnicodeObject, _typeobject};
+use crate::stack_trace::copy_string;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct Object {
+    pub ob_refcnt: isize,
+    pub ob_type: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct DictObject {
+    pub ob_base: Object,
+    pub ma_used: isize,
+    pub ma_version_tag: u64,
+    pub ma_keys: usize,
+    pub ma_values: usize,
+}
+
+// header of a PyDictKeysObject, which is followed by the variable sized index and entry tables
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct DictKeysObject {
+    pub dk_refcnt: isize,
+    pub dk_size: isize,
+    pub dk_lookup: usize,
+    pub dk_usable: isize,
+    pub dk_nentries: isize,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct DictKeyEntry {
+    pub me_hash: isize,
+    pub me_key: usize,
+    pub me_value: usize,
+}
+
+/// Caches the type names of python objects, keyed by the address of the type object
+pub struct TypeNames {
+    names: HashMap<usize, String>,
+}
+
+impl TypeNames {
+    pub fn new() -> TypeNames {
+        TypeNames{names: HashMap::new()}
+    }
+
+    /// Returns the tp_name of the type of the python object at addr
+    pub fn get<P: ProcessMemory>(&mut self, process: &P, addr: usize) -> Result<String, Error> {
+        let object: Object = process.copy_struct(addr)?;
+        if let Some(name) = self.names.get(&object.ob_type) {
+            return Ok(name.clone());
+        }
+
+        let typeobject: _typeobject = process.copy_struct(object.ob_type).context("Failed to copy type object")?;
+        let name = copy_c_string(process, typeobject.tp_name as usize, 128)?;
+        self.names.insert(object.ob_type, name.clone());
+        Ok(name)
+    }
+}
+
+/// Looks up a string key in a python dict, returning the address of the value
+pub fn dict_get<P: ProcessMemory>(process: &P, addr: usize, key: &str) -> Result<Option<usize>, Error> {
+    let dict: DictObject = process.copy_struct(addr).context("Failed to copy dict")?;
+    let keys: DictKeysObject = process.copy_struct(dict.ma_keys).context("Failed to copy dict keys")?;
+    if keys.dk_size <= 0 || keys.dk_size >= 1 << 24 || keys.dk_nentries < 0 || keys.dk_nentries > keys.dk_size {
+        return Err(format_err!("Invalid dict size {} ({} entries)", keys.dk_size, keys.dk_nentries));
+    }
+
+    // the entries are stored after the hash table indices, whose size depends on the size of the dict
+    let size = keys.dk_size as usize;
+    let index_size = if size <= 0xff { 1 } else if size <= 0xffff { 2 } else { 4 };
+    let entries_addr = dict.ma_keys + size_of::<DictKeysObject>() + size * index_size;
+    let entries: Vec<DictKeyEntry> = copy_array(process, entries_addr, keys.dk_nentries as usize)?;
+
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.me_key == 0 {
+            continue;
+        }
+
+        // skip over anything that isn't a string
+        let name = match copy_string(entry.me_key as *const PyUnicodeObject, process) {
+            Ok(name) => name,
+            Err(_) => continue
+        };
+
+        if name == key {
+            // split tables (used for instance dicts) store the values separately
+            let value = if dict.ma_values != 0 {
+                process.copy_struct(dict.ma_values + i * size_of::<usize>())?
+            } else {
+                entry.me_value
+            };
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// Copies an array of count items of type T from the process
+pub fn copy_array<T: Copy, P: ProcessMemory>(process: &P, addr: usize, count: usize) -> Result<Vec<T>, Error> {
+    let bytes = process.copy(addr, count * size_of::<T>())?;
+    Ok(bytes.chunks(size_of::<T>())
+        .map(|chunk| unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const T) })
+        .collect())
+}
+
+/// Copies a null terminated string. This reads a word at a time (aligned so that we never read
+/// past the page the string ends on)
+pub fn copy_c_string<P: ProcessMemory>(process: &P, addr: usize, max_length: usize) -> Result<String, Error> {
+    let word = size_of::<usize>();
+    let mut current = addr - addr % word;
+    let mut bytes = Vec::new();
+    while bytes.len() < max_length {
+        let chunk = process.copy(current, word)?;
+        let start = addr.saturating_sub(current);
+        for &b in &chunk[start..] {
+            if b == 0 {
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            bytes.push(b);
+        }
+        current += word;
+    }
+    Err(format_err!("String at 0x{:x} is longer than {} bytes", addr, max_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+    use crate::python_bindings::v3_7_0::PyASCIIObject;
+
+    // python stores the characters after the PyASCIIObject, allocate some room for these
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct AllocatedPyASCIIObject {
+        base: PyASCIIObject,
+        storage: [u8; 4096]
+    }
+
+    fn to_asciiobject(input: &str) -> Box<AllocatedPyASCIIObject> {
+        let mut base = PyASCIIObject{length: input.len() as isize, ..Default::default()};
+        base.state.set_compact(1);
+        base.state.set_kind(1);
+        base.state.set_ascii(1);
+        let mut ret = Box::new(AllocatedPyASCIIObject{base, storage: [0; 4096]});
+        ret.storage[..input.len()].copy_from_slice(input.as_bytes());
+        ret
+    }
+
+    // a dict with 8 slots, so the index table takes 8 bytes
+    #[repr(C)]
+    struct LocalDictKeys {
+        header: DictKeysObject,
+        indices: [u8; 8],
+        entries: [DictKeyEntry; 5],
+    }
+
+    #[test]
+    fn test_dict_get() {
+        let names = ["foo", "asyncio.tasks", "bar"];
+        let strings: Vec<Box<AllocatedPyASCIIObject>> = names.iter().map(|name| to_asciiobject(name)).collect();
+
+        let mut keys = LocalDictKeys{
+            header: DictKeysObject{dk_refcnt: 1, dk_size: 8, dk_lookup: 0, dk_usable: 1, dk_nentries: 4},
+            indices: [0; 8],
+            entries: [DictKeyEntry{me_hash: 0, me_key: 0, me_value: 0}; 5]
+        };
+        // leave a deleted entry in the middle of the table
+        let keys_in_table = [Some(0), None, Some(1), Some(2)];
+        for (i, key) in keys_in_table.iter().enumerate() {
+            if let Some(key) = key {
+                keys.entries[i].me_key = &*strings[*key] as *const AllocatedPyASCIIObject as usize;
+                keys.entries[i].me_value = 100 + *key;
+            }
+        }
+
+        let dict = DictObject{ob_base: Object{ob_refcnt: 1, ob_type: 0}, ma_used: 3, ma_version_tag: 0,
+                              ma_keys: &keys as *const LocalDictKeys as usize, ma_values: 0};
+        let addr = &dict as *const DictObject as usize;
+        assert_eq!(dict_get(&LocalProcess, addr, "asyncio.tasks").unwrap(), Some(101));
+        assert_eq!(dict_get(&LocalProcess, addr, "bar").unwrap(), Some(102));
+        assert_eq!(dict_get(&LocalProcess, addr, "missing").unwrap(), None);
+
+        // split tables store the values in a separate array
+        let values: [usize; 4] = [200, 0, 201, 202];
+        let split = DictObject{ma_values: values.as_ptr() as usize, ..dict};
+        let addr = &split as *const DictObject as usize;
+        assert_eq!(dict_get(&LocalProcess, addr, "foo").unwrap(), Some(200));
+        assert_eq!(dict_get(&LocalProcess, addr, "bar").unwrap(), Some(202));
+    }
+
+    #[test]
+    fn test_copy_c_string() {
+        let data = b"xx_asyncio.Task\0garbage";
+        let addr = data.as_ptr() as usize + 2;
+        assert_eq!(copy_c_string(&LocalProcess, addr, 128).unwrap(), "_asyncio.Task");
+        assert!(copy_c_string(&LocalProcess, addr, 4).is_err());
+    }
+}
@@ -1,9 +1,9 @@
 use std::collections::HashMap;
-#[cfg(all(target_os="linux", unwind))]
 use std::collections::HashSet;
 use std::mem::size_of;
 use std::slice;
 use std::path::Path;
+use std::time::{Duration, Instant};
 #[cfg(all(target_os="linux", unwind))]
 use std::iter::FromIterator;
 use regex::Regex;
@@ -13,22 +13,34 @@ use remoteprocess::{Process, ProcessMemory, Pid, Tid};
 use proc_maps::{get_process_maps, MapRange};
 
 
+use crate::asyncio::{self, TaskFinder};
 use crate::binary_parser::{parse_binary, BinaryInfo};
 use crate::config::Config;
+#[cfg(target_os="linux")]
+use crate::deadlock::{self, LockFinder, Resource};
+use crate::deadlock::WaitGraph;
 #[cfg(unwind)]
 use crate::native_stack_trace::NativeStack;
 use crate::idle_list::check_idle;
 use crate::python_bindings::{pyruntime, v2_7_15, v3_3_7, v3_5_5, v3_6_6, v3_7_0};
 use crate::python_interpreters::{self, InterpreterState, ThreadState};
-use crate::stack_trace::{StackTrace, get_stack_traces, get_stack_trace};
+use crate::page_cache::{CachedMemory, PageCache};
+use crate::sample_error::{SampleError, SampleErrorKind};
+use crate::sampling_stats::{CountingMemory, MemoryReads, SampleCost};
+use crate::stack_trace::{CodeCache, StackTrace, ThreadWait, get_stack_traces, get_stack_trace, get_checked_stack_trace};
 use crate::version::Version;
 
+type ThreadActivity = HashMap<Tid, bool>;
+
 /// Lets you retrieve stack traces of a running python program
 pub struct PythonSpy {
     pub pid: Pid,
     pub process: Process,
     pub version: Version,
     pub interpreter_address: usize,
+    // address of the pointer to the head of the interpreter list, if known. subinterpreters are
+    // added to the head of this list, so this lets us pick up interpreters created after attaching
+    pub interpreter_head_address: usize,
     pub threadstate_address: usize,
     pub python_filename: String,
     pub version_string: String,
@@ -37,6 +49,17 @@ pub struct PythonSpy {
     pub native: Option<NativeStack>,
     pub short_filenames: HashMap<String, Option<String>>,
     pub python_thread_ids: HashMap<u64, Tid>,
+    pub asyncio_tasks: Option<TaskFinder>,
+    /// What the last call to get_stack_traces cost the process
+    pub last_sample: SampleCost,
+    pub memory_reads: MemoryReads,
+    // the pages read while walking python stacks, cleared for each sample
+    page_cache: PageCache,
+    // the names, filenames and line tables of code objects, kept between samples
+    code_cache: CodeCache,
+    // the system call each thread was last seen waiting in, since suspending the process makes
+    // some of them show up as restart_syscall afterwards
+    thread_waits: HashMap<Tid, ThreadWait>,
 }
 
 impl PythonSpy {
@@ -45,15 +68,33 @@ impl PythonSpy {
         let process = remoteprocess::Process::new(pid)
             .context("Failed to open process - check if it is running.")?;
 
+        if config.native_only {
+            return PythonSpy::new_native_only(pid, process, config);
+        }
+
         // get basic process information (memory maps/symbols etc)
-        let python_info = PythonProcessInfo::new(&process)?;
+        let python_info = PythonProcessInfo::new(&process, config)?;
+        PythonSpy::from_process_info(pid, process, python_info, config)
+    }
 
+    fn from_process_info(pid: Pid, process: Process, python_info: PythonProcessInfo, config: &Config) -> Result<PythonSpy, Error> {
         let version = get_python_version(&python_info, &process)?;
         info!("python version {} detected", version);
 
+        let asyncio_tasks = if config.asyncio {
+            if !asyncio::is_supported(&version) {
+                return Err(format_err!("Getting asyncio tasks isn't supported for python {}", version));
+            }
+            Some(TaskFinder::new(&version))
+        } else {
+            None
+        };
+
         let interpreter_address = get_interpreter_address(&python_info, &process, &version)?;
         info!("Found interpreter at 0x{:016x}", interpreter_address);
 
+        let interpreter_head_address = get_interpreter_head_address(&python_info, &process, &version, interpreter_address);
+
         // lets us figure out which thread has the GIL
          let threadstate_address = match version {
              Version{major: 3, minor: 7..=8, ..} => {
@@ -92,22 +133,55 @@ impl PythonSpy {
 
         #[cfg(all(unwind, not(target_os="linux")))]
         let native = if config.native {
-            Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary)?)
+            Some(NativeStack::new(pid, Some(python_info.python_binary), python_info.libpython_binary, config.defer_symbols)?)
         } else {
             None
         };
 
         #[cfg(all(unwind, target_os="linux"))]
-        let native = Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary)?);
+        let native = Some(NativeStack::new(pid, Some(python_info.python_binary), python_info.libpython_binary, config.defer_symbols)?);
 
-        Ok(PythonSpy{pid, process, version, interpreter_address, threadstate_address,
+        Ok(PythonSpy{pid, process, version, interpreter_address, interpreter_head_address, threadstate_address,
                      python_filename: python_info.python_filename,
                      version_string,
                      #[cfg(unwind)]
                      native,
                      config: config.clone(),
                      short_filenames: HashMap::new(),
-                     python_thread_ids: HashMap::new()})
+                     python_thread_ids: HashMap::new(),
+                     asyncio_tasks,
+                     last_sample: SampleCost::default(),
+                     memory_reads: MemoryReads::default(), page_cache: PageCache::new(),
+                     code_cache: CodeCache::new(), thread_waits: HashMap::new()})
+    }
+
+    // Creates a PythonSpy that only collects native stack traces, without requiring a python
+    // interpreter in the target process
+    #[cfg(unwind)]
+    fn new_native_only(pid: Pid, process: Process, config: &Config) -> Result<PythonSpy, Error> {
+        let python_filename = process.exe()
+            .context("Failed to get process executable name. Check that the process is running.")?;
+        let native = Some(NativeStack::new(pid, None, None, config.defer_symbols)?);
+        info!("collecting native stack traces only from {}", python_filename);
+
+        Ok(PythonSpy{pid, process,
+                     version: Version{major: 0, minor: 0, patch: 0, release_flags: "".to_owned()},
+                     interpreter_address: 0, interpreter_head_address: 0, threadstate_address: 0,
+                     python_filename,
+                     version_string: "native".to_owned(),
+                     native,
+                     config: config.clone(),
+                     short_filenames: HashMap::new(),
+                     python_thread_ids: HashMap::new(),
+                     asyncio_tasks: None,
+                     last_sample: SampleCost::default(),
+                     memory_reads: MemoryReads::default(), page_cache: PageCache::new(),
+                     code_cache: CodeCache::new(), thread_waits: HashMap::new()})
+    }
+
+    #[cfg(not(unwind))]
+    fn new_native_only(_pid: Pid, _process: Process, _config: &Config) -> Result<PythonSpy, Error> {
+        Err(format_err!("Collecting native stack traces is not supported on this OS"))
     }
 
     /// Creates a PythonSpy object, retrying up to max_retries times.
@@ -137,8 +211,109 @@ impl PythonSpy {
         }
     }
 
+    /// Creates a PythonSpy object for a process that we've just launched, polling until the
+    /// interpreter has been initialized. Unlike retry_new this doesn't give up after a fixed
+    /// number of attempts, and polls often enough that we don't miss the start of the program.
+    ///
+    /// The python binary is parsed before calling `resume`, so that a process that was started
+    /// stopped doesn't run until we're ready to start polling it.
+    pub fn wait_for_interpreter<F>(pid: Pid, config: &Config, timeout: Duration, resume: F) -> Result<PythonSpy, Error>
+        where F: FnOnce() -> Result<(), Error> {
+        if config.native_only {
+            resume()?;
+            return PythonSpy::retry_new(pid, config, 8);
+        }
+
+        let process = remoteprocess::Process::new(pid);
+        let mut parsed = match process {
+            Ok(ref process) => PythonProcessInfo::new(process, config),
+            Err(_) => Err(format_err!("Failed to open process {}", pid))
+        };
+        resume()?;
+        let start = Instant::now();
+        let process = process.context("Failed to open process - check if it is running.")?;
+
+        // returns the error if we should stop waiting
+        let check_timeout = |err: Error| -> Result<(), Error> {
+            if process.exe().is_err() {
+                return Err(format_err!("Process {} exited before python was initialized: {}", pid, err));
+            }
+            if start.elapsed() > timeout {
+                return Err(err);
+            }
+            std::thread::sleep(INTERPRETER_POLL_INTERVAL);
+            Ok(())
+        };
+
+        // wait for the dynamic loader to map in libpython. Parsing the binaries is slow, so
+        // only the memory maps are re-read while waiting, and the binaries are parsed again once
+        // libpython shows up. Binaries that have had their symbols stripped won't ever have the
+        // interpreter symbols, so only wait a little while for these
+        let python_lib = libpython_regex(config)?;
+        let mut waited = false;
+        let mut python_info = loop {
+            let err = match parsed {
+                Ok(info) => {
+                    if info.has_interpreter_symbols() || waited {
+                        break info;
+                    }
+                    format_err!("Failed to find python interpreter symbols")
+                },
+                Err(err) if waited => return Err(err),
+                Err(err) => err
+            };
+            check_timeout(err)?;
+
+            while !get_process_maps(pid).is_ok_and(|maps| find_libpython_map(&maps, &python_lib).is_some()) &&
+                  start.elapsed() <= STRIPPED_BINARY_WAIT {
+                check_timeout(format_err!("Failed to find libpython in virtual memory maps"))?;
+            }
+            waited = start.elapsed() > STRIPPED_BINARY_WAIT;
+            parsed = PythonProcessInfo::new(&process, config);
+        };
+
+        // wait for the interpreter to be created. This happens early on in Py_Initialize, before
+        // any python code (including the imports from site.py) has been run
+        loop {
+            let err = match get_python_version(&python_info, &process) {
+                Ok(ref version) if !is_supported_version(version) => {
+                    return Err(format_err!("Unsupported version of Python: {}", version));
+                },
+                Ok(version) => match get_interpreter_address(&python_info, &process, &version) {
+                    Ok(_) => break,
+                    Err(err) => err
+                },
+                Err(err) => err
+            };
+            check_timeout(err)?;
+
+            // the interpreter is allocated on the heap, which might not be in the maps we've read
+            python_info.maps = get_process_maps(pid)?;
+        }
+
+        let mut process = PythonSpy::from_process_info(pid, process, python_info, config)?;
+        process.get_stack_traces()?;
+        info!("Python interpreter initialized after {:.2?}", start.elapsed());
+        Ok(process)
+    }
+
+    /// Returns the version of python running in a process, without attaching to it. This parses
+    /// the binaries to find the interpreter the same way new does, so also finds programs that
+    /// embed python
+    pub fn python_version(process: &remoteprocess::Process, config: &Config) -> Result<Version, Error> {
+        let python_info = PythonProcessInfo::new(process, config)?;
+        get_python_version(&python_info, process)
+    }
+
     /// Gets a StackTrace for each thread in the current process
     pub fn get_stack_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
+        #[cfg(unwind)]
+        {
+            if self.config.native_only {
+                return self._get_native_stack_traces();
+            }
+        }
+
         match self.version {
             // Currently 3.7.x and 3.8.0a0 have the same ABI, but this might change
             // as 3.8 evolves
@@ -157,73 +332,297 @@ impl PythonSpy {
 
     // implementation of get_stack_traces, where we have a type for the InterpreterState
     fn _get_stack_traces<I: InterpreterState>(&mut self) -> Result<Vec<StackTrace>, Error> {
-        // Query the OS to get if each thread in the process is running or not
-        let mut thread_activity = HashMap::new();
-        for thread in self.process.threads()?.iter() {
-            let threadid: Tid = thread.id()?;
-            thread_activity.insert(threadid, thread.active()?);
-        }
+        // Query the OS to get if each thread in the process is running or not, and what the idle
+        // threads are waiting on
+        let (thread_activity, mut thread_waits) = self._get_thread_activity(&self.process.threads()?)?;
 
         // Lock the process if appropiate. Note we have to lock AFTER getting the thread
-        // activity status from the OS (otherwise each thread would report being inactive always).
+        // activity status from the OS (otherwise each thread would report being inactive always,
+        // and would show up as being in the restart_syscall the lock interrupted them with).
         // This has the potential for race conditions (in that the thread activity could change
         // between getting the status and locking the thread, but seems unavoidable right now
+        self.memory_reads.take();
+        // pages left over from a sample that failed part way through are out of date
+        self.page_cache.clear();
+        let mut unwind = Duration::from_secs(0);
+        let paused = Instant::now();
         let _lock = if self.config.non_blocking {
             None
         } else {
-            Some(self.process.lock().context("Failed to suspend process")?)
+            Some(self.process.lock()
+                .map_err(|err| SampleError::wrap(SampleErrorKind::Suspend, "Failed to suspend process", err.into()))?)
         };
 
         let gil_thread_id = self._get_gil_threadid::<I>()?;
 
-        // Get the python interpreter, and loop over all the python threads
-        let interp: I = self.process.copy_struct(self.interpreter_address)
-           .context("Failed to copy PyInterpreterState from process")?;
+        // Get all the python interpreters, and loop over all the python threads in each
+        let interpreters = self._get_interpreters::<I>()?;
 
         let mut traces = Vec::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
-            // Get the stack trace of the python thread
-            let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
-            let mut trace = get_stack_trace(&thread, &self.process)?;
+        for (interpreter_id, interp) in interpreters.iter() {
+            let mut threads = interp.head();
+            while !threads.is_null() {
+                // Get the stack trace of the python thread
+                let thread = self.memory().copy_pointer(threads).context("Failed to copy PyThreadState")?;
+                // without pausing the process, the stack can change while we're reading it
+                let mut trace = if self.config.non_blocking {
+                    get_checked_stack_trace(threads, &CountingMemory::new(&self.process, &self.memory_reads), &mut self.code_cache)?
+                } else {
+                    let memory = CachedMemory::new(CountingMemory::new(&self.process, &self.memory_reads), &self.page_cache);
+                    get_stack_trace(&thread, &memory, &mut self.code_cache)?
+                };
+                trace.interpreter_id = *interpreter_id;
+
+                // Try getting the native thread id
+                let python_thread_id = thread.thread_id();
+                let os_thread_id = self._get_os_thread_id(python_thread_id, &interpreters)?;
+
+                #[cfg(unwind)]
+                {
+                    if self.config.native {
+                        if let Some(native) = self.native.as_mut() {
+                            let os_thread = remoteprocess::Thread::new(os_thread_id.unwrap())?;
+                            let unwinding = Instant::now();
+                            trace.frames = native.merge_native_thread(&trace.frames, &os_thread)?;
+                            unwind += unwinding.elapsed();
+                        }
+                    }
+                }
 
-            // Try getting the native thread id
-            let python_thread_id = thread.thread_id();
-            let os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+                trace.os_thread_id = os_thread_id.map(|id| id as u64);
+                trace.owns_gil = trace.thread_id == gil_thread_id;
 
-            #[cfg(unwind)]
-            {
-                if self.config.native {
-                    if let Some(native) = self.native.as_mut() {
-                        let os_thread = remoteprocess::Thread::new(os_thread_id.unwrap())?;
-                        trace.frames = native.merge_native_thread(&trace.frames, &os_thread)?;
+                trace.active = match os_thread_id.map(|id| thread_activity.get(&id)) {
+                    Some(Some(active)) => *active && !self._heuristic_is_thread_idle(&trace),
+                    _ => !self._heuristic_is_thread_idle(&trace)
+                };
+                if !trace.active {
+                    trace.wait = os_thread_id.and_then(|id| thread_waits.remove(&id));
+                }
+
+                for frame in &mut trace.frames {
+                    // frames with deferred symbolication don't have a filename yet
+                    if frame.addr.is_none() {
+                        frame.short_filename = self.shorten_filename(&frame.filename);
                     }
                 }
+
+                // This seems to happen occasionally when scanning BSS addresses for valid interpeters
+                traces.push(trace);
+                if traces.len() > 4096 {
+                    return Err(SampleError::new(SampleErrorKind::InvalidData, "Max thread recursion depth reached".to_owned()).into());
+                }
+
+                threads = thread.next();
             }
+        }
 
-            trace.os_thread_id = os_thread_id.map(|id| id as u64);
-            trace.owns_gil = trace.thread_id == gil_thread_id;
+        // add in pseudo-threads for any pending asyncio tasks (only supported for 3.7+, where we
+        // can use the v3_7_0 bindings directly)
+        if self.asyncio_tasks.is_some() {
+            for mut trace in self._get_asyncio_traces()? {
+                for frame in &mut trace.frames {
+                    frame.short_filename = self.shorten_filename(&frame.filename);
+                }
+                traces.push(trace);
+            }
+        }
+        let (page_hits, page_misses) = self.page_cache.clear();
+        let (code_hits, code_misses) = self.code_cache.take_stats();
+        debug!("page cache: {} of {} reads didn't need a system call, code cache: {} of {} frames were cached",
+               page_hits, page_hits + page_misses, code_hits, code_hits + code_misses);
+        self.set_last_sample(paused.elapsed(), unwind);
+        Ok(traces)
+    }
 
-            trace.active = match os_thread_id.map(|id| thread_activity.get(&id)) {
-                Some(Some(active)) => *active && !self._heuristic_is_thread_idle(&trace),
-                _ => !self._heuristic_is_thread_idle(&trace)
-            };
+    // Returns whether each thread is running, and what the idle ones are waiting on. Suspending
+    // the process interrupts timed waits, which the kernel then resumes with restart_syscall
+    // (running briefly to do so), so this remembers what each thread was last waiting on
+    fn _get_thread_activity(&mut self, threads: &[remoteprocess::Thread]) -> Result<(ThreadActivity, HashMap<Tid, ThreadWait>), Error> {
+        let mut thread_activity = HashMap::new();
+        let mut thread_waits = HashMap::new();
+        let mut last_waits = HashMap::new();
+        for thread in threads {
+            let threadid = thread.id()?;
+            let active = thread.active()?;
+            thread_activity.insert(threadid, active);
+            if !active {
+                if let Some(wait) = get_thread_wait(thread, self.thread_waits.get(&threadid)) {
+                    thread_waits.insert(threadid, wait);
+                }
+            }
+            if let Some(wait) = thread_waits.get(&threadid).or_else(|| self.thread_waits.get(&threadid)) {
+                last_waits.insert(threadid, wait.clone());
+            }
+        }
+        self.thread_waits = last_waits;
+        Ok((thread_activity, thread_waits))
+    }
 
+    // gets the native stack for every thread in the process, for when we're not
+    // looking for a python interpreter
+    #[cfg(unwind)]
+    fn _get_native_stack_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
+        let threads = self.process.threads()?;
+
+        // get the thread activity before locking (see _get_stack_traces)
+        let (thread_activity, mut thread_waits) = self._get_thread_activity(&threads)?;
+
+        self.memory_reads.take();
+        let paused = Instant::now();
+        let _lock = self.process.lock()
+            .map_err(|err| SampleError::wrap(SampleErrorKind::Suspend, "Failed to suspend process", err.into()))?;
+
+        let native = self.native.as_mut().ok_or_else(|| format_err!("Native stack unwinder isn't available"))?;
+        let mut traces = Vec::new();
+        for thread in threads.iter() {
+            let threadid = thread.id()?;
+            let frames = native.merge_native_thread(&Vec::new(), thread)?;
+            traces.push(StackTrace{thread_id: threadid as u64, os_thread_id: Some(threadid as u64),
+                                   active: thread_activity.get(&threadid).cloned().unwrap_or(true),
+                                   owns_gil: false, interpreter_id: 0, task_name: None,
+                                   wait: thread_waits.remove(&threadid), frames});
+        }
+
+        for trace in traces.iter_mut() {
             for frame in &mut trace.frames {
-                frame.short_filename = self.shorten_filename(&frame.filename);
+                if frame.addr.is_none() {
+                    frame.short_filename = self.shorten_filename(&frame.filename);
+                }
             }
+        }
+        // everything here is unwinding native stacks
+        let pause = paused.elapsed();
+        self.set_last_sample(pause, pause);
+        Ok(traces)
+    }
 
-            // This seems to happen occasionally when scanning BSS addresses for valid interpeters
-            traces.push(trace);
-            if traces.len() > 4096 {
-                return Err(format_err!("Max thread recursion depth reached"));
-            }
+    // The memory of the target process, counting the reads made for the sample cost
+    fn memory(&self) -> CountingMemory<'_, Process> {
+        CountingMemory::new(&self.process, &self.memory_reads)
+    }
 
-            threads = thread.next();
+    fn set_last_sample(&mut self, pause: Duration, unwind: Duration) {
+        let (reads, bytes) = self.memory_reads.take();
+        self.last_sample = SampleCost{pause, unwind, reads, bytes};
+    }
+
+    fn _get_asyncio_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
+        let interpreters = self._get_interpreters::<v3_7_0::_is>()?;
+        let mut traces = Vec::new();
+        if let Some(tasks) = self.asyncio_tasks.as_mut() {
+            for (interpreter_id, interp) in interpreters.iter() {
+                traces.extend(tasks.get_tasks(&CountingMemory::new(&self.process, &self.memory_reads),
+                                              *interpreter_id, interp.modules as usize)?);
+            }
         }
         Ok(traces)
     }
 
+    /// Gets a graph of which lock (or the GIL) each python thread is waiting on, and which
+    /// threads hold those locks. This is used to find deadlocks.
+    #[cfg(target_os="linux")]
+    pub fn get_wait_graph(&mut self) -> Result<WaitGraph, Error> {
+        if !deadlock::is_supported(&self.version) {
+            return Err(format_err!("Finding deadlocks isn't supported for python {}", self.version));
+        }
+
+        // get the syscall each thread is blocked in before locking the process, since that
+        // interrupts the syscalls (see _get_stack_traces)
+        let mut syscalls = HashMap::new();
+        for thread in self.process.threads()?.iter() {
+            if let Some(syscall) = thread.syscall()? {
+                syscalls.insert(thread.id()?, syscall);
+            }
+        }
+
+        let _lock = self.process.lock().context("Failed to suspend process")?;
+
+        // the GIL's mutexes and condition variables are stored in _PyRuntime.ceval, which is
+        // before the gilstate
+        let gil = match pyruntime::get_tstate_current_offset(&self.version) {
+            Some(offset) if self.threadstate_address > offset => Some((self.threadstate_address - offset, self.threadstate_address)),
+            _ => None
+        };
+        let mut finder = LockFinder::new(gil);
+        let mut graph = WaitGraph::new();
+
+        let gil_thread_id = self._get_gil_threadid::<v3_7_0::_is>()?;
+        if gil_thread_id != 0 {
+            graph.add_owner(Resource::Gil, gil_thread_id);
+        }
+
+        let interpreters = self._get_interpreters::<v3_7_0::_is>()?;
+        let mut thread_ids = Vec::new();
+        for (_, interp) in interpreters.iter() {
+            let mut threads = interp.head();
+            while !threads.is_null() {
+                let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
+                for (lock, owner) in finder.get_locks(&self.process, thread.thread_id(), thread.frame())? {
+                    graph.add_owner(lock, owner);
+                }
+                thread_ids.push(thread.thread_id());
+                if thread_ids.len() > 4096 {
+                    return Err(format_err!("Max thread recursion depth reached"));
+                }
+                threads = thread.next();
+            }
+        }
+
+        // figure out which lock each thread is waiting on from the futex it's blocked on
+        for thread_id in thread_ids {
+            let syscall = match self._get_os_thread_id(thread_id, &interpreters)?.and_then(|tid| syscalls.get(&tid)) {
+                Some(syscall) => syscall,
+                None => continue
+            };
+            if syscall.number != libc::SYS_futex as i64 {
+                continue;
+            }
+            if let Some(resource) = finder.get_futex_resource(syscall.args[0] as usize) {
+                graph.add_waiting(thread_id, resource);
+            }
+        }
+        Ok(graph)
+    }
+
+    #[cfg(not(target_os="linux"))]
+    pub fn get_wait_graph(&mut self) -> Result<WaitGraph, Error> {
+        Err(format_err!("Finding deadlocks is only supported on linux"))
+    }
+
+    // Returns each interpreter in the process along with its id, by following the
+    // PyInterpreterState.next linked list
+    fn _get_interpreters<I: InterpreterState>(&self) -> Result<Vec<(i64, I)>, Error> {
+        let mut addr = self.interpreter_address;
+        if self.interpreter_head_address != 0 {
+            let head: usize = self.memory().copy_struct(self.interpreter_head_address)
+                .context("Failed to read head of the interpreter list")?;
+            if head != 0 {
+                addr = head;
+            }
+        }
+
+        let mut interpreters = Vec::new();
+        while addr != 0 {
+            let interp: I = self.memory().copy_struct(addr)
+                .context("Failed to copy PyInterpreterState from process")?;
+            addr = interp.next() as usize;
+            interpreters.push(interp);
+            if interpreters.len() > 4096 {
+                return Err(format_err!("Max interpreter recursion depth reached"));
+            }
+        }
+
+        // Python versions before 3.7 don't store an id in the interpreter. New interpreters
+        // are added at the head of the list, so number them from the tail instead
+        // (which makes the main interpreter 0, same as in 3.7)
+        let count = interpreters.len() as i64;
+        Ok(interpreters.into_iter().enumerate().map(|(index, interp)| {
+            let id = interp.id().unwrap_or(count - 1 - index as i64);
+            (id, interp)
+        }).collect())
+    }
+
     // heuristic fallback for determining if a thread is active, used
     // when we don't have the ability to get the thread information from the OS
     fn _heuristic_is_thread_idle(&self, trace: &StackTrace) -> bool {
@@ -242,12 +641,12 @@ impl PythonSpy {
     }
 
     #[cfg(windows)]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interps: &[(i64, I)]) -> Result<Option<Tid>, Error> {
         Ok(Some(python_thread_id as Tid))
     }
 
     #[cfg(target_os="macos")]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interps: &[(i64, I)]) -> Result<Option<Tid>, Error> {
         // If we've already know this threadid, we're good
         if let Some(thread_id) = self.python_thread_ids.get(&python_thread_id) {
             return Ok(Some(*thread_id));
@@ -267,25 +666,27 @@ impl PythonSpy {
     }
 
     #[cfg(all(target_os="linux", not(unwind)))]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interps: &[(i64, I)]) -> Result<Option<Tid>, Error> {
         Ok(None)
     }
 
     #[cfg(all(target_os="linux", unwind))]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, interps: &[(i64, I)]) -> Result<Option<Tid>, Error> {
         // If we've already know this threadid, we're good
         if let Some(thread_id) = self.python_thread_ids.get(&python_thread_id) {
             return Ok(Some(*thread_id));
         }
 
-        // Get a list of all the python thread ids
+        // Get a list of all the python thread ids, across all the interpreters
         let mut all_python_threads = HashSet::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
-            let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
-            let current = thread.thread_id();
-            all_python_threads.insert(current);
-            threads = thread.next();
+        for (_, interp) in interps {
+            let mut threads = interp.head();
+            while !threads.is_null() {
+                let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
+                let current = thread.thread_id();
+                all_python_threads.insert(current);
+                threads = thread.next();
+            }
         }
 
         let processed_os_threads: HashSet<Tid> = HashSet::from_iter(self.python_thread_ids.values().map(|x| *x));
@@ -336,18 +737,18 @@ impl PythonSpy {
     }
 
     #[cfg(target_os="freebsd")]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interps: &[(i64, I)]) -> Result<Option<Tid>, Error> {
         Ok(None)
     }
 
     fn _get_gil_threadid<I: InterpreterState>(&self) -> Result<u64, Error> {
         // figure out what thread has the GIL by inspecting _PyThreadState_Current
         if self.threadstate_address > 0 {
-            let addr: usize = self.process.copy_struct(self.threadstate_address)?;
+            let addr: usize = self.memory().copy_struct(self.threadstate_address)?;
 
             // if the addr is 0, no thread is currently holding the GIL
             if addr != 0 {
-                let threadstate: I::ThreadState = self.process.copy_struct(addr)?;
+                let threadstate: I::ThreadState = self.memory().copy_struct(addr)?;
                 return Ok(threadstate.thread_id());
             }
         }
@@ -476,6 +877,34 @@ fn get_interpreter_address(python_info: &PythonProcessInfo,
     }
 }
 
+/// Returns the address of the pointer to the head of the interpreter list from symbols,
+/// or 0 if this can't be determined. This is only returned if the current head of the list
+/// is the interpreter we've found
+fn get_interpreter_head_address(python_info: &PythonProcessInfo,
+                                process: &remoteprocess::Process,
+                                version: &Version,
+                                interpreter_address: usize) -> usize {
+    let head_address = match version {
+        Version{major: 3, minor: 7..=8, ..} => {
+            match python_info.get_symbol("_PyRuntime") {
+                Some(&addr) => addr as usize + pyruntime::get_interp_head_offset(&version),
+                None => return 0
+            }
+        },
+        _ => {
+            match python_info.get_symbol("interp_head") {
+                Some(&addr) => addr as usize,
+                None => return 0
+            }
+        }
+    };
+
+    match process.copy_struct::<usize>(head_address) {
+        Ok(addr) if addr == interpreter_address => head_address,
+        _ => 0
+    }
+}
+
 fn get_interpreter_address_from_binary(binary: &BinaryInfo,
                                        maps: &[MapRange],
                                        process: &remoteprocess::Process,
@@ -551,6 +980,10 @@ fn check_interpreter_addresses(addrs: &[usize],
     }
 }
 
+fn is_supported_version(version: &Version) -> bool {
+    matches!(version, Version{major: 3, minor: 3..=8, ..} | Version{major: 2, minor: 3..=7, ..})
+}
+
 /// Holds information about the python process: memory map layout, parsed binary info
 /// for python /libpython etc.
 pub struct PythonProcessInfo {
@@ -563,7 +996,7 @@ pub struct PythonProcessInfo {
 }
 
 impl PythonProcessInfo {
-    fn new(process: &remoteprocess::Process) -> Result<PythonProcessInfo, Error> {
+    fn new(process: &remoteprocess::Process, config: &Config) -> Result<PythonProcessInfo, Error> {
         let filename = process.exe()
             .context("Failed to get process executable name. Check that the process is running.")?;
 
@@ -642,12 +1075,7 @@ impl PythonProcessInfo {
 
         // likewise handle libpython for python versions compiled with --enabled-shared
          let libpython_binary = {
-            let libmap = maps.iter()
-                .find(|m| if let Some(ref pathname) = &m.filename() {
-                    is_python_lib(pathname) && m.is_exec()
-                } else {
-                    false
-                });
+            let libmap = find_libpython_map(&maps, &libpython_regex(config)?);
 
             let mut libpython_binary: Option<BinaryInfo> = None;
             if let Some(libpython) = libmap {
@@ -700,9 +1128,23 @@ impl PythonProcessInfo {
             libpython_binary
         };
 
+        // Programs that embed python (like a C++ server that statically links or dlopen's a
+        // renamed libpython) won't have been picked up above. Look through every other mapped
+        // binary for the interpreter instead - unless this is a stripped python binary
+        let libpython_binary = match libpython_binary {
+            None if !has_interpreter_symbols(&python_binary) && !is_python_binary(process, &python_binary, &python_filename) => {
+                find_embedded_python(&maps, &python_filename)
+            },
+            libpython_binary => libpython_binary
+        };
+
         Ok(PythonProcessInfo{python_binary, libpython_binary, maps, python_filename})
     }
 
+    fn has_interpreter_symbols(&self) -> bool {
+        self.libpython_binary.is_some() || has_interpreter_symbols(&self.python_binary)
+    }
+
     pub fn get_symbol(&self, symbol: &str) -> Option<&u64> {
         if let Some(addr) = self.python_binary.symbols.get(symbol) {
             info!("got symbol {} (0x{:016x}) from python binary", symbol, addr);
@@ -719,6 +1161,71 @@ impl PythonProcessInfo {
     }
 }
 
+// the user can override how we find libpython, for when it has been renamed
+fn libpython_regex(config: &Config) -> Result<Option<Regex>, Error> {
+    match config.python_lib {
+        Some(ref pattern) => Ok(Some(Regex::new(pattern).context("Invalid --python-lib regex")?)),
+        None => Ok(None)
+    }
+}
+
+fn find_libpython_map<'a>(maps: &'a [MapRange], python_lib: &Option<Regex>) -> Option<&'a MapRange> {
+    maps.iter().find(|m| match (m.filename(), python_lib) {
+        (Some(pathname), Some(re)) => re.is_match(pathname) && m.is_exec(),
+        (Some(pathname), None) => is_python_lib(pathname) && m.is_exec(),
+        (None, _) => false
+    })
+}
+
+const INTERPRETER_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const STRIPPED_BINARY_WAIT: Duration = Duration::from_millis(100);
+
+const INTERPRETER_SYMBOLS: [&str; 3] = ["_PyRuntime", "interp_head", "Py_GetVersion"];
+
+fn has_interpreter_symbols(binary: &BinaryInfo) -> bool {
+    INTERPRETER_SYMBOLS.iter().any(|symbol| binary.symbols.contains_key(*symbol))
+}
+
+// Returns whether the main binary is python itself, from the version string in its BSS section or
+// from its filename (the version string isn't filled in until the interpreter is initialized)
+fn is_python_binary(process: &remoteprocess::Process, binary: &BinaryInfo, filename: &str) -> bool {
+    let named_python = Path::new(filename).file_name().is_some_and(|name| name.to_string_lossy().starts_with("python"));
+    named_python || (binary.bss_size != 0 &&
+        process.copy(binary.bss_addr as usize, binary.bss_size as usize).is_ok_and(|bss| Version::scan_bytes(&bss).is_ok()))
+}
+
+/// Searches all the executable files mapped into the process for one exporting the python
+/// interpreter symbols. A python version string alone isn't enough to go on, since other
+/// libraries can contain one without having an interpreter in them
+fn find_embedded_python(maps: &[MapRange], exclude: &str) -> Option<BinaryInfo> {
+    info!("Searching all mapped binaries for an embedded python interpreter");
+
+    let mut seen = HashSet::new();
+    for map in maps.iter().filter(|m| m.is_exec()) {
+        let filename = match map.filename() {
+            Some(filename) => filename,
+            None => continue
+        };
+        if filename == exclude || !seen.insert(filename.clone()) {
+            continue;
+        }
+
+        let binary = match parse_binary(&filename, map.start() as u64, map.size() as u64) {
+            Ok(binary) => binary,
+            Err(e) => {
+                debug!("Failed to parse {}: {}", filename, e);
+                continue;
+            }
+        };
+
+        if has_interpreter_symbols(&binary) {
+            info!("Found python interpreter symbols in {}", filename);
+            return Some(binary);
+        }
+    }
+    None
+}
+
 // We can't use goblin to parse external symbol files (like in a separate .pdb file) on windows,
 // So use the win32 api to load up the couple of symbols we need on windows. Note:
 // we still can get export's from the PE file
@@ -746,6 +1253,23 @@ pub fn get_windows_python_symbols(pid: Pid, filename: &str, offset: u64) -> std:
     Ok(ret)
 }
 
+// Returns the system call an idle thread is sleeping in, or the kernel function if that
+// isn't available. This has to be called before suspending the process, and for threads in
+// restart_syscall returns the wait that was interrupted instead, if we saw it
+#[cfg(target_os="linux")]
+fn get_thread_wait(thread: &remoteprocess::Thread, previous: Option<&ThreadWait>) -> Option<ThreadWait> {
+    match thread.syscall() {
+        Ok(Some(syscall)) if syscall.number == libc::SYS_restart_syscall as i64 && previous.is_some() => previous.cloned(),
+        Ok(Some(syscall)) => Some(ThreadWait::Syscall{number: syscall.number, args: syscall.args}),
+        _ => thread.wchan().ok()?.map(ThreadWait::Kernel)
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_thread_wait(_thread: &remoteprocess::Thread, _previous: Option<&ThreadWait>) -> Option<ThreadWait> {
+    None
+}
+
 #[cfg(any(target_os="linux", target_os="freebsd"))]
 pub fn is_python_lib(pathname: &str) -> bool {
     lazy_static! {
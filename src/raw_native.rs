@@ -0,0 +1,489 @@
+// Support for recording native stacks without symbolicating them, and resolving the recorded
+// addresses afterwards with 'py-spy symbolize' (possibly on a different machine that has debug
+// symbols available for the binaries).
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::program_header::{PF_X, PT_LOAD};
+use goblin::elf::Elf;
+use goblin::Object;
+use memmap::Mmap;
+use proc_maps::get_process_maps;
+use remoteprocess::{Pid, SymbolData};
+
+use crate::cython;
+use crate::native_stack_trace::translate_native_frame;
+use crate::stack_trace::{Frame, StackTrace};
+
+/// Bumped whenever the layout of the recording file changes
+const RECORDING_VERSION: u32 = 1;
+
+/// A binary that was mapped into the target process while recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MappedObject {
+    pub path: String,
+    pub build_id: Option<String>,
+    pub start: u64,
+    pub end: u64,
+    /// The address the binary was loaded at (what symbol addresses are relative to)
+    pub load_address: u64,
+}
+
+impl MappedObject {
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RawFrame {
+    pub name: String,
+    pub filename: String,
+    pub module: Option<String>,
+    pub line: i32,
+    /// Set for native frames that still need to be symbolicated
+    pub addr: Option<u64>,
+    /// The index of the binary containing addr in the recording's objects. Missing from
+    /// recordings made before this was tracked, where the binary is looked up by address
+    #[serde(default)]
+    pub object: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawSample {
+    pub time_stamp: u64,
+    pub thread_id: u64,
+    pub count: u64,
+    pub frames: Vec<RawFrame>,
+}
+
+/// Stack traces recorded with unresolved native frames, along with the memory
+/// layout needed to resolve them later
+#[derive(Serialize, Deserialize)]
+pub struct RawRecording {
+    pub version: u32,
+    pub pid: Pid,
+    pub objects: Vec<MappedObject>,
+    pub samples: Vec<RawSample>,
+    /// What sampling cost the process, from SamplingStats::metadata
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(skip)]
+    sample_index: HashMap<(u64, u64, Vec<RawFrame>), usize>,
+    // addresses that we couldn't find in the memory maps, used to avoid
+    // re-reading /proc/<pid>/maps each time we see them
+    #[serde(skip)]
+    unmapped: HashSet<u64>,
+    // the indices of the objects that were mapped when we last read the memory maps
+    #[serde(skip)]
+    mapped: Vec<usize>,
+    // the time stamp of the samples when we last read the memory maps
+    #[serde(skip)]
+    refreshed_at: Option<u64>,
+}
+
+impl RawRecording {
+    pub fn new(pid: Pid) -> Result<RawRecording, Error> {
+        let mut ret = RawRecording{version: RECORDING_VERSION, pid, objects: Vec::new(), samples: Vec::new(),
+                                   metadata: BTreeMap::new(), sample_index: HashMap::new(), unmapped: HashSet::new(),
+                                   mapped: Vec::new(), refreshed_at: None};
+        ret.refresh_objects()?;
+        Ok(ret)
+    }
+
+    pub fn load(filename: &str) -> Result<RawRecording, Error> {
+        let file = File::open(filename).context("Failed to open recording")?;
+        let recording: RawRecording = serde_json::from_reader(file)
+            .context("Failed to parse recording - was it written with 'record --defer-symbols'?")?;
+        if recording.version != RECORDING_VERSION {
+            return Err(format_err!("Unsupported recording version {} (expected {})",
+                                   recording.version, RECORDING_VERSION));
+        }
+        Ok(recording)
+    }
+
+    pub fn write(&self, filename: &str) -> Result<(), Error> {
+        let mut out_file = File::create(filename)?;
+        serde_json::to_writer(&mut out_file, self)?;
+        Ok(())
+    }
+
+    /// Continues the recording in another process, for when the target is restarted. The binaries
+    /// mapped into the old process are kept for the samples that are already in them
+    pub fn set_pid(&mut self, pid: Pid) {
+        self.pid = pid;
+        self.mapped.clear();
+        self.unmapped.clear();
+        self.refreshed_at = None;
+    }
+
+    pub fn increment(&mut self, time_stamp: u64, trace: &StackTrace, count: u64) -> Result<(), Error> {
+        // libraries can be unloaded and others loaded in their place without any of our addresses
+        // becoming unmapped, so the memory maps get reread for each second of samples. Only the
+        // samples from the second that a library was replaced in can end up with the old one
+        if trace.frames.iter().any(|frame| frame.addr.is_some()) && self.refreshed_at != Some(time_stamp) {
+            // the process may have just exited, in which case the maps we have are still right
+            if let Err(e) = self.refresh_objects() {
+                debug!("Failed to reread memory maps: {}", e);
+            }
+            self.unmapped.clear();
+            self.refreshed_at = Some(time_stamp);
+        }
+
+        let mut frames = Vec::with_capacity(trace.frames.len());
+        for frame in &trace.frames {
+            let object = frame.addr.and_then(|addr| self.find_object(addr));
+            frames.push(RawFrame{name: frame.name.clone(), filename: frame.filename.clone(), module: frame.module.clone(),
+                                 line: frame.line, addr: frame.addr, object});
+        }
+
+        let samples = &mut self.samples;
+        let index = *self.sample_index.entry((time_stamp, trace.thread_id, frames.clone())).or_insert_with(|| {
+            samples.push(RawSample{time_stamp, thread_id: trace.thread_id, count: 0, frames});
+            samples.len() - 1
+        });
+        self.samples[index].count += count;
+        Ok(())
+    }
+
+    // Returns the index of the mapped binary containing an address. Libraries can be loaded while
+    // we are recording, so make sure that we have a snapshot of whatever binary contains this
+    // address while it's still mapped
+    fn find_object(&mut self, addr: u64) -> Option<usize> {
+        if let Some(index) = self.mapped_object(addr) {
+            return Some(index);
+        }
+        if self.unmapped.contains(&addr) {
+            return None;
+        }
+
+        // the process may have just exited, in which case there's no telling what was here
+        if let Err(e) = self.refresh_objects() {
+            debug!("Failed to reread memory maps: {}", e);
+        }
+        let index = self.mapped_object(addr);
+        if index.is_none() {
+            debug!("address 0x{:x} isn't in a mapped binary", addr);
+            self.unmapped.insert(addr);
+        }
+        index
+    }
+
+    fn mapped_object(&self, addr: u64) -> Option<usize> {
+        self.mapped.iter().cloned().find(|&index| self.objects[index].contains(addr))
+    }
+
+    fn refresh_objects(&mut self) -> Result<(), Error> {
+        let maps = get_process_maps(self.pid)?;
+        let mappings: Vec<(String, u64, u64, u64)> = maps.iter()
+            .filter(|m| m.is_exec() && m.is_read())
+            .filter_map(|m| m.filename().as_ref().map(|filename| (filename.to_string(), m.start() as u64,
+                                                                  (m.start() + m.size()) as u64, m.offset as u64)))
+            .collect();
+        self.update_objects(&mappings, parse_object);
+        Ok(())
+    }
+
+    // Updates the mapped binaries from the (filename, start, end, file offset) of each executable
+    // mapping. Binaries that aren't mapped any more stay in the recording for the frames that are
+    // already in them, but new frames are only looked up in what's currently mapped
+    fn update_objects<F>(&mut self, mappings: &[(String, u64, u64, u64)], parse: F)
+            where F: Fn(&str, u64, u64) -> Result<(u64, Option<String>), Error> {
+        let mut mapped = Vec::new();
+        for (filename, start, end, offset) in mappings {
+            let (start, end) = (*start, *end);
+            let existing = self.mapped.iter().cloned().find(|&index| {
+                let o = &self.objects[index];
+                o.start == start && o.end == end && &o.path == filename
+            });
+            if let Some(index) = existing {
+                mapped.push(index);
+                continue;
+            }
+
+            match parse(filename, start, *offset) {
+                Ok((load_address, build_id)) => {
+                    info!("Recording mapped binary {} @ 0x{:016x}", filename, load_address);
+                    self.objects.push(MappedObject{path: filename.clone(), build_id, start, end, load_address});
+                    mapped.push(self.objects.len() - 1);
+                },
+                Err(e) => info!("Failed to parse mapped binary {}: {}", filename, e)
+            }
+        }
+        self.mapped = mapped;
+    }
+}
+
+/// Returns the load address and build-id of a binary, from the start address and file offset
+/// of one of its executable mappings
+fn parse_object(filename: &str, start: u64, offset: u64) -> Result<(u64, Option<String>), Error> {
+    with_elf(filename, |elf, buffer| {
+        // mappings start on a page boundary, so the segment can start part way into the first page
+        let program_header = elf.program_headers
+            .iter()
+            .find(|header| header.p_type == PT_LOAD && header.p_flags & PF_X != 0 &&
+                           offset >= header.p_offset & !0xfff && offset < header.p_offset + header.p_filesz)
+            .ok_or_else(|| format_err!("Failed to find executable PT_LOAD program header at offset 0x{:x} in {}", offset, filename))?;
+
+        // the segment's p_vaddr is (p_offset - offset) bytes past the start of the mapping
+        let load_address = (start + program_header.p_offset).checked_sub(offset + program_header.p_vaddr)
+            .ok_or_else(|| format_err!("Invalid executable PT_LOAD program header in {}", filename))?;
+        Ok((load_address, build_id(elf, buffer)))
+    })
+}
+
+fn build_id(elf: &Elf, buffer: &[u8]) -> Option<String> {
+    let mut build_id = None;
+    if let Some(notes) = elf.iter_note_headers(buffer) {
+        for note in notes.flatten() {
+            if note.n_type == NT_GNU_BUILD_ID {
+                build_id = Some(note.desc.iter().map(|b| format!("{:02x}", b)).collect());
+            }
+        }
+    }
+    build_id
+}
+
+// Maps a binary into memory, and calls back with it parsed as an ELF file
+fn with_elf<T, F>(filename: &str, callback: F) -> Result<T, Error>
+        where F: FnOnce(&Elf, &[u8]) -> Result<T, Error> {
+    let file = File::open(Path::new(filename))?;
+    let buffer = unsafe { Mmap::map(&file)? };
+    match Object::parse(&buffer)? {
+        Object::Elf(elf) => callback(&elf, &buffer),
+        _ => Err(format_err!("{} isn't an ELF binary", filename))
+    }
+}
+
+/// Resolves the native frames in a recording, and calls back with the symbolicated stack traces
+/// (along with the timestamp and number of times each one was seen)
+pub fn symbolize<F>(recording: &RawRecording, symbol_dirs: &[String], mut callback: F) -> Result<(), Error>
+        where F: FnMut(u64, &StackTrace, u64) -> Result<(), Error> {
+    let mut symbols: Vec<Option<SymbolData>> = recording.objects.iter().map(|_| None).collect();
+    let mut loaded: Vec<bool> = recording.objects.iter().map(|_| false).collect();
+    let mut cache: BTreeMap<(Option<usize>, u64), Vec<Frame>> = BTreeMap::new();
+    let mut cython_maps = cython::SourceMaps::new();
+
+    for sample in &recording.samples {
+        let mut frames = Vec::new();
+        for raw in &sample.frames {
+            let addr = match raw.addr {
+                Some(addr) => addr,
+                None => {
+                    frames.push(Frame{name: raw.name.clone(), filename: raw.filename.clone(),
+                                      module: raw.module.clone(), short_filename: None, line: raw.line, addr: None});
+                    continue;
+                }
+            };
+
+            // the same address can be in different binaries over time, as libraries get unloaded
+            let object = raw.object.or_else(|| recording.objects.iter().position(|o| o.contains(addr)));
+            if let Some(resolved) = cache.get(&(object, addr)) {
+                frames.extend(resolved.iter().cloned());
+                continue;
+            }
+
+            let mut resolved = Vec::new();
+            match object.filter(|&index| index < recording.objects.len()) {
+                Some(index) => {
+                    if !loaded[index] {
+                        loaded[index] = true;
+                        let object = &recording.objects[index];
+                        let path = find_binary(object, symbol_dirs);
+                        info!("Loading symbols for {} from {}", object.path, path.display());
+                        symbols[index] = match SymbolData::new(&path.to_string_lossy(), object.load_address) {
+                            Ok(data) => Some(data),
+                            Err(e) => {
+                                warn!("Failed to load symbols for {}: {}", object.path, e);
+                                None
+                            }
+                        };
+                    }
+
+                    let module = &recording.objects[index].path;
+                    match symbols[index].as_ref() {
+                        Some(data) => {
+                            data.symbolicate(addr, true, &mut |frame| {
+                                // report the module as it was named on the recording machine
+                                let mut frame = frame.clone();
+                                frame.module = module.clone();
+                                if let Some(mut frame) = translate_native_frame(&frame) {
+                                    cython_maps.translate(&mut frame);
+                                    resolved.push(frame);
+                                }
+                            })?;
+                        },
+                        None => resolved.push(unresolved_frame(addr, Some(module)))
+                    }
+                },
+                None => resolved.push(unresolved_frame(addr, None))
+            }
+
+            for frame in resolved.iter_mut() {
+                frame.short_filename = Path::new(&frame.filename).file_name()
+                    .map(|f| f.to_string_lossy().to_string());
+            }
+            frames.extend(resolved.iter().cloned());
+            cache.insert((object, addr), resolved);
+        }
+
+        let trace = StackTrace{thread_id: sample.thread_id, os_thread_id: None, active: true, owns_gil: false, interpreter_id: 0, task_name: None, wait: None, frames};
+        callback(sample.time_stamp, &trace, sample.count)?;
+    }
+    Ok(())
+}
+
+fn unresolved_frame(addr: u64, module: Option<&String>) -> Frame {
+    Frame{name: format!("0x{:x}", addr), filename: module.cloned().unwrap_or_else(|| "?".to_owned()),
+          module: module.cloned(), short_filename: None, line: 0, addr: None}
+}
+
+/// Figures out which file to load symbols for a recorded binary from. This prefers files
+/// in the debug directories, either in the standard '.build-id/xx/yyyy.debug' layout or
+/// with the same filename as the original binary
+fn find_binary(object: &MappedObject, symbol_dirs: &[String]) -> PathBuf {
+    for dir in symbol_dirs {
+        let dir = Path::new(dir);
+        if let Some(build_id) = object.build_id.as_ref() {
+            if build_id.len() > 2 {
+                let candidate = dir.join(".build-id").join(&build_id[..2]).join(format!("{}.debug", &build_id[2..]));
+                if candidate.exists() {
+                    return candidate;
+                }
+            }
+        }
+
+        if let Some(name) = Path::new(&object.path).file_name() {
+            let candidate = dir.join(name);
+            if candidate.exists() && build_id_matches(&candidate, object) {
+                return candidate;
+            }
+        }
+    }
+    PathBuf::from(&object.path)
+}
+
+fn build_id_matches(path: &Path, object: &MappedObject) -> bool {
+    match object.build_id.as_ref() {
+        Some(expected) => {
+            match with_elf(&path.to_string_lossy(), |elf, buffer| Ok(build_id(elf, buffer))) {
+                Ok(Some(build_id)) => &build_id == expected,
+                // binaries without a build-id are matched on filename alone
+                Ok(None) => true,
+                Err(_) => false
+            }
+        },
+        None => true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::elf::program_header::PT_PHDR;
+
+    fn object(path: &str, start: u64, end: u64) -> MappedObject {
+        MappedObject{path: path.to_owned(), build_id: None, start, end, load_address: start}
+    }
+
+    #[test]
+    fn test_aggregate_samples() {
+        let mut recording = RawRecording{version: RECORDING_VERSION, pid: 0,
+                                         objects: vec![object("/lib/libfoo.so", 0x1000, 0x2000)],
+                                         samples: Vec::new(), metadata: BTreeMap::new(), sample_index: HashMap::new(),
+                                         unmapped: HashSet::new(), mapped: vec![0], refreshed_at: Some(0)};
+
+        let native = Frame{name: "0x1234".to_owned(), filename: String::new(), module: None,
+                           short_filename: None, line: 0, addr: Some(0x1234)};
+        let python = Frame{name: "foo".to_owned(), filename: "foo.py".to_owned(), module: None,
+                           short_filename: Some("foo.py".to_owned()), line: 10, addr: None};
+        let trace = StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, interpreter_id: 0, task_name: None, wait: None,
+                               frames: vec![native, python]};
+
+        recording.increment(0, &trace, 1).unwrap();
+        recording.increment(0, &trace, 1).unwrap();
+        recording.increment(1, &trace, 1).unwrap();
+
+        assert_eq!(recording.samples.len(), 2);
+        assert_eq!(recording.samples[0].count, 2);
+        assert_eq!(recording.samples[1].count, 1);
+        assert_eq!(recording.samples[0].frames[0].addr, Some(0x1234));
+        assert_eq!(recording.samples[0].frames[1].addr, None);
+    }
+
+    #[test]
+    fn test_remapped_binary() {
+        let mut recording = RawRecording{version: RECORDING_VERSION, pid: 0, objects: Vec::new(), samples: Vec::new(),
+                                         metadata: BTreeMap::new(), sample_index: HashMap::new(), unmapped: HashSet::new(),
+                                         mapped: Vec::new(), refreshed_at: Some(0)};
+        let parse = |_: &str, start: u64, _: u64| Ok((start, None));
+        recording.update_objects(&[("/lib/libfoo.so".to_owned(), 0x1000, 0x2000, 0)], parse);
+
+        let native = Frame{name: "0x1234".to_owned(), filename: String::new(), module: None,
+                           short_filename: None, line: 0, addr: Some(0x1234)};
+        let trace = StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, interpreter_id: 0, task_name: None, wait: None,
+                               frames: vec![native]};
+        recording.increment(0, &trace, 1).unwrap();
+
+        // libfoo gets unloaded, and libbar loaded over the same range
+        recording.update_objects(&[("/lib/libbar.so".to_owned(), 0x1000, 0x3000, 0)], parse);
+        recording.increment(0, &trace, 1).unwrap();
+
+        assert_eq!(recording.objects.len(), 2);
+        assert_eq!(recording.samples.len(), 2);
+        assert_eq!(recording.samples[0].frames[0].object, Some(0));
+        assert_eq!(recording.samples[1].frames[0].object, Some(1));
+
+        // while it's still mapped, the same binary is reused
+        recording.update_objects(&[("/lib/libbar.so".to_owned(), 0x1000, 0x3000, 0)], parse);
+        assert_eq!(recording.objects.len(), 2);
+        assert_eq!(recording.mapped, vec![1]);
+    }
+
+    #[test]
+    fn test_restarted_process() {
+        let mut recording = RawRecording{version: RECORDING_VERSION, pid: 0,
+                                         objects: vec![object("/lib/libfoo.so", 0x1000, 0x2000)],
+                                         samples: Vec::new(), metadata: BTreeMap::new(), sample_index: HashMap::new(),
+                                         unmapped: HashSet::new(), mapped: vec![0], refreshed_at: Some(0)};
+
+        // addresses in the new process shouldn't be looked up in what the old one had mapped
+        recording.set_pid(std::process::id() as Pid);
+        let native = Frame{name: "0x1234".to_owned(), filename: String::new(), module: None,
+                           short_filename: None, line: 0, addr: Some(0x1234)};
+        let trace = StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, interpreter_id: 0, task_name: None, wait: None,
+                               frames: vec![native]};
+        recording.increment(0, &trace, 1).unwrap();
+
+        assert_eq!(recording.samples[0].frames[0].object, None);
+        assert!(recording.mapped.iter().all(|&index| index != 0));
+    }
+
+    #[test]
+    fn test_find_binary_fallback() {
+        let object = object("/lib/libdoesntexist.so", 0x1000, 0x2000);
+        assert_eq!(find_binary(&object, &["/tmp/nothing/here".to_owned()]), PathBuf::from("/lib/libdoesntexist.so"));
+    }
+
+    #[test]
+    fn test_load_address() {
+        // the kernel tells us where our own program headers were loaded, which gives the load
+        // address to check against for each of our executable mappings
+        let exe = std::env::current_exe().unwrap().to_string_lossy().into_owned();
+        const AT_PHDR: libc::c_ulong = 3;
+        let phdr = unsafe { libc::getauxval(AT_PHDR) } as u64;
+        let expected = with_elf(&exe, |elf, _| {
+            Ok(phdr - elf.program_headers.iter().find(|header| header.p_type == PT_PHDR).unwrap().p_vaddr)
+        }).unwrap();
+
+        let maps = get_process_maps(std::process::id() as Pid).unwrap();
+        let mappings: Vec<_> = maps.iter().filter(|m| m.is_exec() && m.filename().as_ref() == Some(&exe)).collect();
+        assert!(!mappings.is_empty());
+        for map in mappings {
+            assert_eq!(parse_object(&exe, map.start() as u64, map.offset as u64).unwrap().0, expected);
+        }
+    }
+}
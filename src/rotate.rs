@@ -0,0 +1,266 @@
+/* Rolling output files for 'py-spy record --rotate'.
+
+The output filename is a template, with strftime style codes for the time the interval started:
+
+    %Y year, %m month, %d day, %H hour, %M minute, %S second, %p pid, %n sequence number, %% a '%'
+
+Old files are cleaned up by --keep-files and --keep-for. Only files in the output directory
+whose names match the template are ever deleted, and with %p only the ones written for the
+processes this run has recorded (so that runs profiling other processes keep their files).
+*/
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::{Error, ResultExt};
+use regex::Regex;
+use remoteprocess::Pid;
+
+use crate::config::Config;
+
+pub struct Rotation {
+    template: String,
+    interval: Duration,
+    pid: Pid,
+    // every process this run has recorded, including the ones replaced with --follow-restarts
+    pids: Vec<Pid>,
+    sequence: u64,
+    // when the current interval started
+    started: Instant,
+    started_at: SystemTime,
+    keep_files: Option<usize>,
+    keep_for: Option<Duration>,
+}
+
+impl Rotation {
+    /// Creates a new rotation from the --rotate options, or returns None if not rotating files
+    pub fn new(config: &Config, pid: Pid) -> Result<Option<Rotation>, Error> {
+        let interval = match config.rotate {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => return Ok(None)
+        };
+        let template = config.filename.clone().ok_or_else(|| format_err!("A filename is required to record samples"))?;
+        if Path::new(&template).parent().is_some_and(|parent| parent.to_string_lossy().contains('%')) {
+            return Err(format_err!("The output directory can't contain any time codes, only the filename: {}", template));
+        }
+
+        // without any codes each file would overwrite the last one, so number them instead
+        let template = if template.contains('%') {
+            template
+        } else {
+            let path = Path::new(&template);
+            let name = match (path.file_stem(), path.extension()) {
+                (Some(stem), Some(extension)) => format!("{}-%n.{}", stem.to_string_lossy(), extension.to_string_lossy()),
+                _ => format!("{}-%n", template)
+            };
+            path.with_file_name(name).to_string_lossy().into_owned()
+        };
+
+        Ok(Some(Rotation{template, interval, pid, pids: vec![pid], sequence: 0, started: Instant::now(), started_at: SystemTime::now(),
+                         keep_files: config.keep_files, keep_for: config.keep_for.map(Duration::from_secs)}))
+    }
+
+    /// Names the files after another process, for when --follow-restarts attaches to a new one
+    pub fn set_pid(&mut self, pid: Pid) {
+        self.pid = pid;
+        self.pids.push(pid);
+    }
+
+    /// Returns whether the current interval is over
+    pub fn is_due(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= self.interval
+    }
+
+    /// Returns the filename for the current interval, and starts the next one
+    pub fn rotate(&mut self, now: Instant) -> Result<String, Error> {
+        let filename = expand_template(&self.template, &local_time(self.started_at)?, self.pid, self.sequence);
+        self.sequence += 1;
+        self.started = now;
+        self.started_at = SystemTime::now();
+        Ok(filename)
+    }
+
+    /// Deletes files written by earlier intervals that are past the retention limits, returning
+    /// the files that were deleted
+    pub fn remove_old_files(&self) -> Result<Vec<PathBuf>, Error> {
+        if self.keep_files.is_none() && self.keep_for.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let path = Path::new(&self.template);
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from(".")
+        };
+        let pattern = template_regex(&path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(), &self.pids)?;
+
+        // the files from previous intervals, newest first
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&directory).context(format!("Failed to read directory {}", directory.display()))? {
+            let entry = entry?;
+            if !pattern.is_match(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.push((metadata.modified()?, entry.path()));
+            }
+        }
+        files.sort_by(|a, b| b.cmp(a));
+
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        for (i, (modified, path)) in files.into_iter().enumerate() {
+            let too_many = self.keep_files.is_some_and(|keep| i >= keep);
+            let too_old = match (self.keep_for, now.duration_since(modified)) {
+                (Some(keep_for), Ok(age)) => age > keep_for,
+                _ => false
+            };
+            if too_many || too_old {
+                fs::remove_file(&path).context(format!("Failed to remove {}", path.display()))?;
+                removed.push(path);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Fills in the time codes, pid and sequence number in a filename template
+fn expand_template(template: &str, time: &libc::tm, pid: Pid, sequence: u64) -> String {
+    let mut filename = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            filename.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => filename.push_str(&format!("{:04}", time.tm_year + 1900)),
+            Some('m') => filename.push_str(&format!("{:02}", time.tm_mon + 1)),
+            Some('d') => filename.push_str(&format!("{:02}", time.tm_mday)),
+            Some('H') => filename.push_str(&format!("{:02}", time.tm_hour)),
+            Some('M') => filename.push_str(&format!("{:02}", time.tm_min)),
+            Some('S') => filename.push_str(&format!("{:02}", time.tm_sec)),
+            Some('p') => filename.push_str(&pid.to_string()),
+            Some('n') => filename.push_str(&sequence.to_string()),
+            Some('%') => filename.push('%'),
+            Some(other) => { filename.push('%'); filename.push(other); },
+            None => filename.push('%')
+        }
+    }
+    filename
+}
+
+// Returns a regex matching the filenames that a template can expand to for any of the pids
+fn template_regex(template: &str, pids: &[Pid]) -> Result<Regex, Error> {
+    let pids = pids.iter().map(|pid| pid.to_string()).collect::<Vec<_>>().join("|");
+    let mut pattern = String::from("^");
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '%' => match chars.next() {
+                Some('Y') => { pattern.push_str(r"\d{4}"); continue; },
+                Some('m') | Some('d') | Some('H') | Some('M') | Some('S') => { pattern.push_str(r"\d{2}"); continue; },
+                Some('p') => { pattern.push_str(&format!("(?:{})", pids)); continue; },
+                Some('n') => { pattern.push_str(r"\d+"); continue; },
+                Some('%') => "%".to_owned(),
+                Some(other) => format!("%{}", other),
+                None => "%".to_owned()
+            },
+            c => c.to_string()
+        };
+        pattern.push_str(&regex::escape(&literal));
+    }
+    pattern.push('$');
+    Ok(Regex::new(&pattern)?)
+}
+
+#[cfg(unix)]
+fn local_time(time: SystemTime) -> Result<libc::tm, Error> {
+    let seconds = time.duration_since(UNIX_EPOCH)?.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        return Err(format_err!("Failed to get the local time"));
+    }
+    Ok(tm)
+}
+
+#[cfg(not(unix))]
+fn local_time(_time: SystemTime) -> Result<libc::tm, Error> {
+    Err(format_err!("Rotating output files is only supported on unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time() -> libc::tm {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = 119;
+        tm.tm_mon = 8;
+        tm.tm_mday = 3;
+        tm.tm_hour = 14;
+        tm.tm_min = 5;
+        tm.tm_sec = 9;
+        tm
+    }
+
+    #[test]
+    fn test_expand_template() {
+        assert_eq!(expand_template("profile-%Y%m%dT%H%M%S-%p.json", &time(), 1234, 0), "profile-20190903T140509-1234.json");
+        assert_eq!(expand_template("/tmp/profile-%n.svg", &time(), 1234, 12), "/tmp/profile-12.svg");
+        assert_eq!(expand_template("100%%-%x%", &time(), 1234, 0), "100%-%x%");
+    }
+
+    #[test]
+    fn test_template_regex() {
+        let regex = template_regex("profile-%Y%m%dT%H%M%S-%p.json", &[1234, 5678]).unwrap();
+        assert!(regex.is_match("profile-20190903T140509-1234.json"));
+        assert!(regex.is_match("profile-20190903T140509-5678.json"));
+        // files from other processes are left for whoever is recording them
+        assert!(!regex.is_match("profile-20190903T140509-4321.json"));
+        assert!(!regex.is_match("profile-20190903T140509-12345.json"));
+        assert!(!regex.is_match("profile-20190903T140509-1234.json.bak"));
+        assert!(!regex.is_match("profile-2019-1234.json"));
+        assert!(!regex.is_match("profileX20190903T140509-1234.json"));
+    }
+
+    #[test]
+    fn test_numbered_template() {
+        let config = Config{filename: Some("/tmp/profile.svg".to_owned()), rotate: Some(60), ..Default::default()};
+        let mut rotation = Rotation::new(&config, 1234).unwrap().unwrap();
+        assert_eq!(rotation.rotate(Instant::now()).unwrap(), "/tmp/profile-0.svg");
+        assert_eq!(rotation.rotate(Instant::now()).unwrap(), "/tmp/profile-1.svg");
+
+        let config = Config{filename: Some("/tmp/%Y/profile.svg".to_owned()), rotate: Some(60), ..Default::default()};
+        assert!(Rotation::new(&config, 1234).is_err());
+    }
+
+    #[test]
+    fn test_remove_old_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let template = directory.path().join("profile-%n.svg").to_string_lossy().into_owned();
+        let config = Config{filename: Some(template), rotate: Some(60), keep_files: Some(2), ..Default::default()};
+        let mut rotation = Rotation::new(&config, 1234).unwrap().unwrap();
+
+        let mut filenames = Vec::new();
+        for i in 0..4 {
+            let filename = rotation.rotate(Instant::now()).unwrap();
+            fs::write(&filename, "").unwrap();
+            // make sure the modification times are distinct
+            let modified = SystemTime::now() - Duration::from_secs(100 - i);
+            let file = fs::OpenOptions::new().write(true).open(&filename).unwrap();
+            file.set_modified(modified).unwrap();
+            filenames.push(filename);
+        }
+        // files that don't match the template are left alone
+        let other = directory.path().join("notes.txt");
+        fs::write(&other, "").unwrap();
+
+        let mut removed = rotation.remove_old_files().unwrap();
+        removed.sort();
+        assert_eq!(removed, vec![PathBuf::from(&filenames[0]), PathBuf::from(&filenames[1])]);
+        assert!(Path::new(&filenames[3]).exists());
+        assert!(other.exists());
+    }
+}
@@ -0,0 +1,236 @@
+/* Classifies why taking a sample failed.
+
+Errors that we detect ourselves are raised as a SampleError with the kind of problem it is. Other
+errors come from reading the process or unwinding its native stacks, and get classified by the
+remoteprocess error at the root of them. This lets 'record' show whether a high error rate
+is from torn reads under --nonblocking, native stacks not lining up with the python ones, or
+permissions.
+*/
+use std::collections::BTreeMap;
+use std::fmt;
+
+use failure::{Error, Fail};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum SampleErrorKind {
+    /// We don't have permission to read the process
+    Permission,
+    /// Suspending the process failed
+    Suspend,
+    /// Reading memory from the process failed, usually because an object was freed while we
+    /// were following a pointer to it
+    MemoryRead,
+    /// The memory we read doesn't make sense, usually because it changed while we were reading it
+    InvalidData,
+    /// The stack of a thread changed while we were reading it, with --nonblocking
+    Torn,
+    /// Unwinding the native stack of a thread failed
+    NativeUnwind,
+    /// The native stack of a thread didn't line up with its python stack
+    NativeMerge,
+    Other,
+}
+
+impl SampleErrorKind {
+    /// Finds out what kind of error caused a sample to fail
+    pub fn of(err: &Error) -> SampleErrorKind {
+        for cause in err.iter_chain() {
+            if let Some(err) = cause.downcast_ref::<SampleError>() {
+                return err.kind;
+            }
+            if let Some(err) = cause.downcast_ref::<remoteprocess::Error>() {
+                return SampleErrorKind::of_process_error(err);
+            }
+            if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                if err.kind() == std::io::ErrorKind::PermissionDenied {
+                    return SampleErrorKind::Permission;
+                }
+            }
+        }
+        SampleErrorKind::Other
+    }
+
+    fn of_process_error(err: &remoteprocess::Error) -> SampleErrorKind {
+        match err {
+            remoteprocess::Error::IOError(err) if err.kind() == std::io::ErrorKind::PermissionDenied => SampleErrorKind::Permission,
+            remoteprocess::Error::IOError(_) => SampleErrorKind::MemoryRead,
+            #[cfg(target_os="linux")]
+            remoteprocess::Error::NixError(err) => {
+                match err.as_errno().map(|errno| errno as i32) {
+                    Some(libc::EPERM) | Some(libc::EACCES) => SampleErrorKind::Permission,
+                    _ => SampleErrorKind::Other
+                }
+            },
+            remoteprocess::Error::Other(_) => SampleErrorKind::Other,
+            // everything else comes from the native unwinder
+            _ => SampleErrorKind::NativeUnwind
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            SampleErrorKind::Permission => "permission denied",
+            SampleErrorKind::Suspend => "failed to suspend process",
+            SampleErrorKind::MemoryRead => "failed to read memory",
+            SampleErrorKind::InvalidData => "invalid data (torn read)",
+            SampleErrorKind::Torn => "stack changed while reading it",
+            SampleErrorKind::NativeUnwind => "failed to unwind native stack",
+            SampleErrorKind::NativeMerge => "failed to merge native and python frames",
+            SampleErrorKind::Other => "other"
+        }
+    }
+}
+
+impl fmt::Display for SampleErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/// An error taking a sample that we've detected ourselves
+#[derive(Debug)]
+pub struct SampleError {
+    pub kind: SampleErrorKind,
+    message: String,
+    // the error this was wrapped around, if any
+    cause: Option<Error>,
+}
+
+impl SampleError {
+    pub fn new(kind: SampleErrorKind, message: String) -> SampleError {
+        SampleError{kind, message, cause: None}
+    }
+
+    /// Wraps another error as this kind of error, unless it's a permission problem (which is
+    /// always reported as one, regardless of what we were doing). The wrapped error is kept
+    /// as the cause
+    pub fn wrap(kind: SampleErrorKind, context: &str, err: Error) -> SampleError {
+        let kind = match SampleErrorKind::of(&err) {
+            SampleErrorKind::Permission => SampleErrorKind::Permission,
+            _ => kind
+        };
+        SampleError{kind, message: context.to_owned(), cause: Some(err)}
+    }
+}
+
+impl Fail for SampleError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.cause.as_ref().map(|err| err.as_fail())
+    }
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Counts the errors of each kind, keeping the most recent message for each
+pub struct ErrorBreakdown {
+    errors: BTreeMap<SampleErrorKind, (u64, String)>,
+}
+
+impl ErrorBreakdown {
+    pub fn new() -> ErrorBreakdown {
+        ErrorBreakdown{errors: BTreeMap::new()}
+    }
+
+    pub fn add(&mut self, err: &Error) -> SampleErrorKind {
+        let kind = SampleErrorKind::of(err);
+        let entry = self.errors.entry(kind).or_insert((0, String::new()));
+        entry.0 += 1;
+        entry.1 = err.iter_chain().map(|cause| cause.to_string()).collect::<Vec<_>>().join(": ");
+        kind
+    }
+
+    /// The number of errors of a kind
+    pub fn count(&self, kind: SampleErrorKind) -> u64 {
+        self.errors.get(&kind).map_or(0, |(count, _)| *count)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.errors.values().map(|(count, _)| count).sum()
+    }
+
+    /// A table of the number of each kind of error, most common first
+    pub fn report(&self, samples: u64) -> Vec<String> {
+        let total = self.total();
+        if total == 0 {
+            return Vec::new();
+        }
+        let mut errors: Vec<_> = self.errors.iter().collect();
+        errors.sort_by(|(a_kind, (a, _)), (b_kind, (b, _))| (b, a_kind).cmp(&(a, b_kind)));
+
+        let mut lines = vec![format!("{} samples failed ({:.2}% of attempts):", total,
+                                     100.0 * total as f64 / (total + samples) as f64)];
+        for (kind, (count, message)) in errors {
+            lines.push(format!("  {:>8}  {:<42} last: {}", count, kind.description(), message));
+        }
+        lines
+    }
+}
+
+impl Default for ErrorBreakdown {
+    fn default() -> ErrorBreakdown {
+        ErrorBreakdown::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::ResultExt;
+
+    fn copy_frame(err: remoteprocess::Error) -> Result<(), Error> {
+        Err(err).context("Failed to copy PyFrameObject")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        let torn = Error::from(SampleError::new(SampleErrorKind::InvalidData, "Refusing to copy 4294967295 chars of a string".to_owned()));
+        assert_eq!(SampleErrorKind::of(&torn), SampleErrorKind::InvalidData);
+        assert_eq!(torn.to_string(), "Refusing to copy 4294967295 chars of a string");
+
+        // errors with context added get classified by what caused them
+        let fault = std::io::Error::from_raw_os_error(libc::EFAULT);
+        let err = copy_frame(remoteprocess::Error::IOError(fault)).unwrap_err();
+        assert_eq!(SampleErrorKind::of(&err), SampleErrorKind::MemoryRead);
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let err = copy_frame(remoteprocess::Error::IOError(denied)).unwrap_err();
+        assert_eq!(SampleErrorKind::of(&err), SampleErrorKind::Permission);
+
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let err = SampleError::wrap(SampleErrorKind::Suspend, "Failed to suspend process", remoteprocess::Error::IOError(denied).into());
+        assert_eq!(err.kind, SampleErrorKind::Permission);
+
+        // wrapped errors keep what caused them
+        let fault = std::io::Error::from_raw_os_error(libc::EFAULT);
+        let err = Error::from(SampleError::wrap(SampleErrorKind::NativeUnwind, "Failed to unwind native stack",
+                                                copy_frame(remoteprocess::Error::IOError(fault)).unwrap_err()));
+        let causes: Vec<String> = err.iter_chain().map(|cause| cause.to_string()).collect();
+        assert_eq!(causes.len(), 3);
+        assert_eq!(causes[..2], ["Failed to unwind native stack", "Failed to copy PyFrameObject"]);
+        assert_eq!(SampleErrorKind::of(&err), SampleErrorKind::NativeUnwind);
+
+        assert_eq!(SampleErrorKind::of(&format_err!("something else")), SampleErrorKind::Other);
+    }
+
+    #[test]
+    fn test_error_breakdown() {
+        let mut breakdown = ErrorBreakdown::new();
+        for i in 0..3 {
+            breakdown.add(&SampleError::new(SampleErrorKind::InvalidData, format!("torn {}", i)).into());
+        }
+        breakdown.add(&SampleError::new(SampleErrorKind::NativeMerge, "merge".to_owned()).into());
+        assert_eq!(breakdown.total(), 4);
+        assert_eq!(breakdown.count(SampleErrorKind::InvalidData), 3);
+        assert_eq!(breakdown.count(SampleErrorKind::Torn), 0);
+
+        let report = breakdown.report(96);
+        assert_eq!(report.len(), 3);
+        assert_eq!(report[0], "4 samples failed (4.00% of attempts):");
+        assert!(report[1].contains("invalid data (torn read)") && report[1].ends_with("last: torn 2"));
+        assert!(report[2].contains("failed to merge native and python frames"));
+    }
+}
@@ -0,0 +1,274 @@
+/* Measures what sampling costs the process being profiled: how long it was paused for each
+sample, how much of that was spent unwinding native stacks, and how much of its memory we read.
+
+Percentiles come from a histogram with buckets that are at most 12.5% wide, so that recording
+for days doesn't need to keep every sample around.
+*/
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use remoteprocess::ProcessMemory;
+
+/// The cost of taking a single sample
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct SampleCost {
+    /// How long the process was suspended for (or spent reading its memory, with --nonblocking)
+    pub pause: Duration,
+    /// How much of the pause was spent unwinding and symbolicating native stacks
+    pub unwind: Duration,
+    /// The number of reads of the process's memory. This doesn't include the reads made by the
+    /// native unwinder
+    pub reads: u64,
+    pub bytes: u64,
+}
+
+/// Counts the reads made through a CountingMemory
+#[derive(Debug, Default)]
+pub struct MemoryReads {
+    reads: Cell<u64>,
+    bytes: Cell<u64>,
+}
+
+impl MemoryReads {
+    /// Returns the number of reads and bytes read since the last call
+    pub fn take(&self) -> (u64, u64) {
+        (self.reads.replace(0), self.bytes.replace(0))
+    }
+}
+
+/// Wraps another process's memory, counting each read
+pub struct CountingMemory<'a, P: ProcessMemory> {
+    process: &'a P,
+    counts: &'a MemoryReads,
+}
+
+impl<'a, P: ProcessMemory> CountingMemory<'a, P> {
+    pub fn new(process: &'a P, counts: &'a MemoryReads) -> CountingMemory<'a, P> {
+        CountingMemory{process, counts}
+    }
+}
+
+impl<'a, P: ProcessMemory> ProcessMemory for CountingMemory<'a, P> {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        self.counts.reads.set(self.counts.reads.get() + 1);
+        self.counts.bytes.set(self.counts.bytes.get() + buf.len() as u64);
+        self.process.read(addr, buf)
+    }
+
+    // a vectored read is a single system call, so it only counts as one read
+    fn read_vectored(&self, reads: &mut [(usize, &mut [u8])]) -> Result<(), remoteprocess::Error> {
+        self.counts.reads.set(self.counts.reads.get() + 1);
+        self.counts.bytes.set(self.counts.bytes.get() + reads.iter().map(|(_, buf)| buf.len() as u64).sum::<u64>());
+        self.process.read_vectored(reads)
+    }
+
+    fn prefetch(&self, ranges: &[(usize, usize)]) {
+        self.process.prefetch(ranges)
+    }
+}
+
+/// Aggregates the cost of every sample taken
+pub struct SamplingStats {
+    started: Instant,
+    samples: u64,
+    pause: Histogram,
+    unwind: Histogram,
+    total_pause: Duration,
+    reads: u64,
+    bytes: u64,
+}
+
+impl SamplingStats {
+    pub fn new() -> SamplingStats {
+        SamplingStats{started: Instant::now(), samples: 0, pause: Histogram::new(), unwind: Histogram::new(),
+                      total_pause: Duration::from_secs(0), reads: 0, bytes: 0}
+    }
+
+    pub fn add(&mut self, cost: &SampleCost) {
+        self.samples += 1;
+        self.pause.add(cost.pause);
+        if cost.unwind > Duration::from_secs(0) {
+            self.unwind.add(cost.unwind);
+        }
+        self.total_pause += cost.pause;
+        self.reads += cost.reads;
+        self.bytes += cost.bytes;
+    }
+
+    /// The percentage of the time since we started that the process was paused for
+    pub fn overhead(&self) -> f64 {
+        100.0 * seconds(self.total_pause) / seconds(self.started.elapsed()).max(1e-9)
+    }
+
+    /// A one line description of the overhead, for the 'top' header
+    pub fn summary(&self) -> String {
+        if self.samples == 0 {
+            return "no samples yet".to_owned();
+        }
+        format!("{:.2}% paused, pause p50 {:.2?} p99 {:.2?}, {} reads/sample",
+                self.overhead(), self.pause.percentile(50.0), self.pause.percentile(99.0),
+                self.reads / self.samples)
+    }
+
+    /// A table of the overhead, for the end of 'record'
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![format!("Sampling paused the process for {:.2}% of the time ({} samples)",
+                                     self.overhead(), self.samples)];
+        if self.samples == 0 {
+            return lines;
+        }
+        lines.push(format!("  pause time   {}", self.pause.describe()));
+        if self.unwind.count > 0 {
+            lines.push(format!("  unwind time  {}", self.unwind.describe()));
+        }
+        lines.push(format!("  memory reads {} reads ({} bytes) per sample",
+                           self.reads / self.samples, self.bytes / self.samples));
+        lines
+    }
+
+    /// The overhead as key/value pairs, for the metadata in output files. Durations are in
+    /// microseconds
+    pub fn metadata(&self) -> BTreeMap<String, String> {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("samples".to_owned(), self.samples.to_string());
+        metadata.insert("overhead_percent".to_owned(), format!("{:.3}", self.overhead()));
+        if self.samples == 0 {
+            return metadata;
+        }
+        for (name, histogram) in &[("pause", &self.pause), ("unwind", &self.unwind)] {
+            if histogram.count == 0 {
+                continue;
+            }
+            for &percentile in &[50.0, 90.0, 99.0] {
+                metadata.insert(format!("{}_p{}_us", name, percentile), micros(histogram.percentile(percentile)).to_string());
+            }
+            metadata.insert(format!("{}_max_us", name), micros(histogram.max).to_string());
+        }
+        metadata.insert("reads_per_sample".to_owned(), (self.reads / self.samples).to_string());
+        metadata.insert("bytes_per_sample".to_owned(), (self.bytes / self.samples).to_string());
+        metadata
+    }
+}
+
+impl Default for SamplingStats {
+    fn default() -> SamplingStats {
+        SamplingStats::new()
+    }
+}
+
+// A histogram of durations in microseconds. Values under 16us get their own bucket, and each
+// power of two above that is split into 8 buckets
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: Duration,
+}
+
+const SUB_BUCKETS: u64 = 8;
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram{buckets: Vec::new(), count: 0, max: Duration::from_secs(0)}
+    }
+
+    fn add(&mut self, value: Duration) {
+        let index = bucket_index(micros(value));
+        if index >= self.buckets.len() {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+
+    // Returns the upper bound of the bucket containing the percentile, or the max if that's lower
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_upper_bound(index)).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn describe(&self) -> String {
+        format!("p50 {:.2?}  p90 {:.2?}  p99 {:.2?}  max {:.2?}", self.percentile(50.0), self.percentile(90.0),
+                self.percentile(99.0), self.max)
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < 2 * SUB_BUCKETS {
+        return value as usize;
+    }
+    // the position of the highest bit set, which is at least 4 here
+    let exponent = 63 - value.leading_zeros() as u64;
+    let sub_bucket = (value >> (exponent - 3)) & (SUB_BUCKETS - 1);
+    (2 * SUB_BUCKETS + (exponent - 4) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2 * SUB_BUCKETS {
+        return index;
+    }
+    let exponent = (index - 2 * SUB_BUCKETS) / SUB_BUCKETS + 4;
+    let sub_bucket = (index - 2 * SUB_BUCKETS) % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub_bucket + 1) << (exponent - 3)) - 1
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+
+    #[test]
+    fn test_histogram_buckets() {
+        for value in (0..100_000).chain(vec![1 << 40, u64::MAX >> 2]) {
+            let index = bucket_index(value);
+            assert!(value <= bucket_upper_bound(index), "value {} above bucket {}", value, index);
+            assert!(index == 0 || value > bucket_upper_bound(index - 1), "value {} in the wrong bucket {}", value, index);
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut stats = SamplingStats::new();
+        for i in 1..=100 {
+            stats.add(&SampleCost{pause: Duration::from_micros(i * 10), unwind: Duration::from_secs(0), reads: 20, bytes: 1000});
+        }
+        assert_eq!(stats.pause.percentile(50.0), Duration::from_micros(511));
+        assert_eq!(stats.pause.percentile(99.0), Duration::from_micros(1000));
+        assert_eq!(stats.pause.percentile(100.0), Duration::from_micros(1000));
+
+        let metadata = stats.metadata();
+        assert_eq!(metadata["samples"], "100");
+        assert_eq!(metadata["pause_max_us"], "1000");
+        assert_eq!(metadata["reads_per_sample"], "20");
+        assert!(!metadata.contains_key("unwind_p50_us"));
+    }
+
+    #[test]
+    fn test_counting_memory() {
+        let value: [u64; 4] = [1, 2, 3, 4];
+        let counts = MemoryReads::default();
+        let memory = CountingMemory::new(&LocalProcess, &counts);
+        let copied: [u64; 4] = memory.copy_struct(value.as_ptr() as usize).unwrap();
+        assert_eq!(copied, value);
+        memory.copy(value.as_ptr() as usize, 3).unwrap();
+        assert_eq!(counts.take(), (2, 35));
+        assert_eq!(counts.take(), (0, 0));
+    }
+}
@@ -26,7 +26,7 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, HashMap};
 use std::io;
 use std::io::Write;
 use std::fs::File;
@@ -68,6 +68,10 @@ struct SpeedscopeFile {
     exporter: Option<String>,
 
     name: Option<String>,
+
+    // not part of the speedscope format, but ignored by it: what sampling cost the process
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    metadata: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -126,7 +130,8 @@ enum ValueUnit {
 }
 
 impl SpeedscopeFile {
-  pub fn new(samples: &HashMap<Tid, Vec<Vec<usize>>>, frames: &Vec<Frame>) -> SpeedscopeFile {
+  pub fn new(samples: &HashMap<Tid, Vec<Vec<usize>>>, weights: &HashMap<Tid, Vec<f64>>, frames: &Vec<Frame>,
+             metadata: &BTreeMap<String, String>) -> SpeedscopeFile {
     let end_value = samples.len();
 
     SpeedscopeFile {
@@ -139,8 +144,8 @@ impl SpeedscopeFile {
 
       exporter: Some(format!("py-spy@{}", env!("CARGO_PKG_VERSION"))),
 
-      profiles: samples.iter().map(|(_, samples)| {
-        let weights: Vec<f64> = (&samples).iter().map(|_s| 1_f64).collect();
+      profiles: samples.iter().map(|(tid, samples)| {
+        let weights = weights[tid].clone();
 
         Profile {
             profile_type: ProfileType::Sampled,
@@ -155,7 +160,9 @@ impl SpeedscopeFile {
 
       shared: Shared {
           frames: frames.clone()
-      }
+      },
+
+      metadata: metadata.clone()
     }
   }
 }
@@ -174,7 +181,9 @@ impl Frame {
 
 pub struct Stats {
     samples: HashMap<Tid, Vec<Vec<usize>>>,
+    weights: HashMap<Tid, Vec<f64>>,
     frames: Vec<Frame>,
+    pub metadata: BTreeMap<String, String>,
     frame_to_index: HashMap<stack_trace::Frame, usize>
 }
 
@@ -182,12 +191,14 @@ impl Stats {
     pub fn new() -> Stats {
         Stats {
             samples: HashMap::new(),
+            weights: HashMap::new(),
             frames: vec![],
+            metadata: BTreeMap::new(),
             frame_to_index: HashMap::new()
         }
     }
 
-    pub fn record(&mut self, stack: &stack_trace::StackTrace) -> Result<(), io::Error> {
+    pub fn record(&mut self, stack: &stack_trace::StackTrace, count: u64) -> Result<(), io::Error> {
         let mut frame_indices: Vec<usize> = stack.frames.iter().map(|frame| {
             let frames = &mut self.frames;
             *self.frame_to_index.entry(frame.clone()).or_insert_with(|| {
@@ -201,11 +212,14 @@ impl Stats {
         self.samples.entry(stack.thread_id as Tid).or_insert_with(|| {
             vec![]
         }).push(frame_indices);
+        self.weights.entry(stack.thread_id as Tid).or_insert_with(|| {
+            vec![]
+        }).push(count as f64);
         Ok(())
     }
 
     pub fn write(&self, w: &mut File) -> Result<(), Error> {
-        let json = serde_json::to_string(&SpeedscopeFile::new(&self.samples, &self.frames))?;
+        let json = serde_json::to_string(&SpeedscopeFile::new(&self.samples, &self.weights, &self.frames, &self.metadata))?;
         writeln!(w, "{}", json)?;
         Ok(())
     }
@@ -1,7 +1,11 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+
 use failure::{Error, ResultExt};
 use remoteprocess::ProcessMemory;
 
 use crate::python_interpreters::{InterpreterState, ThreadState, FrameObject, CodeObject, StringObject, BytesObject};
+use crate::sample_error::{SampleError, SampleErrorKind};
 
 /// Call stack for a single python thread
 #[derive(Debug, Clone)]
@@ -14,10 +18,24 @@ pub struct StackTrace {
     pub active: bool,
     /// Whether or not the thread held the GIL
     pub owns_gil: bool,
+    /// The id of the interpreter the thread belongs to (0 for the main interpreter)
+    pub interpreter_id: i64,
+    /// The name of the asyncio task, for pseudo-threads showing the coroutines of a task
+    pub task_name: Option<String>,
+    /// What the thread was waiting on in the kernel, for threads that were idle
+    pub wait: Option<ThreadWait>,
     /// The frames
     pub frames: Vec<Frame>
 }
 
+/// The system call (or kernel function) an idle thread was sleeping in. This has to be read
+/// before the process is suspended, since that interrupts the system call
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ThreadWait {
+    Syscall{number: i64, args: [u64; 6]},
+    Kernel(String)
+}
+
 /// Information about a single function call in a stack trace
 #[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
 pub struct Frame {
@@ -31,6 +49,8 @@ pub struct Frame {
     pub short_filename: Option<String>,
     /// The line number inside the file (or 0 for native frames without line information)
     pub line: i32,
+    /// The instruction pointer for native frames whose symbolication has been deferred
+    pub addr: Option<u64>,
 }
 
 /// Given an InterpreterState, this function returns a vector of stack traces for each thread
@@ -38,13 +58,14 @@ pub fn get_stack_traces<I, P>(interpreter: &I, process: &P) -> Result<(Vec<Stack
         where I: InterpreterState, P: ProcessMemory {
     // TODO: deprecate this method
     let mut ret = Vec::new();
+    let mut code_cache = CodeCache::new();
     let mut threads = interpreter.head();
     while !threads.is_null() {
         let thread = process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
-        ret.push(get_stack_trace(&thread, process)?);
+        ret.push(get_stack_trace(&thread, process, &mut code_cache)?);
         // This seems to happen occasionally when scanning BSS addresses for valid interpeters
         if ret.len() > 4096 {
-            return Err(format_err!("Max thread recursion depth reached"));
+            return Err(SampleError::new(SampleErrorKind::InvalidData, "Max thread recursion depth reached".to_owned()).into());
         }
         threads = thread.next();
     }
@@ -52,28 +73,194 @@ pub fn get_stack_traces<I, P>(interpreter: &I, process: &P) -> Result<(Vec<Stack
 }
 
 /// Gets a stack trace for an individual thread
-pub fn get_stack_trace<T, P>(thread: &T, process: &P) -> Result<StackTrace, Error>
+pub fn get_stack_trace<T, P>(thread: &T, process: &P, code_cache: &mut CodeCache) -> Result<StackTrace, Error>
         where T: ThreadState, P: ProcessMemory {
     // TODO: just return frames here? everything else probably should be returned out of scopee
-    let mut frames = Vec::new();
+    let frames = get_frames(thread, process, code_cache, false)?;
+    Ok(StackTrace{frames, thread_id: thread.thread_id(), owns_gil: false, active: true, os_thread_id: None,
+                  interpreter_id: 0, task_name: None, wait: None})
+}
+
+/// Gets a stack trace for a thread that is still running (with --nonblocking), checking that
+/// its frames didn't change while we were reading them. Otherwise the thread can push or pop
+/// frames part way through, and we'd return a stack that never existed
+pub fn get_checked_stack_trace<T, P>(thread_ptr: *const T, process: &P, code_cache: &mut CodeCache) -> Result<StackTrace, Error>
+        where T: ThreadState, P: ProcessMemory {
+    let thread = process.copy_pointer(thread_ptr).context("Failed to copy PyThreadState")?;
+    let top = thread.frame();
+    let before = get_frame_links(top, process)?;
+
+    let frames = get_frames(&thread, process, code_cache, true)?;
+
+    // frame objects get reused, so the top frame pointer being the same isn't enough: it also
+    // needs to be running the same code, called from the same place
+    let current = process.copy_pointer(thread_ptr).context("Failed to copy PyThreadState")?;
+    if current.frame() != top || get_frame_links(top, process)? != before {
+        return Err(SampleError::new(SampleErrorKind::Torn, "Stack changed while reading it".to_owned()).into());
+    }
+
+    Ok(StackTrace{frames, thread_id: thread.thread_id(), owns_gil: false, active: true, os_thread_id: None,
+                  interpreter_id: 0, task_name: None, wait: None})
+}
+
+// Walks the f_back chain of a thread. When checking, frame chains that can't be right are
+// rejected: ones that loop, or have frames without code or with impossible line numbers
+fn get_frames<T, P>(thread: &T, process: &P, code_cache: &mut CodeCache, check: bool) -> Result<Vec<Frame>, Error>
+        where T: ThreadState, P: ProcessMemory {
+    let mut frame_objects = Vec::new();
+    let mut seen = HashSet::new();
     let mut frame_ptr = thread.frame();
     while !frame_ptr.is_null() {
+        if check && !seen.insert(frame_ptr as usize) {
+            return Err(SampleError::new(SampleErrorKind::Torn, "Frame chain loops back on itself".to_owned()).into());
+        }
         let frame = process.copy_pointer(frame_ptr).context("Failed to copy PyFrameObject")?;
-        let code = process.copy_pointer(frame.code()).context("Failed to copy PyCodeObject")?;
+        if check && frame.code().is_null() {
+            return Err(SampleError::new(SampleErrorKind::Torn, "Frame has no code object".to_owned()).into());
+        }
+        frame_ptr = frame.back();
+        frame_objects.push(frame);
+        if frame_objects.len() > 4096 {
+            return Err(SampleError::new(SampleErrorKind::InvalidData, "Max frame recursion depth reached".to_owned()).into());
+        }
+    }
 
-        let filename = copy_string(code.filename(), process).context("Failed to copy filename")?;
-        let name = copy_string(code.name(), process).context("Failed to copy function name")?;
-        let line = get_line_number(&code, frame.lasti(), process).context("Failed to get line number")?;
+    // the page cache that makes prefetching worthwhile is only used while the process is paused,
+    // which is when we don't need to check the frames
+    if !check {
+        prefetch_code(&frame_objects, process, code_cache);
+    }
 
-        frames.push(Frame{name, filename, line, short_filename: None, module: None});
-        if frames.len() > 4096 {
-            return Err(format_err!("Max frame recursion depth reached"));
+    let mut frames = Vec::with_capacity(frame_objects.len());
+    for frame in &frame_objects {
+        let value = get_frame(frame, process, code_cache)?;
+        if check && (value.line < 0 || value.line > MAX_LINE_NUMBER) {
+            return Err(SampleError::new(SampleErrorKind::Torn, format!("Invalid line number {} in {}", value.line, value.name)).into());
         }
+        frames.push(value);
+    }
+    Ok(frames)
+}
 
-        frame_ptr = frame.back();
+// Reads the code objects of all the frames in a stack with a single read, and then the names,
+// filenames and line number tables of the ones that aren't cached yet with another. Otherwise
+// each of these objects can need its own read, since they're scattered around the heap
+fn prefetch_code<F, P>(frames: &[F], process: &P, code_cache: &CodeCache)
+        where F: FrameObject, P: ProcessMemory {
+    let code_size = size_of::<F::CodeObject>();
+    let code: Vec<(usize, usize)> = frames.iter().map(|frame| (frame.code() as usize, code_size)).collect();
+    process.prefetch(&code);
+
+    let string_size = size_of::<<F::CodeObject as CodeObject>::StringObject>();
+    let bytes_size = size_of::<<F::CodeObject as CodeObject>::BytesObject>();
+    let mut objects = Vec::new();
+    for frame in frames {
+        let code: F::CodeObject = match process.copy_pointer(frame.code()) {
+            Ok(code) => code,
+            Err(_) => continue
+        };
+        if code_cache.code.get(&(frame.code() as usize)).is_some_and(|info| info.is_code(&code)) {
+            continue;
+        }
+        objects.push((code.name() as usize, string_size));
+        objects.push((code.filename() as usize, string_size));
+        objects.push((code.lnotab() as usize, bytes_size));
+    }
+    if !objects.is_empty() {
+        process.prefetch(&objects);
+    }
+}
+
+// no real source file is this long, so a line past this means we read a frame being torn down
+const MAX_LINE_NUMBER: i32 = 1 << 24;
+
+// The code object and caller of a frame, which identify it when the frame object gets reused
+fn get_frame_links<F, P>(frame_ptr: *const F, process: &P) -> Result<Option<(usize, usize)>, Error>
+        where F: FrameObject, P: ProcessMemory {
+    if frame_ptr.is_null() {
+        return Ok(None);
+    }
+    let frame = process.copy_pointer(frame_ptr).context("Failed to copy PyFrameObject")?;
+    Ok(Some((frame.code() as usize, frame.back() as usize)))
+}
+
+/// Gets the function name/filename/line for a single python frame
+pub fn get_frame<F, P>(frame: &F, process: &P, code_cache: &mut CodeCache) -> Result<Frame, Error>
+        where F: FrameObject, P: ProcessMemory {
+    let addr = frame.code() as usize;
+    let code = process.copy_pointer(frame.code()).context("Failed to copy PyCodeObject")?;
+
+    if let Some(info) = code_cache.code.get(&addr).filter(|info| info.is_code(&code)) {
+        code_cache.hits += 1;
+        let line = line_number(&info.line_table, code.first_lineno(), frame.lasti());
+        return Ok(Frame{name: info.name.clone(), filename: info.filename.clone(), line,
+                        short_filename: None, module: None, addr: None});
     }
+    code_cache.misses += 1;
 
-    Ok(StackTrace{frames, thread_id: thread.thread_id(), owns_gil: false, active: true, os_thread_id: None})
+    let filename = copy_string(code.filename(), process).context("Failed to copy filename")?;
+    let name = copy_string(code.name(), process).context("Failed to copy function name")?;
+    let line_table = copy_bytes(code.lnotab(), process).context("Failed to copy line number table")?;
+    let line = line_number(&line_table, code.first_lineno(), frame.lasti());
+
+    if code_cache.code.len() >= MAX_CACHED_CODE {
+        code_cache.code.clear();
+    }
+    code_cache.code.insert(addr, CodeInfo{first_lineno: code.first_lineno(), name_ptr: code.name() as usize,
+                                          lnotab_ptr: code.lnotab() as usize, name: name.clone(),
+                                          filename: filename.clone(), line_table});
+
+    Ok(Frame{name, filename, line, short_filename: None, module: None, addr: None})
+}
+
+/// Caches what we need from code objects to describe the frames running them, keyed by the
+/// address of the code object. Code objects are immutable, but can be freed and another one
+/// created at the same address, so entries are only used if the first line number and the
+/// name and line table pointers of the code object still match
+pub struct CodeCache {
+    code: HashMap<usize, CodeInfo>,
+    hits: u64,
+    misses: u64,
+}
+
+struct CodeInfo {
+    first_lineno: i32,
+    name_ptr: usize,
+    lnotab_ptr: usize,
+    name: String,
+    filename: String,
+    line_table: Vec<u8>,
+}
+
+// the cache is emptied when it gets this big, rather than tracking which entries are still in use
+const MAX_CACHED_CODE: usize = 1 << 16;
+
+impl CodeInfo {
+    fn is_code<C: CodeObject>(&self, code: &C) -> bool {
+        self.first_lineno == code.first_lineno() && self.name_ptr == code.name() as usize &&
+            self.lnotab_ptr == code.lnotab() as usize
+    }
+}
+
+impl CodeCache {
+    pub fn new() -> CodeCache {
+        CodeCache{code: HashMap::new(), hits: 0, misses: 0}
+    }
+
+    /// Returns the number of frames whose code was found in the cache and the number whose code
+    /// wasn't since the last call
+    pub fn take_stats(&mut self) -> (u64, u64) {
+        let stats = (self.hits, self.misses);
+        self.hits = 0;
+        self.misses = 0;
+        stats
+    }
+}
+
+impl Default for CodeCache {
+    fn default() -> CodeCache {
+        CodeCache::new()
+    }
 }
 
 impl StackTrace {
@@ -86,15 +273,14 @@ impl StackTrace {
     }
 }
 
-/// Returns the line number from a PyCodeObject (given the lasti index from a PyFrameObject)
-fn get_line_number<C: CodeObject, P: ProcessMemory>(code: &C, lasti: i32, process: &P) -> Result<i32, Error> {
-    let table = copy_bytes(code.lnotab(), process).context("Failed to copy line number table")?;
-
+/// Returns the line number for the lasti index from a PyFrameObject, given the line number table
+/// and first line number of its code object
+fn line_number(table: &[u8], first_lineno: i32, lasti: i32) -> i32 {
     // unpack the line table. format is specified here:
     // https://github.com/python/cpython/blob/master/Objects/lnotab_notes.txt
     let size = table.len();
     let mut i = 0;
-    let mut line_number: i32 = code.first_lineno();
+    let mut line_number: i32 = first_lineno;
     let mut bytecode_address: i32 = 0;
     while (i + 1) < size {
         bytecode_address += i32::from(table[i]);
@@ -106,14 +292,14 @@ fn get_line_number<C: CodeObject, P: ProcessMemory>(code: &C, lasti: i32, proces
         i += 2;
     }
 
-    Ok(line_number)
+    line_number
 }
 
 /// Copies a string from a target process. Attempts to handle unicode differences, which mostly seems to be working
 pub fn copy_string<T: StringObject, P: ProcessMemory>(ptr: * const T, process: &P) -> Result<String, Error> {
     let obj = process.copy_pointer(ptr)?;
     if obj.size() >= 4096 {
-        return Err(format_err!("Refusing to copy {} chars of a string", obj.size()));
+        return Err(SampleError::new(SampleErrorKind::InvalidData, format!("Refusing to copy {} chars of a string", obj.size())).into());
     }
 
     let kind = obj.kind();
@@ -132,9 +318,10 @@ pub fn copy_string<T: StringObject, P: ProcessMemory>(ptr: * const T, process: &
             //            or with python 3.2 --with-wide-unicode=ucs2
             Err(format_err!("ucs2 strings aren't supported yet!"))
         },
-        (1, true) => Ok(String::from_utf8(bytes)?),
+        (1, true) => String::from_utf8(bytes)
+            .map_err(|err| SampleError::new(SampleErrorKind::InvalidData, format!("Invalid utf8 string: {}", err)).into()),
         (1, false) => Ok(bytes.iter().map(|&b| { b as char }).collect()),
-        _ => Err(format_err!("Unknown string kind {}", kind))
+        _ => Err(SampleError::new(SampleErrorKind::InvalidData, format!("Unknown string kind {}", kind)).into())
     }
 }
 
@@ -143,7 +330,7 @@ pub fn copy_bytes<T: BytesObject, P: ProcessMemory>(ptr: * const T, process: &P)
     let obj = process.copy_pointer(ptr)?;
     let size = obj.size();
     if size >= 8192 {
-        return Err(format_err!("Refusing to copy {} bytes", size));
+        return Err(SampleError::new(SampleErrorKind::InvalidData, format!("Refusing to copy {} bytes", size)).into());
     }
     Ok(process.copy(obj.address(ptr as usize), size as usize)?)
 }
@@ -154,7 +341,8 @@ mod tests {
     // and then test out that the above code handles appropiately
     use super::*;
     use remoteprocess::LocalProcess;
-    use python_bindings::v3_7_0::{PyCodeObject, PyBytesObject, PyVarObject, PyUnicodeObject, PyASCIIObject};
+    use python_bindings::v3_7_0::{PyCodeObject, PyBytesObject, PyVarObject, PyUnicodeObject, PyASCIIObject,
+                                  PyFrameObject, PyThreadState, PyObject};
     use std::ptr::copy_nonoverlapping;
 
     // python stores data after pybytesobject/pyasciiobject. hack by initializing a 4k buffer for testing.
@@ -200,7 +388,8 @@ mod tests {
         let code = PyCodeObject{co_firstlineno: 3,
                                 co_lnotab: &mut lnotab.base.ob_base.ob_base,
                                 ..Default::default()};
-        let lineno = get_line_number(&code, 30, &LocalProcess).unwrap();
+        let table = copy_bytes(code.lnotab(), &LocalProcess).unwrap();
+        let lineno = line_number(&table, code.first_lineno(), 30);
         assert_eq!(lineno, 7);
     }
 
@@ -214,6 +403,61 @@ mod tests {
         assert_eq!(copied, original);
     }
 
+    #[test]
+    fn test_checked_stack_trace() {
+        // empty names, so that the strings can be copied without any storage
+        let mut name = to_asciiobject("");
+        let mut lnotab = to_byteobject(&[0u8, 1]);
+        let name_ptr = &mut name.base as *mut PyASCIIObject as *mut PyObject;
+        let mut code = PyCodeObject{co_firstlineno: 3, co_name: name_ptr, co_filename: name_ptr,
+                                    co_lnotab: &mut lnotab.base.ob_base.ob_base, ..Default::default()};
+        let mut caller = PyFrameObject{f_code: &mut code, ..Default::default()};
+        let caller: *mut PyFrameObject = &mut caller;
+        let mut callee = PyFrameObject{f_code: &mut code, f_back: caller, ..Default::default()};
+        let thread = PyThreadState{frame: &mut callee, ..Default::default()};
+
+        let trace = get_checked_stack_trace(&thread, &LocalProcess, &mut CodeCache::new()).unwrap();
+        assert_eq!(trace.frames.len(), 2);
+        assert_eq!(trace.frames[0].line, 4);
+
+        // a frame chain that loops is what we'd see if the caller was reused while we read it
+        unsafe { (*caller).f_back = &mut callee; }
+        let err = get_checked_stack_trace(&thread, &LocalProcess, &mut CodeCache::new()).unwrap_err();
+        assert_eq!(SampleErrorKind::of(&err), SampleErrorKind::Torn);
+        // which isn't checked when the process is paused
+        assert_eq!(get_stack_trace(&thread, &LocalProcess, &mut CodeCache::new()).unwrap_err().to_string(), "Max frame recursion depth reached");
+
+        unsafe {
+            (*caller).f_back = std::ptr::null_mut();
+            (*caller).f_code = std::ptr::null_mut();
+        }
+        let err = get_checked_stack_trace(&thread, &LocalProcess, &mut CodeCache::new()).unwrap_err();
+        assert_eq!(SampleErrorKind::of(&err), SampleErrorKind::Torn);
+    }
+
+    #[test]
+    fn test_code_cache() {
+        let mut name = to_asciiobject("");
+        let mut lnotab = to_byteobject(&[0u8, 1, 10, 1]);
+        let name_ptr = &mut name.base as *mut PyASCIIObject as *mut PyObject;
+        let mut code = PyCodeObject{co_firstlineno: 3, co_name: name_ptr, co_filename: name_ptr,
+                                    co_lnotab: &mut lnotab.base.ob_base.ob_base, ..Default::default()};
+        let code_ptr: *mut PyCodeObject = &mut code;
+        let frame = PyFrameObject{f_code: code_ptr, f_lasti: 12, ..Default::default()};
+
+        let mut code_cache = CodeCache::new();
+        assert_eq!(get_frame(&frame, &LocalProcess, &mut code_cache).unwrap().line, 5);
+        // the line number still comes from the frame when the code is cached
+        let frame = PyFrameObject{f_lasti: 2, ..frame};
+        assert_eq!(get_frame(&frame, &LocalProcess, &mut code_cache).unwrap().line, 4);
+        assert_eq!(code_cache.take_stats(), (1, 1));
+
+        // a different code object at the same address isn't read from the cache
+        unsafe { (*code_ptr).co_firstlineno = 10; }
+        assert_eq!(get_frame(&frame, &LocalProcess, &mut code_cache).unwrap().line, 11);
+        assert_eq!(code_cache.take_stats(), (0, 1));
+    }
+
     #[test]
     fn test_copy_bytes() {
         let original = [10_u8, 20, 30, 40, 50, 70, 80];
@@ -29,6 +29,73 @@ impl Timer {
         let start = Instant::now();
         Timer{start, desired: Duration::from_secs(0), exp: Exp::new(rate)}
     }
+
+    /// Changes the number of samples a second, starting from the next sample
+    pub fn set_rate(&mut self, rate: f64) {
+        self.exp = Exp::new(rate);
+    }
+}
+
+/// Lowers the sampling rate when taking samples pauses the target process for longer than a
+/// percentage of the time (for --max-overhead), and raises it back up again once sampling gets
+/// cheaper. The rate is only ever the requested rate divided by a whole number, so each sample
+/// can be counted as exactly that many samples at the requested rate.
+pub struct AdaptiveRate {
+    max_rate: u64,
+    budget: f64,
+    divisor: u64,
+    max_divisor: u64,
+    // moving average of how long each sample paused the process for, in seconds
+    pause: Option<f64>,
+}
+
+impl AdaptiveRate {
+    pub fn new(max_rate: u64, max_overhead: u64) -> AdaptiveRate {
+        AdaptiveRate{max_rate, budget: max_overhead as f64 / 100.0, divisor: 1, max_divisor: 1, pause: None}
+    }
+
+    /// The number of samples to take a second
+    pub fn rate(&self) -> f64 {
+        self.max_rate as f64 / self.divisor as f64
+    }
+
+    /// The lowest rate that has been used so far
+    pub fn lowest_rate(&self) -> f64 {
+        self.max_rate as f64 / self.max_divisor as f64
+    }
+
+    /// The number of samples at the requested rate that each sample stands for
+    pub fn weight(&self) -> u64 {
+        self.divisor
+    }
+
+    /// Records how long a sample paused the process for, returning the new rate if it changed
+    pub fn update(&mut self, pause: Duration) -> Option<f64> {
+        let seconds = pause.as_secs() as f64 + pause.subsec_nanos() as f64 / 1e9;
+        let pause = match self.pause {
+            Some(average) => 0.9 * average + 0.1 * seconds,
+            None => seconds
+        };
+        self.pause = Some(pause);
+
+        // the fraction of the time the process would be paused for when dividing the rate by this
+        let overhead = |divisor: u64| pause * self.max_rate as f64 / divisor as f64;
+        let divisor = if overhead(self.divisor) > self.budget {
+            (pause * self.max_rate as f64 / self.budget).ceil() as u64
+        } else if self.divisor > 1 && overhead(self.divisor - 1) < 0.8 * self.budget {
+            // speed back up slowly, with some slack so that we don't flip back and forth
+            self.divisor - 1
+        } else {
+            self.divisor
+        };
+
+        if divisor == self.divisor {
+            return None;
+        }
+        self.divisor = divisor;
+        self.max_divisor = self.max_divisor.max(divisor);
+        Some(self.rate())
+    }
 }
 
 impl Iterator for Timer {
@@ -62,3 +129,30 @@ impl Drop for Timer {
         unsafe { timeapi::timeEndPeriod(1); }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_rate() {
+        let mut rate = AdaptiveRate::new(100, 5);
+        // 0.1ms pauses at 100Hz is only 1% overhead
+        assert_eq!(rate.update(Duration::from_micros(100)), None);
+        assert_eq!(rate.weight(), 1);
+
+        // 2ms pauses would be 20%, so sample a quarter as often
+        rate.pause = None;
+        assert_eq!(rate.update(Duration::from_millis(2)), Some(25.0));
+        assert_eq!(rate.weight(), 4);
+        assert_eq!(rate.update(Duration::from_millis(2)), None);
+
+        // and speed back up one step at a time once samples get cheaper
+        rate.pause = None;
+        assert_eq!(rate.update(Duration::from_micros(100)), Some(100.0 / 3.0));
+        assert_eq!(rate.update(Duration::from_micros(100)), Some(50.0));
+        assert_eq!(rate.update(Duration::from_micros(100)), Some(100.0));
+        assert_eq!(rate.update(Duration::from_micros(100)), None);
+        assert_eq!(rate.lowest_rate(), 25.0);
+    }
+}
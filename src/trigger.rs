@@ -0,0 +1,332 @@
+/* Conditional capture for 'py-spy record --trigger-*'.
+
+Samples are kept in a ring buffer holding the last few seconds. When one of the trigger
+conditions fires, we keep sampling for a little while longer and then hand back everything
+in the buffer, so that the output shows what led up to the problem as well as the problem
+itself. The conditions are:
+
+ * the process using more than a percentage of a CPU (linux only, read from /proc/<pid>/stat)
+ * an active thread staying in the same function for more than a number of seconds
+ * a function matching a regex showing up in any stack
+*/
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use regex::Regex;
+use remoteprocess::Pid;
+
+use crate::config::Config;
+use crate::stack_trace::StackTrace;
+
+/// The traces collected from a single sample of the process
+pub struct Sample {
+    pub time_stamp: u64,
+    /// The number of samples at the requested rate that this sample counts as
+    pub weight: u64,
+    pub traces: Vec<StackTrace>,
+}
+
+/// The samples from before and after a trigger fired
+pub struct Window {
+    pub reason: String,
+    pub samples: Vec<Sample>,
+}
+
+pub enum Event {
+    /// A trigger condition fired, and the window will be written once the 'after' time is up
+    Triggered(String),
+    Window(Window),
+}
+
+// the function each active thread is in, and when it entered it
+type StuckThreads = HashMap<u64, (String, Instant)>;
+
+pub struct TriggeredCapture {
+    buffer: VecDeque<(Instant, Sample)>,
+    before: Duration,
+    after: Duration,
+    cpu: Option<(f64, CpuUsage)>,
+    stuck: Option<(Duration, StuckThreads)>,
+    function: Option<Regex>,
+    // why the trigger fired, and when to stop recording
+    fired: Option<(String, Instant)>,
+}
+
+impl TriggeredCapture {
+    /// Creates a new capture from the --trigger-* options, or returns None if there are no triggers
+    pub fn new(config: &Config, pid: Pid) -> Result<Option<TriggeredCapture>, Error> {
+        if config.trigger_cpu.is_none() && config.trigger_stuck.is_none() && config.trigger_function.is_none() {
+            return Ok(None);
+        }
+
+        let cpu = match config.trigger_cpu {
+            Some(threshold) => Some((threshold as f64, CpuUsage::new(pid)?)),
+            None => None
+        };
+        let stuck = config.trigger_stuck.map(|seconds| (Duration::from_secs(seconds), HashMap::new()));
+        let function = match config.trigger_function.as_ref() {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None
+        };
+        Ok(Some(TriggeredCapture{buffer: VecDeque::new(),
+                                 before: Duration::from_secs(config.trigger_before),
+                                 after: Duration::from_secs(config.trigger_after),
+                                 cpu, stuck, function, fired: None}))
+    }
+
+    /// Starts watching another process, for when the process being recorded has been restarted
+    pub fn set_pid(&mut self, pid: Pid) -> Result<(), Error> {
+        if let Some((_, usage)) = self.cpu.as_mut() {
+            *usage = CpuUsage::new(pid)?;
+        }
+        // the threads in the new process are different ones, even if they have the same ids
+        if let Some((_, threads)) = self.stuck.as_mut() {
+            threads.clear();
+        }
+        Ok(())
+    }
+
+    /// Adds a sample to the buffer, and checks whether any of the triggers have fired
+    pub fn add(&mut self, now: Instant, sample: Sample) -> Option<Event> {
+        let reason = match self.fired {
+            Some(_) => None,
+            None => self.check(now, &sample)
+        };
+        self.buffer.push_back((now, sample));
+
+        match self.fired.take() {
+            Some((reason, until)) => {
+                if now >= until {
+                    return Some(Event::Window(self.take_window(reason)));
+                }
+                self.fired = Some((reason, until));
+                None
+            },
+            None => {
+                // drop samples that are too old to be included if a trigger fires
+                while let Some((time, _)) = self.buffer.front() {
+                    if now.duration_since(*time) <= self.before {
+                        break;
+                    }
+                    self.buffer.pop_front();
+                }
+
+                let reason = reason?;
+                self.fired = Some((reason.clone(), now + self.after));
+                Some(Event::Triggered(reason))
+            }
+        }
+    }
+
+    /// Returns the samples for a trigger that has fired, but hasn't finished recording yet
+    pub fn finish(&mut self) -> Option<Window> {
+        let (reason, _) = self.fired.take()?;
+        Some(self.take_window(reason))
+    }
+
+    fn take_window(&mut self, reason: String) -> Window {
+        // don't trigger again for threads that were already stuck in the window we're writing
+        if let Some((_, threads)) = self.stuck.as_mut() {
+            threads.clear();
+        }
+        Window{reason, samples: self.buffer.drain(..).map(|(_, sample)| sample).collect()}
+    }
+
+    // Returns why a trigger fired, if any of them did
+    fn check(&mut self, now: Instant, sample: &Sample) -> Option<String> {
+        if let Some((threshold, usage)) = self.cpu.as_mut() {
+            if let Some(percent) = usage.update(now) {
+                if percent > *threshold {
+                    return Some(format!("CPU usage of {:.0}%", percent));
+                }
+            }
+        }
+
+        if let Some((threshold, threads)) = self.stuck.as_mut() {
+            // forget about threads that are idle or have exited
+            threads.retain(|thread_id, _| sample.traces.iter().any(|trace| trace.active && trace.thread_id == *thread_id));
+
+            for trace in sample.traces.iter().filter(|trace| trace.active) {
+                let function = match trace.frames.first() {
+                    Some(frame) => format!("{} ({})", frame.name, frame.short_filename.as_ref().unwrap_or(&frame.filename)),
+                    None => continue
+                };
+                let (current, since) = threads.entry(trace.thread_id).or_insert_with(|| (function.clone(), now));
+                if *current != function {
+                    *current = function;
+                    *since = now;
+                } else if now.duration_since(*since) >= *threshold {
+                    return Some(format!("thread {:#X} in {} for {:.1?}", trace.thread_id, current, now.duration_since(*since)));
+                }
+            }
+        }
+
+        if let Some(function) = self.function.as_ref() {
+            for trace in &sample.traces {
+                if let Some(frame) = trace.frames.iter().find(|frame| function.is_match(&frame.name)) {
+                    return Some(format!("{} in thread {:#X}", frame.name, trace.thread_id));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Measures the CPU usage of a process from /proc/<pid>/stat
+struct CpuUsage {
+    pid: Pid,
+    ticks_per_second: f64,
+    previous: Option<(Instant, u64)>,
+}
+
+impl CpuUsage {
+    #[cfg(target_os="linux")]
+    fn new(pid: Pid) -> Result<CpuUsage, Error> {
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let mut usage = CpuUsage{pid, ticks_per_second, previous: None};
+        usage.previous = Some((Instant::now(), usage.read_ticks()?));
+        Ok(usage)
+    }
+
+    #[cfg(not(target_os="linux"))]
+    fn new(_pid: Pid) -> Result<CpuUsage, Error> {
+        Err(format_err!("--trigger-cpu is only supported on linux"))
+    }
+
+    /// Returns the percentage of a CPU used since the last time this returned a value. This
+    /// only updates once a second, so that the measurement isn't too noisy
+    fn update(&mut self, now: Instant) -> Option<f64> {
+        let (previous_time, previous_ticks) = self.previous?;
+        let elapsed = now.duration_since(previous_time);
+        if elapsed < Duration::from_secs(1) {
+            return None;
+        }
+        // the process might have exited, we'll find out about that elsewhere
+        let ticks = self.read_ticks().ok()?;
+        self.previous = Some((now, ticks));
+
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        Some(100.0 * ticks.saturating_sub(previous_ticks) as f64 / self.ticks_per_second / seconds)
+    }
+
+    fn read_ticks(&self) -> Result<u64, Error> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", self.pid))?;
+        parse_cpu_ticks(&stat).ok_or_else(|| format_err!("Failed to parse /proc/{}/stat", self.pid))
+    }
+}
+
+// Returns the user + system time from the contents of /proc/<pid>/stat
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    // the process name is in brackets and can contain spaces, so start after it
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // utime and stime are the 14th and 15th fields, counting from the pid
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::Frame;
+
+    fn sample(time_stamp: u64, function: &str) -> Sample {
+        let frames = vec![Frame{name: function.to_owned(), filename: "app.py".to_owned(), module: None,
+                                short_filename: None, line: 1, addr: None}];
+        Sample{time_stamp, weight: 1, traces: vec![StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false,
+                                                   interpreter_id: 0, task_name: None, wait: None, frames}]}
+    }
+
+    #[test]
+    fn test_parse_cpu_ticks() {
+        let stat = "1234 (python3 my app) S 1 1234 1234 0 -1 4194560 2337 0 0 0 150 25 0 0 20 0 1 0 5032 23126016 2401";
+        assert_eq!(parse_cpu_ticks(stat), Some(175));
+        assert_eq!(parse_cpu_ticks("1234 (python3) S 1"), None);
+    }
+
+    #[test]
+    fn test_function_trigger() {
+        let config = Config{trigger_function: Some("^slow_query$".to_owned()), trigger_before: 2, trigger_after: 1,
+                            ..Default::default()};
+        let mut capture = TriggeredCapture::new(&config, 0).unwrap().unwrap();
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        // samples older than the buffer get dropped
+        for i in 0..5 {
+            assert!(capture.add(at(i), sample(i, "handle_request")).is_none());
+        }
+        match capture.add(at(5), sample(5, "slow_query")) {
+            Some(Event::Triggered(reason)) => assert_eq!(reason, "slow_query in thread 0x1"),
+            _ => panic!("expected trigger to fire")
+        }
+        assert!(capture.add(at(5), sample(6, "handle_request")).is_none());
+        let window = match capture.add(at(6), sample(7, "handle_request")) {
+            Some(Event::Window(window)) => window,
+            _ => panic!("expected window to be written")
+        };
+        let time_stamps: Vec<u64> = window.samples.iter().map(|sample| sample.time_stamp).collect();
+        assert_eq!(time_stamps, vec![3, 4, 5, 6, 7]);
+
+        // and then the trigger gets re-armed
+        assert!(capture.finish().is_none());
+        assert!(capture.add(at(7), sample(8, "handle_request")).is_none());
+        assert!(capture.add(at(8), sample(9, "slow_query")).is_some());
+        assert_eq!(capture.finish().unwrap().samples.len(), 2);
+    }
+
+    #[test]
+    fn test_stuck_trigger() {
+        let config = Config{trigger_stuck: Some(3), trigger_before: 10, trigger_after: 0, ..Default::default()};
+        let mut capture = TriggeredCapture::new(&config, 0).unwrap().unwrap();
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        assert!(capture.add(at(0), sample(0, "parse")).is_none());
+        assert!(capture.add(at(2), sample(1, "parse")).is_none());
+        assert!(capture.add(at(3), sample(2, "render")).is_none());
+        assert!(capture.add(at(5), sample(3, "render")).is_none());
+        match capture.add(at(6), sample(4, "render")) {
+            Some(Event::Triggered(reason)) => assert_eq!(reason, "thread 0x1 in render (app.py) for 3.0s"),
+            _ => panic!("expected trigger to fire")
+        }
+    }
+
+    #[test]
+    fn test_stuck_trigger_idle() {
+        let config = Config{trigger_stuck: Some(3), trigger_before: 10, trigger_after: 0, ..Default::default()};
+        let mut capture = TriggeredCapture::new(&config, 0).unwrap().unwrap();
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        // a thread that waits in between calls to the same function isn't stuck in it
+        let mut idle = sample(1, "poll");
+        idle.traces[0].active = false;
+        assert!(capture.add(at(0), sample(0, "poll")).is_none());
+        assert!(capture.add(at(2), idle).is_none());
+        assert!(capture.add(at(3), sample(2, "poll")).is_none());
+        assert!(capture.add(at(5), sample(3, "poll")).is_none());
+        assert!(capture.add(at(6), sample(4, "poll")).is_some());
+    }
+    #[cfg(target_os="linux")]
+    #[test]
+    fn test_restarted_process() {
+        let pid = std::process::id() as Pid;
+        let config = Config{trigger_cpu: Some(1000), trigger_stuck: Some(3), trigger_before: 10, trigger_after: 0,
+                            ..Default::default()};
+        let mut capture = TriggeredCapture::new(&config, pid).unwrap().unwrap();
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        // the new process's threads haven't been stuck for as long as the old one's
+        assert!(capture.add(at(0), sample(0, "parse")).is_none());
+        assert!(capture.add(at(2), sample(1, "parse")).is_none());
+        capture.set_pid(pid).unwrap();
+        assert!(capture.add(at(3), sample(2, "parse")).is_none());
+        assert!(capture.add(at(6), sample(3, "parse")).is_some());
+
+        // we can't measure the CPU usage of a process that doesn't exist
+        assert!(capture.set_pid(i32::MAX as Pid).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use crate::stack_trace::{StackTrace, Frame};
+
+/// Tracks how the stack of each thread changes between the snapshots taken by 'dump --interval'
+pub struct StackHistory {
+    threads: HashMap<ThreadKey, ThreadHistory>,
+    pub snapshots: u64,
+}
+
+// threads are keyed by their thread id, and the task name for asyncio pseudo-threads
+type ThreadKey = (u64, Option<String>);
+
+struct ThreadHistory {
+    first: Vec<Frame>,
+    previous: Vec<Frame>,
+    // the number of outermost frames that have been the same in every snapshot
+    stable: usize,
+    changed: bool,
+}
+
+impl StackHistory {
+    pub fn new() -> StackHistory {
+        StackHistory{threads: HashMap::new(), snapshots: 0}
+    }
+
+    /// Adds a snapshot, returning the traces that changed since the previous snapshot along
+    /// with the number of outermost frames that have been the same in every snapshot
+    pub fn update<'a>(&mut self, traces: &'a [StackTrace]) -> Vec<(&'a StackTrace, usize)> {
+        self.snapshots += 1;
+
+        // forget about threads that have exited
+        self.threads.retain(|key, _| traces.iter().any(|trace| trace_key(trace) == *key));
+
+        let mut changed = Vec::new();
+        for trace in traces {
+            match self.threads.get_mut(&trace_key(trace)) {
+                Some(history) => {
+                    if history.previous == trace.frames {
+                        continue;
+                    }
+                    history.stable = history.stable.min(common_root_frames(&history.first, &trace.frames));
+                    history.previous = trace.frames.clone();
+                    history.changed = true;
+                    changed.push((trace, history.stable));
+                },
+                None => {
+                    self.threads.insert(trace_key(trace), ThreadHistory{first: trace.frames.clone(),
+                                                                        previous: trace.frames.clone(),
+                                                                        stable: trace.frames.len(),
+                                                                        changed: false});
+                    changed.push((trace, trace.frames.len()));
+                }
+            }
+        }
+        changed
+    }
+
+    /// Returns whether the stack of a thread has been the same in every snapshot
+    pub fn is_stuck(&self, trace: &StackTrace) -> bool {
+        match self.threads.get(&trace_key(trace)) {
+            Some(history) => !history.changed,
+            None => false
+        }
+    }
+}
+
+fn trace_key(trace: &StackTrace) -> ThreadKey {
+    (trace.thread_id, trace.task_name.clone())
+}
+
+// Returns how many frames two stacks have in common, starting from the outermost frame
+fn common_root_frames(a: &[Frame], b: &[Frame]) -> usize {
+    a.iter().rev().zip(b.iter().rev()).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, line: i32) -> Frame {
+        Frame{name: name.to_owned(), filename: "test.py".to_owned(), module: None, short_filename: None, line, addr: None}
+    }
+
+    fn trace(thread_id: u64, frames: Vec<Frame>) -> StackTrace {
+        StackTrace{thread_id, os_thread_id: None, active: true, owns_gil: false, interpreter_id: 0,
+                   task_name: None, wait: None, frames}
+    }
+
+    #[test]
+    fn test_stack_history() {
+        let mut history = StackHistory::new();
+
+        // every thread is shown in the first snapshot, with all of its frames unchanged
+        let first = vec![trace(1, vec![frame("wait", 10), frame("main", 1)]),
+                         trace(2, vec![frame("read", 5), frame("process", 20), frame("run", 2)])];
+        let changed = history.update(&first);
+        assert_eq!(changed.iter().map(|(trace, stable)| (trace.thread_id, *stable)).collect::<Vec<_>>(),
+                   vec![(1, 2), (2, 3)]);
+
+        // thread 1 is stuck, thread 2 is making progress inside of 'process'
+        let second = vec![trace(1, vec![frame("wait", 10), frame("main", 1)]),
+                          trace(2, vec![frame("parse", 8), frame("process", 21), frame("run", 2)])];
+        let changed = history.update(&second);
+        assert_eq!(changed.iter().map(|(trace, stable)| (trace.thread_id, *stable)).collect::<Vec<_>>(),
+                   vec![(2, 1)]);
+        assert!(history.is_stuck(&second[0]));
+        assert!(!history.is_stuck(&second[1]));
+
+        // returning to the original line in 'process' doesn't make the frame stable again
+        let third = vec![trace(1, vec![frame("wait", 10), frame("main", 1)]),
+                         trace(2, vec![frame("read", 5), frame("process", 20), frame("run", 2)])];
+        let changed = history.update(&third);
+        assert_eq!(changed.iter().map(|(trace, stable)| (trace.thread_id, *stable)).collect::<Vec<_>>(),
+                   vec![(2, 1)]);
+        assert_eq!(history.snapshots, 3);
+
+        // threads that exit are dropped from the history
+        let fourth = vec![trace(2, vec![frame("run", 2)])];
+        history.update(&fourth);
+        assert!(!history.is_stuck(&first[0]));
+    }
+}
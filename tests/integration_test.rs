@@ -1,3 +1,4 @@
+extern crate libc;
 extern crate py_spy;
 
 use py_spy::{Config, PythonSpy};
@@ -75,3 +76,27 @@ fn test_long_sleep() {
     #[cfg(unwind)]
     assert!(!traces[0].active);
 }
+
+#[cfg(all(target_os="linux", target_arch="x86_64", unwind))]
+#[test]
+fn test_long_sleep_syscall() {
+    let mut runner = TestRunner::new("./tests/scripts/longsleep.py");
+
+    // suspending the process to get a stack trace interrupts the sleep, so give the thread a
+    // moment to go back to sleep in between samples
+    for _ in 0..2 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let traces = runner.spy.get_stack_traces().unwrap();
+        assert!(!traces[0].active);
+
+        // time.sleep is either select or clock_nanosleep depending on the python version, and
+        // shouldn't show up as the restart_syscall the kernel resumes interrupted sleeps with
+        match traces[0].wait {
+            Some(py_spy::ThreadWait::Syscall{number, ..}) => {
+                assert!([libc::SYS_select, libc::SYS_pselect6, libc::SYS_clock_nanosleep].contains(&number),
+                        "unexpected syscall {}", number);
+            },
+            ref wait => panic!("expected a syscall, got {:?}", wait)
+        }
+    }
+}